@@ -1,71 +1,1503 @@
-use anyhow::{Context, Result};
-use serde::{Deserialize, Serialize};
-use std::collections::BTreeSet;
+use crate::detector::Detection;
+use crate::error::BridgeError;
+use crate::plan::Families;
+use crate::ports::{ManualPorts, PortFilter, PortRange, PortRemap, PortSource, RuleFamily};
+use crate::process;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 use std::fs;
+use std::io::{self, Write};
+use std::net::Ipv4Addr;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use toml_edit::{DocumentMut, Item, Table};
+
+const DEFAULT_CADDY_TIMEOUT: Duration = Duration::from_secs(3);
+const DEFAULT_TRAEFIK_API: &str = "http://localhost:8080";
+
+const DEFAULT_DEV_SERVER_BINARIES: [&str; 7] = [
+    "vite",
+    "webpack",
+    "webpack-dev-server",
+    "next",
+    "nuxt",
+    "astro",
+    "ng",
+];
+const DEFAULT_STARTUP_GRACE_SECS: u64 = 30;
+const DEFAULT_REMOVAL_GRACE_POLLS: u32 = 2;
+const DEFAULT_JSON_MAX_DEPTH: usize = 64;
+/// Windows listen ports below this are held back unless `--allow-privileged`.
+const PRIVILEGED_BELOW: u16 = 1024;
+/// RPC, SMB, and RDP: ports Windows itself usually holds.
+const DEFAULT_RESERVED_PORTS: [u16; 3] = [135, 445, 3389];
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct PortsConfig {
-    #[serde(default)]
-    pub manual_ports: BTreeSet<u16>,
+    #[serde(flatten)]
+    pub manual_ports: ManualPorts,
+    /// Listen ports forwarded to a different WSL port, e.g. `"8080:3000"`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remaps: Vec<PortRemap>,
+    /// UDP ports the daemon relays from the WSL address to loopback, e.g. `"5353"`
+    /// or `"5300:53"`. netsh portproxy is TCP-only, so these never get a rule.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub udp_ports: Vec<PortRemap>,
+    /// TCP ports whose service binds only 127.0.0.1; the daemon relays the WSL
+    /// IP's port to loopback so the portproxy rule has something to reach.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub loopback_relay_ports: Vec<u16>,
     #[serde(default)]
     pub pm2_ports: BTreeSet<u16>,
     #[serde(default)]
     pub caddy_ports: BTreeSet<u16>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub listener_ports: BTreeSet<u16>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub dotenv_ports: BTreeSet<u16>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub traefik_ports: BTreeSet<u16>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub dev_server_ports: BTreeSet<u16>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub docker_ports: BTreeSet<u16>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub compose_ports: BTreeSet<u16>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub remote_ports: BTreeSet<u16>,
+    /// Per-detector allowlists, applied before the detected sets are merged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pm2_port_filter: Option<PortFilter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caddy_port_filter: Option<PortFilter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listener_port_filter: Option<PortFilter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dotenv_port_filter: Option<PortFilter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traefik_port_filter: Option<PortFilter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dev_server_port_filter: Option<PortFilter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub docker_port_filter: Option<PortFilter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compose_port_filter: Option<PortFilter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote_port_filter: Option<PortFilter>,
+    /// Forward detected ports even when they are only bound to loopback.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub forward_localhost_only: bool,
+    /// Also forward every port something in WSL listens on (via `ss -tlnp`).
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub detect_listeners: bool,
+    /// Also forward the entrypoint ports Traefik's API reports.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub detect_traefik: bool,
+    /// Base URL of the Traefik API; defaults to `http://localhost:8080`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traefik_api: Option<String>,
+    /// URL serving a JSON array of ports to forward, e.g. from a build server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote_ports_url: Option<String>,
+    /// Where `version --check` looks up the latest release; defaults to crates.io.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub update_check_url: Option<String>,
+    /// Also forward `--port` arguments of running dev servers (vite, webpack, ...).
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub detect_dev_servers: bool,
+    /// Also forward host ports published by running Docker containers.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub detect_docker: bool,
+    /// Program names `detect_dev_servers` looks for; replaces the default list.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dev_server_binaries: Option<Vec<String>>,
+    /// Whether `remove` on a detected port also adds it to `exclude_ports` so
+    /// detection can't bring it back; true when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remove_adds_exclude: Option<bool>,
+    /// Detected ports to never forward; explicitly added ports still win.
+    #[serde(
+        default,
+        alias = "exclude_detected",
+        skip_serializing_if = "BTreeSet::is_empty"
+    )]
+    pub exclude_ports: BTreeSet<u16>,
+    /// Ports to never forward from any source, manual ones included.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub exclude_all: BTreeSet<u16>,
+    /// Which inputs may contribute forwarded ports, e.g. `["manual", "pm2"]`;
+    /// all of them when unset. Detection still runs for the others.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sources: Option<BTreeSet<PortSource>>,
+    /// Listen ports whose rules `prune` never deletes unless `--include-sticky`.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub sticky_ports: BTreeSet<u16>,
+    /// Listen ports opened as `http://localhost:<port>` in the Windows browser
+    /// whenever a sync creates their rule (not when it merely rewrites one).
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub open_on_sync_ports: BTreeSet<u16>,
+    /// Like `exclude_ports`, as ranges such as `"40000-60999"`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_ranges: Vec<PortRange>,
+    /// Like `exclude_all`, as ranges.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_all_ranges: Vec<PortRange>,
+    /// Forward nothing below `min_port` or above `max_port`, from any source.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_port: Option<u16>,
+    /// Let manual, profile, temp, and remapped ports through `min_port`/`max_port`.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub bounds_exempt_manual: bool,
+    /// Windows listen ports held back like privileged ones; defaults to 135, 445, 3389.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reserved_ports: Option<BTreeSet<u16>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command_timeout_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caddy_timeout_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub json_max_depth: Option<usize>,
+    /// Take the WSL IP from this interface (e.g. `eth0`) instead of `hostname -I`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    /// File a boot script keeps the current WSL IPv4 in; read on every sync instead of
+    /// `hostname -I`, which is still used if the file is missing or unparseable.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_address_file: Option<PathBuf>,
+    /// Never take the WSL IP from these interfaces (e.g. `docker0`); ignored when `interface` is set.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_interfaces: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub startup_grace_secs: Option<u64>,
+    /// Detection polls a detected port must stay missing before the daemon deletes its rule.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub removal_grace_polls: Option<u32>,
+    /// `.env` files to read `PORT`/`*_PORT` values from.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dotenv_files: Vec<PathBuf>,
+    /// Compose project directories whose running services' published ports
+    /// (`docker compose ps`) are forwarded.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub compose_dirs: Vec<PathBuf>,
+    /// pm2 ecosystem files (`.json`, or `.js`/`.cjs` run through `node`) whose
+    /// `env` `PORT`/`*_PORT` values count as pm2 ports before the apps start.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pm2_ecosystem_files: Vec<PathBuf>,
+    /// Caddyfile to read ports from when the admin API is unreachable.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caddyfile: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub listen_addresses: Vec<ListenAddress>,
+    /// Listen on 0.0.0.0 (with a warning) when a named listen interface can't
+    /// be resolved, instead of failing the sync.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub listen_interface_fallback: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub only_when_listening: bool,
+    /// Unset follows the WSL networking mode: loopback when mirrored, eth0 otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_mode: Option<ConnectMode>,
+    /// Shell command run before a sync applies rules; see the README for its contract.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_sync_hook: Option<String>,
+    /// Shell command run after every successful sync, with `WSL_IP` and
+    /// `WSL_PORTS` (space-separated listen ports) in its environment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_sync_hook: Option<String>,
+    /// Pop a Windows notification when the daemon re-syncs after an IP change.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub notify_on_ip_change: bool,
+    /// Windows listens on each manual/detected port plus this; remaps are unaffected.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub connect_port_offset: i32,
+    /// netsh family per listen port, e.g. `v6tov4 = [8080]`; unlisted ports use `v4tov4`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub port_families: BTreeMap<RuleFamily, BTreeSet<u16>>,
+    /// Run netsh on this Windows host over SSH instead of local interop.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssh: Option<SshConfig>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub targets: BTreeMap<String, TargetConfig>,
+    /// Listen ports whose rule falls back to another address when this
+    /// distro's stops accepting connections.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failover: Vec<FailoverConfig>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub temp_ports: Vec<TempPort>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_profile: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, ProfileConfig>,
+    /// Merged from `ports.d/*.toml` at load time; never saved to the main file.
+    #[serde(skip)]
+    pub fragments: Fragment,
+    /// Plaintext file of extra manual ports, one port or range per line; relative to the config dir.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_file: Option<PathBuf>,
+    /// Read from `include_file` at load time; never saved.
+    #[serde(skip)]
+    pub included_ports: BTreeSet<u16>,
+    #[serde(skip)]
+    pub runtime: RuntimeOverrides,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+fn is_zero(value: &i32) -> bool {
+    *value == 0
+}
+
+/// Which address this distro's rules connect to on the WSL side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectMode {
+    /// The distro's `hostname -I` address.
+    Eth0,
+    /// 127.0.0.1, for mirrored networking where Windows shares WSL's loopback.
+    Loopback,
+    /// Probe from Windows at sync time and use whichever of the two answers.
+    Auto,
+}
+
+impl fmt::Display for ConnectMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            ConnectMode::Eth0 => "eth0",
+            ConnectMode::Loopback => "loopback",
+            ConnectMode::Auto => "auto",
+        })
+    }
+}
+
+/// A Windows-side listen address: a fixed IPv4, `"auto-lan"` for the host's
+/// primary LAN address, or a Windows interface alias such as `"vEthernet (WSL)"`
+/// whose IPv4 is looked up on every sync.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ListenAddress {
+    Ip(Ipv4Addr),
+    AutoLan,
+    Interface(String),
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddress::Ip(ip) => ip.fmt(f),
+            ListenAddress::AutoLan => f.write_str("auto-lan"),
+            ListenAddress::Interface(name) => f.write_str(name),
+        }
+    }
+}
+
+impl FromStr for ListenAddress {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        if input == "auto-lan" {
+            return Ok(ListenAddress::AutoLan);
+        }
+        if let Ok(ip) = input.parse() {
+            return Ok(ListenAddress::Ip(ip));
+        }
+        // Anything dotted-numeric is a mistyped IP, not an interface alias.
+        if input.trim().is_empty() || input.chars().all(|c| c.is_ascii_digit() || c == '.') {
+            return Err(format!(
+                "invalid listen address `{input}`; expected an IPv4, \"auto-lan\", or a Windows interface name"
+            ));
+        }
+        Ok(ListenAddress::Interface(input.to_string()))
+    }
+}
+
+impl Serialize for ListenAddress {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        raw.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Which IP families rules may use, from `--ipv4-only` / `--ipv6-only`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FamilyPolicy {
+    /// `port_families` as configured, `v4tov4` for everything else.
+    #[default]
+    Dual,
+    /// Every rule is `v4tov4`, ignoring `port_families`.
+    Ipv4Only,
+    /// Every rule is `v6tov6`, ignoring `port_families`.
+    Ipv6Only,
+}
+
+/// Per-invocation overrides from CLI flags; never written back to disk.
+#[derive(Debug, Clone, Default)]
+pub struct RuntimeOverrides {
+    pub caddy_timeout_ms: Option<u64>,
+    pub no_detect: bool,
+    pub transactional: bool,
+    pub interface: Option<String>,
+    pub exclude_interfaces: Vec<String>,
+    pub sync_timeout: Option<Duration>,
+    pub family: FamilyPolicy,
+    pub allow_privileged: bool,
+    pub force: bool,
+    /// Connect every rule here instead of looking up the WSL IP (`sync --connect-address`).
+    pub connect_address: Option<Ipv4Addr>,
+    /// How many netsh rules a sync applies at once.
+    pub max_concurrency: usize,
+    /// Whether old rules are all deleted before the adds or one by one (`--apply-order`).
+    pub apply_order: crate::windows::ApplyOrder,
+    pub pre_sync_hook: Option<String>,
+    pub post_sync_hook: Option<String>,
+    /// Where successful syncs are logged; unset when no config dir is known.
+    pub history_path: Option<PathBuf>,
+    /// When each detected port was last reported; unset when no config dir is known.
+    pub seen_path: Option<PathBuf>,
+    /// Sentinel whose presence pauses the daemon (`wsl-port pause`).
+    pub pause_path: Option<PathBuf>,
+    /// Never start PowerShell; every Windows call fails with `BridgeError::Offline`.
+    pub offline: bool,
+    /// A failed detector for a forwarded source fails the command (`--strict`).
+    pub strict: bool,
+}
+
+/// How a detected port overlaps a manual remap; see `PortsConfig::remap_shadows`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemapShadow {
+    /// The remap's connect port was detected, so it is also forwarded on its own.
+    ConnectDetected(PortRemap),
+    /// The remap's listen port was detected; the remap wins and the service is hidden.
+    ListenDetected(PortRemap),
+}
+
+impl fmt::Display for RemapShadow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemapShadow::ConnectDetected(r) => write!(
+                f,
+                "remap {} -> {} overlaps detected port {}: Windows ports {} and {} both reach WSL port {}",
+                r.listen, r.connect, r.connect, r.listen, r.connect, r.connect
+            ),
+            RemapShadow::ListenDetected(r) => write!(
+                f,
+                "remap {} -> {} hides detected port {}: Windows port {} reaches WSL port {}, not the detected service",
+                r.listen, r.connect, r.listen, r.listen, r.connect
+            ),
+        }
+    }
+}
+
+/// An additional forwarding target, e.g. another WSL distro with its own IP.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct TargetConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distro: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<Ipv4Addr>,
+    #[serde(flatten)]
+    pub manual_ports: ManualPorts,
+}
+
+/// Active-passive failover for one forwarded listen port: each sync connects
+/// its rule to the distro's own address if that accepts a TCP connection on
+/// the connect port, else to the first of `fallbacks` that does.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FailoverConfig {
+    pub port: u16,
+    pub fallbacks: Vec<Ipv4Addr>,
+}
+
+/// A Windows host reachable with OpenSSH whose default shell can start `powershell`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SshConfig {
+    /// `host` or `user@host`, as passed to `ssh`.
+    pub host: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity_file: Option<PathBuf>,
+}
+
+/// A manual port that stops being forwarded at `expires_at` (unix seconds).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TempPort {
+    pub port: u16,
+    pub expires_at: u64,
+}
+
+/// The slice of config a `ports.d/*.toml` fragment may contribute.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Fragment {
+    #[serde(flatten)]
+    pub manual_ports: ManualPorts,
+    #[serde(default)]
+    pub remaps: Vec<PortRemap>,
+    #[serde(default)]
+    pub exclude_ports: BTreeSet<u16>,
+    #[serde(default)]
+    pub exclude_all: BTreeSet<u16>,
+}
+
+impl Fragment {
+    /// Unions `other` in; for remaps the first fragment to claim a listen port wins.
+    fn merge(&mut self, other: Fragment) {
+        self.manual_ports.extend(other.manual_ports.iter().copied());
+        for remap in other.remaps {
+            if !self
+                .remaps
+                .iter()
+                .any(|existing| existing.listen == remap.listen)
+            {
+                self.remaps.push(remap);
+            }
+        }
+        self.exclude_ports.extend(other.exclude_ports);
+        self.exclude_all.extend(other.exclude_all);
+    }
+}
+
+/// A named set of manual ports layered on top of `manual_ports` while active.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ProfileConfig {
+    #[serde(flatten)]
+    pub manual_ports: ManualPorts,
 }
 
 impl PortsConfig {
+    /// Every listen port to forward: remaps, manual ports and ranges (plus
+    /// profile, temp, and `ports.d` ones) are always kept, detected ports only
+    /// when no `exclude_ports`/`exclude_ranges` entry covers them, and
+    /// `exclude_all`/`exclude_all_ranges` drop a port from every source.
+    /// `sources` and `min_port`/`max_port` filter each input first.
     pub fn all_ports(&self) -> BTreeSet<u16> {
-        self.manual_ports
+        let mut ports = self.detected_ports();
+        ports.extend(self.effective_manual_ports());
+        ports.extend(self.effective_remaps().iter().map(|remap| remap.listen));
+        ports
+    }
+
+    /// Whether `min_port`/`max_port` let this port be forwarded.
+    fn within_bounds(&self, port: u16) -> bool {
+        let keep = self.min_port.is_none_or(|min| port >= min)
+            && self.max_port.is_none_or(|max| port <= max);
+        if !keep {
+            tracing::debug!(port, "dropping port outside min_port/max_port");
+        }
+        keep
+    }
+
+    /// `within_bounds` for a port added by hand, which `bounds_exempt_manual` spares.
+    fn hand_added_within_bounds(&self, port: u16) -> bool {
+        self.bounds_exempt_manual || self.within_bounds(port)
+    }
+
+    /// Whether `sources` lets this input contribute forwarded ports.
+    pub fn forwards_source(&self, source: PortSource) -> bool {
+        self.sources
+            .as_ref()
+            .is_none_or(|sources| sources.contains(&source))
+    }
+
+    /// Top-level, fragment, and `include_file` manual ports plus those of the
+    /// active profile, if any, each only when `sources` allows it.
+    pub fn effective_manual_ports(&self) -> BTreeSet<u16> {
+        let mut ports = BTreeSet::new();
+        if self.forwards_source(PortSource::Manual) {
+            ports.extend(self.manual_ports.iter().copied());
+            ports.extend(self.fragments.manual_ports.iter().copied());
+            ports.extend(self.included_ports.iter().copied());
+        }
+        if let Some(profile) = self
+            .active_profile()
+            .filter(|_| self.forwards_source(PortSource::Profile))
+        {
+            ports.extend(profile.manual_ports.iter().copied());
+        }
+        if self.forwards_source(PortSource::Temp) {
+            ports.extend(self.live_temp_ports());
+        }
+        ports
+            .retain(|&port| !self.excluded_everywhere(port) && self.hand_added_within_bounds(port));
+        ports
+    }
+
+    /// Main-file remaps, then fragment remaps for listen ports the main file
+    /// doesn't remap, minus anything in `exclude_all`.
+    pub fn effective_remaps(&self) -> Vec<PortRemap> {
+        if !self.forwards_source(PortSource::Remap) {
+            return Vec::new();
+        }
+        let fragment_remaps = self.fragments.remaps.iter().filter(|remap| {
+            !self
+                .remaps
+                .iter()
+                .any(|existing| existing.listen == remap.listen)
+        });
+        self.remaps
             .iter()
-            .chain(self.pm2_ports.iter())
-            .chain(self.caddy_ports.iter())
+            .chain(fragment_remaps)
+            .filter(|remap| {
+                !self.excluded_everywhere(remap.listen)
+                    && self.hand_added_within_bounds(remap.listen)
+            })
             .copied()
             .collect()
     }
 
+    /// Remaps that a detected port makes ambiguous: the detector also forwards
+    /// the remap's connect port as-is, or found a service on its listen port,
+    /// which the remap then hides. Identity remaps (`3000:3000`) never count.
+    pub fn remap_shadows(&self) -> Vec<RemapShadow> {
+        let detected = self.detected_ports();
+        let mut shadows = Vec::new();
+        for remap in self.effective_remaps() {
+            if remap.listen == remap.connect {
+                continue;
+            }
+            if detected.contains(&remap.connect) {
+                shadows.push(RemapShadow::ConnectDetected(remap));
+            }
+            if detected.contains(&remap.listen) {
+                shadows.push(RemapShadow::ListenDetected(remap));
+            }
+        }
+        shadows
+    }
+
+    pub fn excluded_everywhere(&self, port: u16) -> bool {
+        self.exclude_all.contains(&port)
+            || self.fragments.exclude_all.contains(&port)
+            || self.exclude_all_ranges.iter().any(|r| r.contains(port))
+    }
+
+    fn excluded_from_detection(&self, port: u16) -> bool {
+        self.exclude_ports.contains(&port)
+            || self.fragments.exclude_ports.contains(&port)
+            || self.exclude_ranges.iter().any(|r| r.contains(port))
+            || self.excluded_everywhere(port)
+    }
+
+    /// Temporary ports that haven't expired yet.
+    pub fn live_temp_ports(&self) -> impl Iterator<Item = u16> + '_ {
+        let now = unix_now();
+        self.temp_ports
+            .iter()
+            .filter(move |temp| temp.expires_at > now)
+            .map(|temp| temp.port)
+    }
+
+    /// Records a temporary forward, extending any existing one for the port.
+    pub fn add_temp_port(&mut self, port: u16, ttl: Duration) -> u64 {
+        let expires_at = unix_now().saturating_add(ttl.as_secs());
+        self.exclude_ports.remove(&port);
+        self.temp_ports.retain(|temp| temp.port != port);
+        self.temp_ports.push(TempPort { port, expires_at });
+        expires_at
+    }
+
+    /// Drops expired temporary ports and returns them.
+    pub fn take_expired_temp_ports(&mut self) -> Vec<u16> {
+        let now = unix_now();
+        let (expired, live): (Vec<_>, Vec<_>) = self
+            .temp_ports
+            .drain(..)
+            .partition(|temp| temp.expires_at <= now);
+        self.temp_ports = live;
+        expired.into_iter().map(|temp| temp.port).collect()
+    }
+
+    /// Every source that contributes each listen port, honoring excludes and `--no-detect`.
+    pub fn port_sources(&self) -> BTreeMap<u16, BTreeSet<PortSource>> {
+        let mut sources: BTreeMap<u16, BTreeSet<PortSource>> = BTreeMap::new();
+        let mut tag = |port: u16, source| {
+            if self.forwards_source(source) {
+                sources.entry(port).or_default().insert(source);
+            }
+        };
+
+        for remap in self.effective_remaps() {
+            tag(remap.listen, PortSource::Remap);
+        }
+        for &port in self
+            .manual_ports
+            .iter()
+            .chain(self.fragments.manual_ports.iter())
+            .chain(&self.included_ports)
+        {
+            tag(port, PortSource::Manual);
+        }
+        if let Some(profile) = self.active_profile() {
+            for &port in profile.manual_ports.iter() {
+                tag(port, PortSource::Profile);
+            }
+        }
+        for port in self.live_temp_ports() {
+            tag(port, PortSource::Temp);
+        }
+        let detected = self.detected_ports();
+        for (source, ports) in self.detected_by_source() {
+            for &port in ports.iter().filter(|p| detected.contains(p)) {
+                tag(port, source);
+            }
+        }
+        sources.retain(|&port, sources| {
+            !self.excluded_everywhere(port)
+                && (self.within_bounds(port)
+                    || (self.bounds_exempt_manual && sources.iter().any(|s| !s.is_detected())))
+        });
+        sources
+    }
+
+    pub fn active_profile(&self) -> Option<&ProfileConfig> {
+        self.active_profile
+            .as_deref()
+            .and_then(|name| self.profiles.get(name))
+    }
+
+    /// Each detector's last result, tagged with its source.
+    fn detected_by_source(&self) -> [(PortSource, &BTreeSet<u16>); 9] {
+        [
+            (PortSource::Pm2, &self.pm2_ports),
+            (PortSource::Caddy, &self.caddy_ports),
+            (PortSource::Listener, &self.listener_ports),
+            (PortSource::Dotenv, &self.dotenv_ports),
+            (PortSource::Traefik, &self.traefik_ports),
+            (PortSource::DevServer, &self.dev_server_ports),
+            (PortSource::Docker, &self.docker_ports),
+            (PortSource::Compose, &self.compose_ports),
+            (PortSource::Remote, &self.remote_ports),
+        ]
+    }
+
+    /// Detected ports from the allowed `sources` that survive both exclude
+    /// lists; none when detection is disabled.
+    pub fn detected_ports(&self) -> BTreeSet<u16> {
+        if self.runtime.no_detect {
+            return BTreeSet::new();
+        }
+        self.detected_by_source()
+            .into_iter()
+            .filter(|&(source, _)| self.forwards_source(source))
+            .flat_map(|(_, ports)| ports.iter().copied())
+            .filter(|&port| !self.excluded_from_detection(port) && self.within_bounds(port))
+            .collect()
+    }
+
     pub fn add_manual_port(&mut self, port: u16) -> bool {
+        self.exclude_ports.remove(&port);
         self.manual_ports.insert(port)
     }
 
+    /// Whether any detector reported `port`, excluded or not.
+    pub fn is_detected_port(&self, port: u16) -> bool {
+        self.detected_by_source()
+            .iter()
+            .any(|(_, ports)| ports.contains(&port))
+    }
+
+    /// Excludes a port that a detector reported; returns false if no detector found it.
+    pub fn suppress_detected_port(&mut self, port: u16) -> bool {
+        self.is_detected_port(port) && self.exclude_ports.insert(port)
+    }
+
+    /// Stores a remap, replacing any existing one for the same listen port.
+    /// Returns false if the identical remap was already present.
+    pub fn add_remap(&mut self, remap: PortRemap) -> bool {
+        if self.remaps.contains(&remap) {
+            return false;
+        }
+        self.exclude_ports.remove(&remap.listen);
+        self.remaps
+            .retain(|existing| existing.listen != remap.listen);
+        self.remaps.push(remap);
+        self.remaps.sort();
+        true
+    }
+
+    pub fn remove_remap(&mut self, listen_port: u16) -> Option<PortRemap> {
+        let index = self
+            .remaps
+            .iter()
+            .position(|remap| remap.listen == listen_port)?;
+        Some(self.remaps.remove(index))
+    }
+
+    /// Drops every remap that connects to `connect_port`, returning them.
+    pub fn remove_remaps_by_connect(&mut self, connect_port: u16) -> Vec<PortRemap> {
+        let (removed, kept) = self
+            .remaps
+            .drain(..)
+            .partition(|remap| remap.connect == connect_port);
+        self.remaps = kept;
+        removed
+    }
+
     pub fn remove_manual_port(&mut self, port: u16) -> bool {
         self.manual_ports.remove(&port)
     }
 
-    pub fn set_detected_ports(&mut self, pm2_ports: BTreeSet<u16>, caddy_ports: BTreeSet<u16>) {
-        self.pm2_ports = pm2_ports;
-        self.caddy_ports = caddy_ports;
+    /// Whether a Windows listen port is privileged or likely owned by Windows.
+    pub fn is_reserved(&self, listen_port: u16) -> bool {
+        listen_port < PRIVILEGED_BELOW
+            || match &self.reserved_ports {
+                Some(ports) => ports.contains(&listen_port),
+                None => DEFAULT_RESERVED_PORTS.contains(&listen_port),
+            }
+    }
+
+    pub fn command_timeout(&self) -> Duration {
+        self.command_timeout_secs
+            .map(Duration::from_secs)
+            .unwrap_or(process::DEFAULT_TIMEOUT)
+    }
+
+    pub fn interface(&self) -> Option<&str> {
+        self.runtime
+            .interface
+            .as_deref()
+            .or(self.interface.as_deref())
+    }
+
+    /// Whether `wsl-port pause` is in effect; checked fresh on every call.
+    pub fn is_paused(&self) -> bool {
+        self.runtime.pause_path.as_deref().is_some_and(Path::exists)
+    }
+
+    /// Interfaces from `--exclude-interface` and `exclude_interfaces` combined.
+    pub fn exclude_interfaces(&self) -> Vec<&str> {
+        self.runtime
+            .exclude_interfaces
+            .iter()
+            .chain(&self.exclude_interfaces)
+            .map(String::as_str)
+            .collect()
+    }
+
+    pub fn pre_sync_hook(&self) -> Option<&str> {
+        self.runtime
+            .pre_sync_hook
+            .as_deref()
+            .or(self.pre_sync_hook.as_deref())
+    }
+
+    pub fn post_sync_hook(&self) -> Option<&str> {
+        self.runtime
+            .post_sync_hook
+            .as_deref()
+            .or(self.post_sync_hook.as_deref())
+    }
+
+    pub fn removal_grace_polls(&self) -> u32 {
+        self.removal_grace_polls
+            .unwrap_or(DEFAULT_REMOVAL_GRACE_POLLS)
+    }
+
+    /// How long the daemon keeps retrying the WSL IP lookup at startup.
+    pub fn startup_grace(&self) -> Duration {
+        Duration::from_secs(
+            self.startup_grace_secs
+                .unwrap_or(DEFAULT_STARTUP_GRACE_SECS),
+        )
+    }
+
+    /// Nesting limit when scanning detector JSON for ports.
+    pub fn json_max_depth(&self) -> usize {
+        self.json_max_depth.unwrap_or(DEFAULT_JSON_MAX_DEPTH)
+    }
+
+    pub fn remove_adds_exclude(&self) -> bool {
+        self.remove_adds_exclude.unwrap_or(true)
+    }
+
+    pub fn update_check_url(&self) -> &str {
+        self.update_check_url
+            .as_deref()
+            .unwrap_or(crate::update::DEFAULT_URL)
+    }
+
+    pub fn caddy_timeout(&self) -> Duration {
+        self.runtime
+            .caddy_timeout_ms
+            .or(self.caddy_timeout_ms)
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_CADDY_TIMEOUT)
+    }
+
+    pub fn traefik_api(&self) -> &str {
+        self.traefik_api.as_deref().unwrap_or(DEFAULT_TRAEFIK_API)
+    }
+
+    pub fn dev_server_binaries(&self) -> Vec<&str> {
+        match &self.dev_server_binaries {
+            Some(binaries) => binaries.iter().map(String::as_str).collect(),
+            None => DEFAULT_DEV_SERVER_BINARIES.to_vec(),
+        }
+    }
+
+    /// The rule family for every listen port under the current policy. A port
+    /// listed under two families keeps the first, in family order.
+    pub fn families(&self) -> Families {
+        match self.runtime.family {
+            FamilyPolicy::Ipv4Only => return Families::default(),
+            FamilyPolicy::Ipv6Only => {
+                return Families {
+                    by_port: BTreeMap::new(),
+                    default: RuleFamily::V6ToV6,
+                }
+            }
+            FamilyPolicy::Dual => {}
+        }
+        let mut by_port = BTreeMap::new();
+        for (&family, ports) in &self.port_families {
+            for &port in ports {
+                if let Some(&kept) = by_port.get(&port) {
+                    tracing::warn!(port, %kept, ignored = %family, "port listed under two port_families; keeping the first");
+                    continue;
+                }
+                by_port.insert(port, family);
+            }
+        }
+        Families {
+            by_port,
+            default: RuleFamily::V4ToV4,
+        }
+    }
+
+    pub fn wants_lan_address(&self) -> bool {
+        self.listen_addresses.contains(&ListenAddress::AutoLan)
+    }
+
+    /// Interface aliases in `listen_addresses`, which need a lookup every sync.
+    pub fn listen_interfaces(&self) -> impl Iterator<Item = &str> {
+        self.listen_addresses
+            .iter()
+            .filter_map(|address| match address {
+                ListenAddress::Interface(name) => Some(name.as_str()),
+                _ => None,
+            })
+    }
+
+    /// Windows-side addresses to listen on, with `auto-lan` replaced by `lan`
+    /// and interface names by their entry in `interfaces`; all interfaces when
+    /// none are configured.
+    pub fn listen_addresses(
+        &self,
+        lan: Option<Ipv4Addr>,
+        interfaces: &BTreeMap<String, Ipv4Addr>,
+    ) -> Vec<Ipv4Addr> {
+        if self.listen_addresses.is_empty() {
+            return vec![Ipv4Addr::UNSPECIFIED];
+        }
+        let unique: BTreeSet<Ipv4Addr> = self
+            .listen_addresses
+            .iter()
+            .filter_map(|address| match address {
+                ListenAddress::Ip(ip) => Some(*ip),
+                ListenAddress::AutoLan => lan,
+                ListenAddress::Interface(name) => interfaces.get(name).copied(),
+            })
+            .collect();
+        unique.into_iter().collect()
+    }
+
+    pub fn set_detected_ports(&mut self, detection: Detection) {
+        self.pm2_ports = detection.pm2;
+        self.caddy_ports = detection.caddy;
+        self.listener_ports = detection.listeners;
+        self.dotenv_ports = detection.dotenv;
+        self.traefik_ports = detection.traefik;
+        self.dev_server_ports = detection.dev_servers;
+        self.docker_ports = detection.docker;
+        self.compose_ports = detection.compose;
+        self.remote_ports = detection.remote;
     }
 }
 
-pub fn config_dir() -> Result<PathBuf> {
-    let base = dirs::config_dir().context("could not resolve config directory")?;
-    Ok(base.join("wsl-port-forwarder"))
+/// The sentinel next to the config file that keeps the daemon from syncing.
+pub fn pause_path(config_dir: &Path) -> PathBuf {
+    config_dir.join("paused")
 }
 
-pub fn config_path() -> Result<PathBuf> {
-    Ok(config_dir()?.join("ports.toml"))
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
 }
 
-pub fn load_or_default(path: &Path) -> Result<PortsConfig> {
+const DIR_NAME: &str = "wsl-port";
+const LEGACY_DIR_NAME: &str = "wsl-port-forwarder";
+
+/// Resolves the config directory: an explicit override (flag or
+/// `WSL_PORT_CONFIG_DIR`) wins, otherwise `$XDG_CONFIG_HOME/wsl-port`, falling
+/// back to the legacy `wsl-port-forwarder` folder when only that one exists.
+pub fn config_dir(override_dir: Option<&Path>) -> Result<PathBuf, BridgeError> {
+    if let Some(dir) = override_dir {
+        return Ok(dir.to_path_buf());
+    }
+
+    let base = dirs::config_dir().ok_or(BridgeError::ConfigDirUnavailable)?;
+    let dir = base.join(DIR_NAME);
+    let legacy = base.join(LEGACY_DIR_NAME);
+    if !dir.exists() && legacy.exists() {
+        tracing::debug!(path = %legacy.display(), "using legacy config directory");
+        return Ok(legacy);
+    }
+    Ok(dir)
+}
+
+/// On-disk config syntax, picked from the file extension.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConfigFormat {
+    #[default]
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    const ALL: [ConfigFormat; 2] = [ConfigFormat::Toml, ConfigFormat::Json];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ConfigFormat::Toml => "toml",
+            ConfigFormat::Json => "json",
+        }
+    }
+
+    /// `.json` selects JSON; anything else is TOML.
+    pub fn of(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Toml,
+        }
+    }
+}
+
+impl FromStr for ConfigFormat {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "toml" => Ok(ConfigFormat::Toml),
+            "json" => Ok(ConfigFormat::Json),
+            _ => Err(format!(
+                "unknown config format `{input}`; expected toml or json"
+            )),
+        }
+    }
+}
+
+/// `ports.<ext>` in the config directory. Without an explicit `format`, the
+/// first of `ports.toml`, `ports.json` that exists wins, and a fresh setup gets
+/// `ports.toml`.
+pub fn config_path(
+    override_dir: Option<&Path>,
+    format: Option<ConfigFormat>,
+) -> Result<PathBuf, BridgeError> {
+    let dir = config_dir(override_dir)?;
+    let path_for = |format: ConfigFormat| dir.join(format!("ports.{}", format.extension()));
+    if let Some(format) = format {
+        return Ok(path_for(format));
+    }
+    if let Some(path) = ConfigFormat::ALL
+        .into_iter()
+        .map(path_for)
+        .find(|path| path.exists())
+    {
+        return Ok(path);
+    }
+    Ok(path_for(ConfigFormat::Toml))
+}
+
+/// Merges every `ports.d/*.toml` next to `path`, in file-name order.
+pub fn load_fragments(path: &Path) -> Result<Fragment, BridgeError> {
+    let mut merged = Fragment::default();
+    let Some(dir) = path.parent().map(|parent| parent.join("ports.d")) else {
+        return Ok(merged);
+    };
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(merged),
+        Err(source) => return Err(BridgeError::ConfigRead { path: dir, source }),
+    };
+
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+        .collect();
+    paths.sort();
+
+    for path in paths {
+        let raw = fs::read_to_string(&path).map_err(|source| BridgeError::ConfigRead {
+            path: path.clone(),
+            source,
+        })?;
+        let fragment: Fragment =
+            toml::from_str(&raw).map_err(|source| BridgeError::ConfigParse { path, source })?;
+        merged.merge(fragment);
+    }
+    Ok(merged)
+}
+
+/// Ports listed in `include_file`, one port or `start-end` range per line; blank
+/// lines and `#` comments are ignored. A missing file or bad line is only warned
+/// about, so a half-written file from another tool never blocks a sync.
+pub fn load_include_file(config_path: &Path, include: &Path) -> Result<BTreeSet<u16>, BridgeError> {
+    let path = match config_path.parent() {
+        Some(dir) => dir.join(include),
+        None => include.to_path_buf(),
+    };
+    let raw = match fs::read_to_string(&path) {
+        Ok(raw) => raw,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            tracing::warn!(path = %path.display(), "include_file not found; skipping it");
+            return Ok(BTreeSet::new());
+        }
+        Err(source) => return Err(BridgeError::ConfigRead { path, source }),
+    };
+
+    let mut ports = BTreeSet::new();
+    for (index, line) in raw.lines().enumerate() {
+        let entry = line.split('#').next().unwrap_or_default().trim();
+        if entry.is_empty() {
+            continue;
+        }
+        match entry.parse::<PortRange>() {
+            Ok(range) => ports.extend(range.ports()),
+            Err(reason) => tracing::warn!(
+                path = %path.display(),
+                line = index + 1,
+                %reason,
+                "skipping bad include_file line"
+            ),
+        }
+    }
+    Ok(ports)
+}
+
+pub fn load_or_default(path: &Path) -> Result<PortsConfig, BridgeError> {
     if !path.exists() {
         return Ok(PortsConfig::default());
     }
 
-    let raw = fs::read_to_string(path)
-        .with_context(|| format!("failed reading config from {}", path.display()))?;
-    let cfg: PortsConfig = toml::from_str(&raw)
-        .with_context(|| format!("failed parsing toml from {}", path.display()))?;
+    let raw = fs::read_to_string(path).map_err(|source| BridgeError::ConfigRead {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let cfg: PortsConfig = match ConfigFormat::of(path) {
+        ConfigFormat::Toml => toml::from_str(&raw).map_err(|source| BridgeError::ConfigParse {
+            path: path.to_path_buf(),
+            source,
+        })?,
+        ConfigFormat::Json => {
+            serde_json::from_str(&raw).map_err(|source| BridgeError::ConfigParseJson {
+                path: path.to_path_buf(),
+                source,
+            })?
+        }
+    };
+    if let (Some(min), Some(max)) = (cfg.min_port, cfg.max_port) {
+        if min > max {
+            return Err(BridgeError::InvalidPortBounds {
+                path: path.to_path_buf(),
+                min,
+                max,
+            });
+        }
+    }
     Ok(cfg)
 }
 
-pub fn save(path: &Path, cfg: &PortsConfig) -> Result<()> {
+pub fn save(path: &Path, cfg: &PortsConfig) -> Result<(), BridgeError> {
     if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent)
-            .with_context(|| format!("failed creating config dir {}", parent.display()))?;
+        create_private_dir(parent).map_err(|source| BridgeError::ConfigWrite {
+            path: parent.to_path_buf(),
+            source,
+        })?;
+    }
+
+    let raw = match ConfigFormat::of(path) {
+        ConfigFormat::Toml => {
+            let fresh = toml::to_string_pretty(cfg)?;
+            match fs::read_to_string(path) {
+                Ok(existing) => preserve_annotations(&existing, &fresh).unwrap_or(fresh),
+                Err(_) => fresh,
+            }
+        }
+        ConfigFormat::Json => {
+            let mut raw =
+                serde_json::to_string_pretty(cfg).map_err(BridgeError::ConfigSerializeJson)?;
+            raw.push('\n');
+            raw
+        }
+    };
+    // Leave the file (and its mtime) alone when nothing changed.
+    if fs::read_to_string(path).is_ok_and(|existing| existing == raw) {
+        return Ok(());
     }
+    write_atomic(path, raw.as_bytes())
+}
+
+/// Re-applies `fresh` on top of the user's existing document so hand-written
+/// comments and formatting survive for any value that didn't change.
+fn preserve_annotations(existing: &str, fresh: &str) -> Option<String> {
+    let mut doc: DocumentMut = existing.parse().ok()?;
+    let fresh: DocumentMut = fresh.parse().ok()?;
+    merge_table(doc.as_table_mut(), fresh.as_table());
+    Some(doc.to_string())
+}
 
-    let raw = toml::to_string_pretty(cfg).context("failed serializing config")?;
-    fs::write(path, raw).with_context(|| format!("failed writing config {}", path.display()))?;
+fn merge_table(target: &mut Table, fresh: &Table) {
+    let stale: Vec<String> = target
+        .iter()
+        .map(|(key, _)| key.to_string())
+        .filter(|key| !fresh.contains_key(key))
+        .collect();
+    for key in stale {
+        target.remove(&key);
+    }
+
+    for (key, item) in fresh.iter() {
+        match (target.get_mut(key), item) {
+            (Some(Item::Table(existing)), Item::Table(new)) => merge_table(existing, new),
+            (Some(Item::Value(existing)), Item::Value(new)) => {
+                if !same_value(existing, new) {
+                    let decor = existing.decor().clone();
+                    *existing = new.clone();
+                    *existing.decor_mut() = decor;
+                }
+            }
+            _ => {
+                target.insert(key, item.clone());
+            }
+        }
+    }
+}
+
+fn same_value(a: &toml_edit::Value, b: &toml_edit::Value) -> bool {
+    let parse = |v: &toml_edit::Value| {
+        toml::from_str::<toml::Table>(&format!("v = {}", v.clone().decorated("", ""))).ok()
+    };
+    parse(a) == parse(b)
+}
+
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), BridgeError> {
+    let write_err = |path: &Path| {
+        let path = path.to_path_buf();
+        move |source| BridgeError::ConfigWrite { path, source }
+    };
+
+    let tmp_path = sibling_path(path, "tmp");
+    let mut file = create_private_file(&tmp_path).map_err(write_err(&tmp_path))?;
+    file.write_all(contents)
+        .and_then(|_| file.sync_all())
+        .map_err(write_err(&tmp_path))?;
+
+    if path.exists() {
+        let bak_path = sibling_path(path, "bak");
+        fs::copy(path, &bak_path)
+            .and_then(|_| restrict_to_owner(&bak_path))
+            .map_err(write_err(&bak_path))?;
+    }
+
+    fs::rename(&tmp_path, path).map_err(write_err(path))?;
+    Ok(())
+}
+
+/// Creates missing config directories as `0700`; existing ones keep their mode.
+pub fn create_private_dir(dir: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(dir)
+}
+
+/// Open options that create files as `0600`; the mode is ignored for existing files.
+pub fn private_open_options() -> fs::OpenOptions {
+    let mut options = fs::OpenOptions::new();
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+}
+
+/// Truncates or creates `path` readable by its owner only, so a file renamed
+/// over the config ends up `0600` even if an older copy was world-readable.
+pub fn create_private_file(path: &Path) -> io::Result<fs::File> {
+    let file = private_open_options()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    // `mode` only applies to new files; a leftover .tmp keeps its old one.
+    restrict_to_owner(path)?;
+    Ok(file)
+}
+
+/// `fs::copy` carries the source's mode over, which may be wider than `0600`.
+fn restrict_to_owner(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
     Ok(())
 }
+
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A fresh, empty directory under the system temp dir.
+    fn scratch_dir() -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "wsl-port-config-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn sample() -> PortsConfig {
+        let mut cfg = PortsConfig::default();
+        cfg.add_manual_port(3000);
+        cfg.add_manual_port(5173);
+        cfg.add_remap("8080:3000".parse().unwrap());
+        cfg.pm2_ports.insert(4000);
+        cfg.detect_listeners = true;
+        cfg.traefik_api = Some("http://localhost:9000".to_string());
+        cfg
+    }
+
+    fn round_trip(format: ConfigFormat) {
+        let dir = scratch_dir();
+        let path = config_path(Some(&dir), Some(format)).unwrap();
+        assert_eq!(ConfigFormat::of(&path), format);
+
+        let cfg = sample();
+        save(&path, &cfg).unwrap();
+        let loaded = load_or_default(&path).unwrap();
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&cfg).unwrap()
+        );
+        // With no explicit format, the file just written is the one picked.
+        assert_eq!(config_path(Some(&dir), None).unwrap(), path);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn toml_round_trips() {
+        round_trip(ConfigFormat::Toml);
+    }
+
+    #[test]
+    fn json_round_trips() {
+        round_trip(ConfigFormat::Json);
+    }
+
+    #[test]
+    fn exclude_ports_only_silences_detection_but_exclude_all_wins_everywhere() {
+        #[derive(Clone, Copy)]
+        enum Exclude {
+            None,
+            Detected,
+            All,
+        }
+        // (manual, detected, exclude, forwarded)
+        let cases = [
+            (true, false, Exclude::None, true),
+            (true, false, Exclude::Detected, true),
+            (true, false, Exclude::All, false),
+            (false, true, Exclude::None, true),
+            (false, true, Exclude::Detected, false),
+            (false, true, Exclude::All, false),
+            (true, true, Exclude::None, true),
+            (true, true, Exclude::Detected, true),
+            (true, true, Exclude::All, false),
+        ];
+        for (manual, detected, exclude, forwarded) in cases {
+            let mut cfg = PortsConfig::default();
+            if manual {
+                cfg.manual_ports.insert(3000);
+            }
+            if detected {
+                cfg.pm2_ports.insert(3000);
+            }
+            match exclude {
+                Exclude::None => {}
+                Exclude::Detected => {
+                    cfg.exclude_ports.insert(3000);
+                }
+                Exclude::All => {
+                    cfg.exclude_all.insert(3000);
+                }
+            }
+            assert_eq!(
+                cfg.all_ports().contains(&3000),
+                forwarded,
+                "manual={manual} detected={detected}"
+            );
+        }
+    }
+
+    #[test]
+    fn exclude_all_drops_remaps_too() {
+        let mut cfg = PortsConfig::default();
+        cfg.add_remap("8080:3000".parse().unwrap());
+        cfg.exclude_ports.insert(8080);
+        assert!(cfg.all_ports().contains(&8080));
+        cfg.exclude_all.insert(8080);
+        assert!(cfg.all_ports().is_empty());
+    }
+
+    #[test]
+    fn exclude_ranges_spare_manual_ranges_unless_they_exclude_everywhere() {
+        let raw = r#"
+manual_ranges = ["40000-40010"]
+exclude_ranges = ["40000-60999"]
+pm2_ports = [3000, 40005, 50000]
+"#;
+        let mut cfg: PortsConfig = toml::from_str(raw).unwrap();
+        let mut expected: BTreeSet<u16> = (40000..=40010).collect();
+        expected.insert(3000);
+        assert_eq!(cfg.all_ports(), expected);
+
+        cfg.exclude_all_ranges = vec!["40008-40100".parse().unwrap()];
+        expected.retain(|&port| !(40008..=40100).contains(&port));
+        assert_eq!(cfg.all_ports(), expected);
+    }
+
+    #[test]
+    fn invalid_exclude_ranges_fail_to_load() {
+        let dir = scratch_dir();
+        let path = dir.join("ports.toml");
+        for range in ["60999-40000", "0-100", "40000-70000", "ephemeral"] {
+            fs::write(&path, format!("exclude_ranges = [\"{range}\"]\n")).unwrap();
+            assert!(load_or_default(&path).is_err(), "{range} loaded");
+        }
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn remap_shadows_flag_detected_ports_on_either_side_of_a_remap() {
+        let remap: PortRemap = "8080:3000".parse().unwrap();
+        let shadows = |detected: &[u16]| {
+            let mut cfg = PortsConfig::default();
+            cfg.add_remap(remap);
+            cfg.add_remap("5173:5173".parse().unwrap());
+            cfg.pm2_ports.extend(detected);
+            cfg.remap_shadows()
+        };
+        assert_eq!(shadows(&[4000]), []);
+        assert_eq!(shadows(&[3000]), [RemapShadow::ConnectDetected(remap)]);
+        assert_eq!(shadows(&[8080]), [RemapShadow::ListenDetected(remap)]);
+        assert_eq!(
+            shadows(&[3000, 8080]),
+            [
+                RemapShadow::ConnectDetected(remap),
+                RemapShadow::ListenDetected(remap)
+            ]
+        );
+        // An identity remap matching a detected port is just a duplicate.
+        assert_eq!(shadows(&[5173]), []);
+    }
+
+    #[test]
+    fn excluded_detections_do_not_shadow_remaps() {
+        let mut cfg = PortsConfig::default();
+        cfg.add_remap("8080:3000".parse().unwrap());
+        cfg.pm2_ports.insert(3000);
+        cfg.exclude_ports.insert(3000);
+        assert_eq!(cfg.remap_shadows(), []);
+    }
+
+    fn remapped() -> PortsConfig {
+        let mut cfg = PortsConfig::default();
+        cfg.add_manual_port(3000);
+        cfg.add_remap("8080:3000".parse().unwrap());
+        cfg.add_remap("8443:3000".parse().unwrap());
+        cfg.add_remap("9000:9001".parse().unwrap());
+        cfg
+    }
+
+    #[test]
+    fn remove_remap_goes_by_listen_port() {
+        let mut cfg = remapped();
+        assert_eq!(cfg.remove_remap(3000), None);
+        assert_eq!(cfg.remove_remap(8080), Some("8080:3000".parse().unwrap()));
+        assert_eq!(cfg.all_ports(), BTreeSet::from([3000, 8443, 9000]));
+    }
+
+    #[test]
+    fn remove_by_connect_drops_every_remap_onto_that_port() {
+        let mut cfg = remapped();
+        let removed: Vec<String> = cfg
+            .remove_remaps_by_connect(3000)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(removed, ["8080:3000", "8443:3000"]);
+        // The manual 3000 is not a remap and stays; the rules to delete are
+        // the removed remaps' listen ports.
+        assert_eq!(cfg.all_ports(), BTreeSet::from([3000, 9000]));
+        assert!(cfg.remove_remaps_by_connect(9000).is_empty());
+    }
+
+    #[test]
+    fn config_path_prefers_toml_then_json() {
+        let dir = scratch_dir();
+        assert_eq!(
+            config_path(Some(&dir), None).unwrap(),
+            dir.join("ports.toml")
+        );
+        fs::write(dir.join("ports.json"), "{}").unwrap();
+        assert_eq!(
+            config_path(Some(&dir), None).unwrap(),
+            dir.join("ports.json")
+        );
+        fs::write(dir.join("ports.toml"), "").unwrap();
+        assert_eq!(
+            config_path(Some(&dir), None).unwrap(),
+            dir.join("ports.toml")
+        );
+        assert_eq!(
+            config_path(Some(&dir), Some(ConfigFormat::Json)).unwrap(),
+            dir.join("ports.json")
+        );
+        assert!("yaml".parse::<ConfigFormat>().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_file_loads_defaults() {
+        let dir = scratch_dir();
+        let cfg = load_or_default(&dir.join("ports.toml")).unwrap();
+        assert!(cfg.manual_ports.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,26 +1,686 @@
+use crate::config::PortsConfig;
+use crate::network;
+use crate::ports::{PortFilter, PortSource};
+use crate::process;
 use anyhow::{Context, Result};
 use serde_json::Value;
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tokio::process::Command;
 
-pub async fn detect_ports() -> (BTreeSet<u16>, BTreeSet<u16>) {
-    let pm2_ports = detect_pm2_ports().await.unwrap_or_else(|err| {
-        tracing::debug!(error = %err, "pm2 detection failed");
+#[derive(Debug, Default)]
+pub struct Detection {
+    pub pm2: BTreeSet<u16>,
+    pub caddy: BTreeSet<u16>,
+    /// Every listening TCP port in WSL; only collected with `detect_listeners`.
+    pub listeners: BTreeSet<u16>,
+    /// `PORT`/`*_PORT` values from the configured dotenv files.
+    pub dotenv: BTreeSet<u16>,
+    /// Traefik entrypoint ports; only collected with `detect_traefik`.
+    pub traefik: BTreeSet<u16>,
+    /// `--port` arguments of running dev servers; only with `detect_dev_servers`.
+    pub dev_servers: BTreeSet<u16>,
+    /// Host ports published by running containers; only with `detect_docker`.
+    pub docker: BTreeSet<u16>,
+    /// Host ports published by the compose projects in `compose_dirs`.
+    pub compose: BTreeSet<u16>,
+    /// Ports another machine reports at `remote_ports_url`.
+    pub remote: BTreeSet<u16>,
+}
+
+/// How one detector fared on the last scan.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DetectorStatus {
+    Succeeded,
+    /// The detector ran but errored; its ports are whatever it could still find (often none).
+    Failed(String),
+    /// Turned off in the config, so it never ran.
+    Disabled,
+}
+
+impl DetectorStatus {
+    fn from_error(error: Option<String>) -> Self {
+        error.map_or(DetectorStatus::Succeeded, DetectorStatus::Failed)
+    }
+}
+
+/// Per-source outcome of a scan, keyed like `port_sources`.
+pub type DetectionReport = BTreeMap<PortSource, DetectorStatus>;
+
+/// Runs every detector, noting whether each succeeded, failed, or was disabled.
+pub async fn detect_ports_reported(cfg: &PortsConfig) -> (Detection, DetectionReport) {
+    let scan = JsonScan {
+        max_depth: cfg.json_max_depth(),
+        keep_loopback: cfg.forward_localhost_only,
+    };
+    let mut report = DetectionReport::new();
+
+    let mut pm2_error = None;
+    let mut pm2_ports = detect_pm2_ports(cfg.command_timeout(), scan)
+        .await
+        .unwrap_or_else(|err| {
+            tracing::debug!(error = %err, "pm2 detection failed");
+            pm2_error = Some(brief_error(&err));
+            BTreeSet::new()
+        });
+    for path in &cfg.pm2_ecosystem_files {
+        match detect_ecosystem_ports(path, cfg.command_timeout()).await {
+            Ok(ports) => pm2_ports.extend(ports),
+            Err(err) => {
+                tracing::debug!(error = %err, path = %path.display(), "pm2 ecosystem detection failed");
+                pm2_error
+                    .get_or_insert_with(|| format!("{}: {}", path.display(), brief_error(&err)));
+            }
+        }
+    }
+    report.insert(PortSource::Pm2, DetectorStatus::from_error(pm2_error));
+
+    let (caddy_ports, caddy_error) = match detect_caddy_ports(cfg.caddy_timeout(), scan).await {
+        Ok(ports) => (ports, None),
+        Err(err) => {
+            tracing::debug!(error = %err, "caddy detection failed");
+            match cfg.caddyfile.as_deref() {
+                Some(path) => match detect_caddyfile_ports(path, cfg.command_timeout(), scan).await
+                {
+                    Ok(ports) => (ports, None),
+                    Err(file_err) => {
+                        tracing::debug!(error = %file_err, "caddyfile detection failed");
+                        (
+                            BTreeSet::new(),
+                            Some(format!("{}; {}", brief_error(&err), brief_error(&file_err))),
+                        )
+                    }
+                },
+                None => (BTreeSet::new(), Some(brief_error(&err))),
+            }
+        }
+    };
+    report.insert(PortSource::Caddy, DetectorStatus::from_error(caddy_error));
+
+    let traefik_ports = if cfg.detect_traefik {
+        match detect_traefik_ports(cfg.traefik_api(), cfg.caddy_timeout(), scan).await {
+            Ok(ports) => {
+                report.insert(PortSource::Traefik, DetectorStatus::Succeeded);
+                ports
+            }
+            Err(err) => {
+                tracing::debug!(error = %err, "traefik detection failed");
+                report.insert(
+                    PortSource::Traefik,
+                    DetectorStatus::Failed(brief_error(&err)),
+                );
+                BTreeSet::new()
+            }
+        }
+    } else {
+        report.insert(PortSource::Traefik, DetectorStatus::Disabled);
         BTreeSet::new()
-    });
+    };
 
-    let caddy_ports = detect_caddy_ports().await.unwrap_or_else(|err| {
-        tracing::debug!(error = %err, "caddy detection failed");
+    let dev_server_ports = if cfg.detect_dev_servers {
+        report.insert(PortSource::DevServer, DetectorStatus::Succeeded);
+        detect_dev_server_ports(&cfg.dev_server_binaries())
+    } else {
+        report.insert(PortSource::DevServer, DetectorStatus::Disabled);
         BTreeSet::new()
-    });
+    };
 
-    (pm2_ports, caddy_ports)
+    let docker_ports = if cfg.detect_docker {
+        match detect_docker_ports(cfg.command_timeout(), scan.keep_loopback).await {
+            Ok(ports) => {
+                report.insert(PortSource::Docker, DetectorStatus::Succeeded);
+                ports
+            }
+            Err(err) => {
+                tracing::debug!(error = %err, "docker detection failed");
+                report.insert(
+                    PortSource::Docker,
+                    DetectorStatus::Failed(brief_error(&err)),
+                );
+                BTreeSet::new()
+            }
+        }
+    } else {
+        report.insert(PortSource::Docker, DetectorStatus::Disabled);
+        BTreeSet::new()
+    };
+
+    let remote_ports = match cfg.remote_ports_url.as_deref() {
+        Some(url) => match detect_remote_ports(url, cfg.caddy_timeout()).await {
+            Ok(ports) => {
+                report.insert(PortSource::Remote, DetectorStatus::Succeeded);
+                ports
+            }
+            Err(err) => {
+                tracing::debug!(error = %err, "remote port detection failed");
+                report.insert(
+                    PortSource::Remote,
+                    DetectorStatus::Failed(brief_error(&err)),
+                );
+                BTreeSet::new()
+            }
+        },
+        None => {
+            report.insert(PortSource::Remote, DetectorStatus::Disabled);
+            BTreeSet::new()
+        }
+    };
+
+    let compose_ports = if cfg.compose_dirs.is_empty() {
+        report.insert(PortSource::Compose, DetectorStatus::Disabled);
+        BTreeSet::new()
+    } else {
+        let mut ports = BTreeSet::new();
+        let mut error = None;
+        for dir in &cfg.compose_dirs {
+            match detect_compose_ports(dir, cfg.command_timeout(), scan.keep_loopback).await {
+                Ok(found) => ports.extend(found),
+                Err(err) => {
+                    tracing::debug!(error = %err, dir = %dir.display(), "compose detection failed");
+                    error
+                        .get_or_insert_with(|| format!("{}: {}", dir.display(), brief_error(&err)));
+                }
+            }
+        }
+        report.insert(PortSource::Compose, DetectorStatus::from_error(error));
+        ports
+    };
+
+    let listeners = if cfg.detect_listeners {
+        report.insert(PortSource::Listener, DetectorStatus::Succeeded);
+        listening_processes(cfg.command_timeout())
+            .await
+            .into_keys()
+            .collect()
+    } else {
+        report.insert(PortSource::Listener, DetectorStatus::Disabled);
+        BTreeSet::new()
+    };
+
+    let dotenv_ports = if cfg.dotenv_files.is_empty() {
+        report.insert(PortSource::Dotenv, DetectorStatus::Disabled);
+        BTreeSet::new()
+    } else {
+        let (ports, error) = detect_dotenv_ports(&cfg.dotenv_files);
+        report.insert(PortSource::Dotenv, DetectorStatus::from_error(error));
+        ports
+    };
+
+    let detection = Detection {
+        pm2: apply_filter(pm2_ports, cfg.pm2_port_filter.as_ref()),
+        caddy: apply_filter(caddy_ports, cfg.caddy_port_filter.as_ref()),
+        listeners: apply_filter(listeners, cfg.listener_port_filter.as_ref()),
+        dotenv: apply_filter(dotenv_ports, cfg.dotenv_port_filter.as_ref()),
+        traefik: apply_filter(traefik_ports, cfg.traefik_port_filter.as_ref()),
+        dev_servers: apply_filter(dev_server_ports, cfg.dev_server_port_filter.as_ref()),
+        docker: apply_filter(docker_ports, cfg.docker_port_filter.as_ref()),
+        compose: apply_filter(compose_ports, cfg.compose_port_filter.as_ref()),
+        remote: apply_filter(remote_ports, cfg.remote_port_filter.as_ref()),
+    };
+    (detection, report)
 }
 
-async fn detect_pm2_ports() -> Result<BTreeSet<u16>> {
-    let output = Command::new("pm2")
-        .arg("jlist")
-        .output()
+/// The outermost context and the root cause, e.g. "failed requesting caddy
+/// config: Connection refused (os error 111)", skipping the middle layers.
+fn brief_error(err: &anyhow::Error) -> String {
+    let root = err.root_cause().to_string();
+    let top = err.to_string();
+    if top == root {
+        top
+    } else {
+        format!("{top}: {root}")
+    }
+}
+
+fn apply_filter(mut ports: BTreeSet<u16>, filter: Option<&PortFilter>) -> BTreeSet<u16> {
+    if let Some(filter) = filter {
+        ports.retain(|&port| filter.allows(port));
+    }
+    ports
+}
+
+/// Ports from every readable file, plus the first read error, if any.
+fn detect_dotenv_ports(paths: &[PathBuf]) -> (BTreeSet<u16>, Option<String>) {
+    let mut ports = BTreeSet::new();
+    let mut error = None;
+    for path in paths {
+        match fs::read_to_string(path) {
+            Ok(raw) => ports.extend(parse_dotenv_ports(&raw)),
+            Err(err) => {
+                tracing::debug!(error = %err, path = %path.display(), "dotenv detection failed");
+                error.get_or_insert_with(|| format!("{}: {err}", path.display()));
+            }
+        }
+    }
+    (ports, error)
+}
+
+/// Ports from lines like `PORT=3000` or `export API_PORT="8080"`, skipping
+/// comments and values that aren't a plain port number.
+fn parse_dotenv_ports(raw: &str) -> impl Iterator<Item = u16> + '_ {
+    raw.lines().filter_map(|line| {
+        let line = line.trim();
+        if line.starts_with('#') {
+            return None;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line.split_once('=')?;
+        if !is_port_env_key(key.trim()) {
+            return None;
+        }
+        let value = value.split(" #").next().unwrap_or_default().trim();
+        let value = value.trim_matches(|c| c == '"' || c == '\'');
+        value.parse::<u64>().ok().and_then(to_valid_port)
+    })
+}
+
+/// `PORT`/`*_PORT` values from every `env*` block of a pm2 ecosystem file, so
+/// apps count before they start. `.json` is read directly; a `.js`/`.cjs`
+/// file is evaluated by `node` and its export dumped as JSON.
+async fn detect_ecosystem_ports(path: &Path, timeout: Duration) -> Result<BTreeSet<u16>> {
+    let value: Value = if path.extension().is_some_and(|ext| ext == "json") {
+        let raw = fs::read_to_string(path)
+            .with_context(|| format!("failed reading {}", path.display()))?;
+        serde_json::from_str(&raw).context("invalid ecosystem json")?
+    } else {
+        let path = fs::canonicalize(path)
+            .with_context(|| format!("failed resolving {}", path.display()))?;
+        let mut command = Command::new("node");
+        command
+            .arg("-e")
+            .arg("console.log(JSON.stringify(require(process.argv[1])))")
+            .arg(&path);
+        let output = process::output_with_timeout(&mut command, timeout).await?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "node failed to load {} ({}): {}",
+                path.display(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        serde_json::from_slice(&output.stdout).context("invalid ecosystem export")?
+    };
+
+    let apps = value
+        .get("apps")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten();
+    let envs = apps
+        .filter_map(Value::as_object)
+        .flat_map(|app| app.iter())
+        .filter(|(key, _)| key.starts_with("env"))
+        .filter_map(|(_, env)| env.as_object());
+    let mut ports = BTreeSet::new();
+    for (key, value) in envs.flatten() {
+        if !is_port_env_key(key) {
+            continue;
+        }
+        let port = match value {
+            Value::Number(n) => n.as_u64(),
+            Value::String(s) => s.trim().parse().ok(),
+            _ => None,
+        };
+        ports.extend(port.and_then(to_valid_port));
+    }
+    Ok(ports)
+}
+
+/// `--port N` / `--port=N` from the command line of every running process that
+/// is one of `binaries`, started directly (`vite`) or as a script (`node
+/// node_modules/vite/bin/vite.js`).
+fn detect_dev_server_ports(binaries: &[&str]) -> BTreeSet<u16> {
+    let entries = match fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(err) => {
+            tracing::debug!(error = %err, "dev server detection failed");
+            return BTreeSet::new();
+        }
+    };
+
+    let mut ports = BTreeSet::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        if !name.to_string_lossy().bytes().all(|b| b.is_ascii_digit()) {
+            continue;
+        }
+        // Processes can exit between listing and reading; skip them quietly.
+        let Ok(raw) = fs::read(entry.path().join("cmdline")) else {
+            continue;
+        };
+        let raw = String::from_utf8_lossy(&raw);
+        let args: Vec<&str> = raw.split('\0').filter(|arg| !arg.is_empty()).collect();
+        if !args.iter().any(|arg| is_dev_server(arg, binaries)) {
+            continue;
+        }
+        ports.extend(parse_port_args(&args));
+    }
+    ports
+}
+
+fn is_dev_server(arg: &str, binaries: &[&str]) -> bool {
+    let file = Path::new(arg)
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let stem = ["js", "cjs", "mjs"]
+        .iter()
+        .find_map(|ext| file.strip_suffix(&format!(".{ext}")))
+        .unwrap_or(&file);
+    binaries.contains(&stem)
+}
+
+fn parse_port_args<'a>(args: &'a [&'a str]) -> impl Iterator<Item = u16> + 'a {
+    args.iter().enumerate().filter_map(|(i, arg)| {
+        let value = match arg.strip_prefix("--port") {
+            Some("") => *args.get(i + 1)?,
+            Some(rest) => rest.strip_prefix('=')?,
+            None => return None,
+        };
+        value.parse::<u64>().ok().and_then(to_valid_port)
+    })
+}
+
+/// Listening TCP ports in WSL with the owning process name when `ss` can see it.
+/// Falls back to `lsof`, then to `/proc/net/tcp` (ports only), if `ss` isn't
+/// installed or is restricted.
+pub async fn listening_processes(timeout: Duration) -> BTreeMap<u16, Option<String>> {
+    let mut command = Command::new("ss");
+    command.arg("-Htlnp");
+    match process::output_with_timeout(&mut command, timeout).await {
+        Ok(output) if output.status.success() => {
+            return parse_ss_listeners(&String::from_utf8_lossy(&output.stdout));
+        }
+        Ok(output) => tracing::debug!(status = %output.status, "ss -Htlnp failed"),
+        Err(err) => tracing::debug!(error = %err, "ss unavailable"),
+    }
+
+    if let Some(listeners) = lsof_listeners(timeout).await {
+        return listeners;
+    }
+
+    match network::listening_ports() {
+        Ok(ports) => ports.into_iter().map(|port| (port, None)).collect(),
+        Err(err) => {
+            tracing::debug!(error = %err, "listener detection failed");
+            BTreeMap::new()
+        }
+    }
+}
+
+/// Host ports published by running containers, named by their compose service
+/// (`com.docker.compose.service`) or, without that label, the container name.
+/// Empty when docker isn't installed or nothing is running.
+pub async fn container_services(timeout: Duration) -> BTreeMap<u16, String> {
+    match inspect_containers(timeout).await {
+        Ok(value) => parse_container_services(&value),
+        Err(err) => {
+            tracing::debug!(error = %err, "docker unavailable");
+            BTreeMap::new()
+        }
+    }
+}
+
+/// Host ports of running containers for forwarding. Docker publishes them on
+/// the WSL side, so the usual rule to the WSL IP reaches them
+/// (Windows -> WSL IP:host port -> container). Ports published only on
+/// loopback (`127.0.0.1:3000:3000`) can't be reached that way and are skipped
+/// unless `keep_loopback`.
+async fn detect_docker_ports(timeout: Duration, keep_loopback: bool) -> Result<BTreeSet<u16>> {
+    let value = inspect_containers(timeout).await?;
+    Ok(parse_docker_ports(&value, keep_loopback))
+}
+
+/// `docker inspect` of every running container, as a JSON array (empty when
+/// nothing runs).
+async fn inspect_containers(timeout: Duration) -> Result<Value> {
+    let output = process::output_with_timeout(Command::new("docker").args(["ps", "-q"]), timeout)
+        .await
+        .context("failed to run docker ps")?;
+    if !output.status.success() {
+        anyhow::bail!("docker ps failed ({})", output.status);
+    }
+    let ids = String::from_utf8_lossy(&output.stdout).to_string();
+    let ids: Vec<&str> = ids.split_whitespace().collect();
+    if ids.is_empty() {
+        return Ok(Value::Array(Vec::new()));
+    }
+
+    let mut command = Command::new("docker");
+    command.arg("inspect").args(&ids);
+    let output = process::output_with_timeout(&mut command, timeout)
+        .await
+        .context("failed to run docker inspect")?;
+    if !output.status.success() {
+        anyhow::bail!("docker inspect failed ({})", output.status);
+    }
+    serde_json::from_slice(&output.stdout).context("invalid docker inspect json")
+}
+
+/// Every published port binding as `(host ip, host port)`; the IP is empty
+/// when docker bound all addresses.
+fn port_bindings(container: &Value) -> impl Iterator<Item = (&str, u16)> {
+    container
+        .pointer("/NetworkSettings/Ports")
+        .and_then(Value::as_object)
+        .into_iter()
+        .flat_map(|ports| ports.values())
+        .filter_map(Value::as_array)
+        .flatten()
+        .filter_map(|binding| {
+            let port = binding
+                .get("HostPort")
+                .and_then(Value::as_str)
+                .and_then(|port| port.parse::<u16>().ok())
+                .filter(|&port| port != 0)?;
+            let ip = binding.get("HostIp").and_then(Value::as_str).unwrap_or("");
+            Some((ip, port))
+        })
+}
+
+fn parse_docker_ports(value: &Value, keep_loopback: bool) -> BTreeSet<u16> {
+    let mut ports = BTreeSet::new();
+    for container in value.as_array().into_iter().flatten() {
+        for (ip, port) in port_bindings(container) {
+            let loopback = ip.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback());
+            if loopback && !keep_loopback {
+                tracing::debug!(
+                    port,
+                    host_ip = ip,
+                    "skipping container port published only on loopback"
+                );
+                continue;
+            }
+            ports.insert(port);
+        }
+    }
+    ports
+}
+
+fn parse_container_services(value: &Value) -> BTreeMap<u16, String> {
+    let mut services = BTreeMap::new();
+    for container in value.as_array().into_iter().flatten() {
+        let label = container
+            .pointer("/Config/Labels/com.docker.compose.service")
+            .and_then(Value::as_str);
+        let name = container
+            .get("Name")
+            .and_then(Value::as_str)
+            .map(|name| name.trim_start_matches('/'));
+        let Some(service) = label.or(name) else {
+            continue;
+        };
+        for (_, port) in port_bindings(container) {
+            services.entry(port).or_insert_with(|| service.to_string());
+        }
+    }
+    services
+}
+
+/// Published TCP host ports of the compose project in `dir`, from
+/// `docker compose ps --format json` (Compose v2 as a docker plugin), falling
+/// back to the standalone `docker-compose` binary. A project with nothing
+/// running is an empty set, not an error.
+async fn detect_compose_ports(
+    dir: &Path,
+    timeout: Duration,
+    keep_loopback: bool,
+) -> Result<BTreeSet<u16>> {
+    if !dir.is_dir() {
+        anyhow::bail!("not a directory");
+    }
+    let mut failures = Vec::new();
+    for (program, prefix) in [("docker", &["compose"][..]), ("docker-compose", &[][..])] {
+        let mut command = Command::new(program);
+        command
+            .args(prefix)
+            .args(["ps", "--format", "json"])
+            .current_dir(dir);
+        match process::output_with_timeout(&mut command, timeout).await {
+            Ok(output) if output.status.success() => {
+                return Ok(parse_compose_ports(
+                    &String::from_utf8_lossy(&output.stdout),
+                    keep_loopback,
+                ));
+            }
+            Ok(output) => failures.push(format!(
+                "{program} ps exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )),
+            Err(err) => failures.push(err.to_string()),
+        }
+    }
+    anyhow::bail!("{}", failures.join("; "))
+}
+
+/// Compose has printed `ps --format json` as one JSON array (before 2.21) and
+/// as one object per line (since); both carry `Publishers` entries like
+/// `{"URL": "0.0.0.0", "PublishedPort": 8088, "Protocol": "tcp"}`.
+fn parse_compose_ports(raw: &str, keep_loopback: bool) -> BTreeSet<u16> {
+    let services: Vec<Value> = match serde_json::from_str::<Value>(raw.trim()) {
+        Ok(Value::Array(services)) => services,
+        _ => raw
+            .lines()
+            .filter_map(|line| serde_json::from_str(line.trim()).ok())
+            .collect(),
+    };
+    let mut ports = BTreeSet::new();
+    for publisher in services
+        .iter()
+        .filter_map(|service| service.get("Publishers").and_then(Value::as_array))
+        .flatten()
+    {
+        if publisher.get("Protocol").and_then(Value::as_str) == Some("udp") {
+            continue;
+        }
+        let Some(port) = publisher
+            .get("PublishedPort")
+            .and_then(Value::as_u64)
+            .and_then(to_valid_port)
+        else {
+            continue;
+        };
+        let url = publisher.get("URL").and_then(Value::as_str).unwrap_or("");
+        if !keep_loopback && url.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback()) {
+            tracing::debug!(
+                port,
+                host_ip = url,
+                "skipping compose port published only on loopback"
+            );
+            continue;
+        }
+        ports.insert(port);
+    }
+    ports
+}
+
+/// Listening TCP ports from `lsof -nP -iTCP -sTCP:LISTEN`, named by command.
+/// Without root it only sees the caller's own processes; `None` when `lsof`
+/// is missing or fails so the caller can fall back further.
+pub async fn lsof_listeners(timeout: Duration) -> Option<BTreeMap<u16, Option<String>>> {
+    let mut command = Command::new("lsof");
+    command.args(["-nP", "-iTCP", "-sTCP:LISTEN", "-Fcn"]);
+    match process::output_with_timeout(&mut command, timeout).await {
+        Ok(output) if output.status.success() => Some(parse_lsof_listeners(
+            &String::from_utf8_lossy(&output.stdout),
+        )),
+        // lsof exits 1 with no output when nothing matched.
+        Ok(output) if output.status.code() == Some(1) && output.stderr.is_empty() => {
+            Some(BTreeMap::new())
+        }
+        Ok(output) => {
+            tracing::debug!(status = %output.status, "lsof -iTCP -sTCP:LISTEN failed");
+            None
+        }
+        Err(err) => {
+            tracing::debug!(error = %err, "lsof unavailable");
+            None
+        }
+    }
+}
+
+/// `-F` output: a `c<command>` line per process, then `n<addr>:<port>` per socket.
+fn parse_lsof_listeners(raw: &str) -> BTreeMap<u16, Option<String>> {
+    let mut listeners = BTreeMap::new();
+    let mut command: Option<&str> = None;
+    for line in raw.lines() {
+        match line.split_at_checked(1) {
+            Some(("p", _)) => command = None,
+            Some(("c", name)) => command = Some(name),
+            Some(("n", name)) => {
+                let Some(port) = name
+                    .rsplit_once(':')
+                    .and_then(|(_, port)| port.parse::<u16>().ok())
+                    .filter(|&p| p != 0)
+                else {
+                    continue;
+                };
+                let entry = listeners.entry(port).or_insert(None);
+                if entry.is_none() {
+                    *entry = command.map(str::to_string);
+                }
+            }
+            _ => {}
+        }
+    }
+    listeners
+}
+
+/// Parses `ss -Htln[p]` lines like
+/// `LISTEN 0 511 0.0.0.0:3000 0.0.0.0:* users:(("node",pid=42,fd=20))`.
+fn parse_ss_listeners(raw: &str) -> BTreeMap<u16, Option<String>> {
+    let mut listeners = BTreeMap::new();
+    for line in raw.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let Some(local) = fields.get(3) else {
+            continue;
+        };
+        let Some(port) = local
+            .rsplit_once(':')
+            .and_then(|(_, port)| port.parse::<u16>().ok())
+            .filter(|&p| p != 0)
+        else {
+            continue;
+        };
+        let process = line
+            .split_once("users:((\"")
+            .and_then(|(_, rest)| rest.split_once('"'))
+            .map(|(name, _)| name.to_string());
+        let entry = listeners.entry(port).or_insert(None);
+        if entry.is_none() {
+            *entry = process;
+        }
+    }
+    listeners
+}
+
+async fn detect_pm2_ports(timeout: Duration, scan: JsonScan) -> Result<BTreeSet<u16>> {
+    let output = process::output_with_timeout(Command::new("pm2").arg("jlist"), timeout)
         .await
         .context("failed to execute pm2 jlist")?;
 
@@ -28,21 +688,76 @@ async fn detect_pm2_ports() -> Result<BTreeSet<u16>> {
         anyhow::bail!("pm2 jlist exited with {}", output.status);
     }
 
-    let value: Value = serde_json::from_slice(&output.stdout).context("invalid pm2 json")?;
+    let value = parse_noisy_json(&output.stdout).context("invalid pm2 json")?;
     let mut ports = BTreeSet::new();
-    collect_ports_from_json(&value, &mut ports);
+    collect_pm2_env_ports(&value, &mut ports);
+    collect_ports_from_json(&value, &mut ports, scan);
     Ok(ports)
 }
 
-async fn detect_caddy_ports() -> Result<BTreeSet<u16>> {
+/// pm2 may print update banners or warnings around the JSON payload, so fall back
+/// to parsing from the first line that opens an array or object.
+fn parse_noisy_json(raw: &[u8]) -> Result<Value> {
+    if let Ok(value) = serde_json::from_slice(raw) {
+        return Ok(value);
+    }
+
+    let text = String::from_utf8_lossy(raw);
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with('[') || trimmed.starts_with('{') {
+            let start = offset + (line.len() - trimmed.len());
+            let mut stream =
+                serde_json::Deserializer::from_str(&text[start..]).into_iter::<Value>();
+            if let Some(Ok(value)) = stream.next() {
+                return Ok(value);
+            }
+        }
+        offset += line.len();
+    }
+
+    anyhow::bail!("no JSON document found in output")
+}
+
+fn collect_pm2_env_ports(value: &Value, out: &mut BTreeSet<u16>) {
+    let Some(processes) = value.as_array() else {
+        return;
+    };
+
+    for process in processes {
+        let Some(env) = process.pointer("/pm2_env/env").and_then(Value::as_object) else {
+            continue;
+        };
+
+        for (key, v) in env {
+            if !is_port_env_key(key) {
+                continue;
+            }
+
+            let port = match v {
+                Value::String(s) => s.trim().parse::<u64>().ok(),
+                Value::Number(n) => n.as_u64(),
+                _ => None,
+            };
+            if let Some(p) = port.and_then(to_valid_port) {
+                out.insert(p);
+            }
+        }
+    }
+}
+
+fn is_port_env_key(key: &str) -> bool {
+    key.eq_ignore_ascii_case("port") || key.to_ascii_uppercase().ends_with("_PORT")
+}
+
+async fn detect_caddy_ports(timeout: Duration, scan: JsonScan) -> Result<BTreeSet<u16>> {
     let client = reqwest::Client::builder()
-        .timeout(std::time::Duration::from_secs(3))
+        .timeout(timeout)
         .build()
         .context("failed to build reqwest client")?;
 
-    let value: Value = client
-        .get("http://localhost:2019/config/")
-        .send()
+    let value: Value = process::http_get(&client, "http://localhost:2019/config/")
         .await
         .context("failed requesting caddy config")?
         .error_for_status()
@@ -52,42 +767,243 @@ async fn detect_caddy_ports() -> Result<BTreeSet<u16>> {
         .context("invalid caddy config json")?;
 
     let mut ports = BTreeSet::new();
-    collect_ports_from_json(&value, &mut ports);
+    collect_ports_from_json(&value, &mut ports, scan);
     Ok(ports)
 }
 
-fn collect_ports_from_json(value: &Value, out: &mut BTreeSet<u16>) {
-    match value {
-        Value::Object(map) => {
-            for (k, v) in map {
-                if k.eq_ignore_ascii_case("port") || k.eq_ignore_ascii_case("listen_port") {
-                    if let Some(p) = v.as_u64().and_then(to_valid_port) {
-                        out.insert(p);
-                    }
-                }
+/// A JSON array of ports like `[3000, 8080]` served by another machine.
+/// Entries that aren't a valid port are skipped; anything but an array is an error.
+async fn detect_remote_ports(url: &str, timeout: Duration) -> Result<BTreeSet<u16>> {
+    let client = reqwest::Client::builder()
+        .timeout(timeout)
+        .build()
+        .context("failed to build reqwest client")?;
 
-                if k.eq_ignore_ascii_case("listen") || k.eq_ignore_ascii_case("address") {
-                    if let Some(s) = v.as_str() {
-                        for p in extract_ports_from_string(s) {
-                            out.insert(p);
-                        }
-                    }
-                }
+    let value: Value = process::http_get(&client, url)
+        .await
+        .context("failed requesting remote ports")?
+        .error_for_status()
+        .context("remote ports returned error status")?
+        .json()
+        .await
+        .context("invalid remote ports json")?;
+    let Value::Array(entries) = value else {
+        anyhow::bail!("remote ports response is not a JSON array");
+    };
+
+    let mut ports = BTreeSet::new();
+    for entry in &entries {
+        match entry.as_u64().and_then(to_valid_port) {
+            Some(port) => {
+                ports.insert(port);
+            }
+            None => tracing::debug!(%entry, "skipping invalid remote port"),
+        }
+    }
+    Ok(ports)
+}
+
+/// Ports from the `address` of each entrypoint in Traefik's API, e.g. `:80`.
+async fn detect_traefik_ports(
+    base: &str,
+    timeout: Duration,
+    scan: JsonScan,
+) -> Result<BTreeSet<u16>> {
+    let client = reqwest::Client::builder()
+        .timeout(timeout)
+        .build()
+        .context("failed to build reqwest client")?;
+
+    let url = format!("{}/api/entrypoints", base.trim_end_matches('/'));
+    let value: Value = process::http_get(&client, &url)
+        .await
+        .with_context(|| format!("failed requesting {url}"))?
+        .error_for_status()
+        .context("traefik entrypoints returned error status")?
+        .json()
+        .await
+        .context("invalid traefik entrypoints json")?;
+
+    let mut ports = BTreeSet::new();
+    let addresses = value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|entrypoint| entrypoint.get("address")?.as_str());
+    for address in addresses {
+        if !scan.keep_loopback && is_loopback_bind(address) {
+            tracing::debug!(
+                address,
+                "skipping traefik entrypoint bound only to loopback"
+            );
+            continue;
+        }
+        ports.extend(extract_ports_from_string(address));
+    }
+    Ok(ports)
+}
+
+/// Reads ports from a Caddyfile while Caddy itself is down, preferring
+/// `caddy adapt` and falling back to scanning site addresses by hand.
+async fn detect_caddyfile_ports(
+    path: &Path,
+    timeout: Duration,
+    scan: JsonScan,
+) -> Result<BTreeSet<u16>> {
+    let mut command = Command::new("caddy");
+    command
+        .arg("adapt")
+        .arg("--config")
+        .arg(path)
+        .arg("--adapter")
+        .arg("caddyfile");
+
+    match process::output_with_timeout(&mut command, timeout).await {
+        Ok(output) if output.status.success() => {
+            let value: Value =
+                serde_json::from_slice(&output.stdout).context("invalid caddy adapt json")?;
+            let mut ports = BTreeSet::new();
+            collect_ports_from_json(&value, &mut ports, scan);
+            Ok(ports)
+        }
+        Ok(output) => anyhow::bail!(
+            "caddy adapt failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(err) => {
+            tracing::debug!(error = %err, "caddy adapt unavailable, parsing caddyfile directly");
+            let raw = fs::read_to_string(path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            Ok(parse_caddyfile_site_ports(&raw))
+        }
+    }
+}
+
+/// Explicit ports from top-level site addresses such as `localhost:8080, :9000 {`.
+fn parse_caddyfile_site_ports(raw: &str) -> BTreeSet<u16> {
+    let mut ports = BTreeSet::new();
+    let mut depth = 0usize;
 
-                collect_ports_from_json(v, out);
+    for line in raw.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if depth == 0 && !line.starts_with('(') {
+            let addresses = line.trim_end_matches('{');
+            for address in addresses.split([',', ' ', '\t']).filter(|a| !a.is_empty()) {
+                ports.extend(extract_ports_from_string(address));
             }
         }
-        Value::Array(items) => {
-            for item in items {
-                collect_ports_from_json(item, out);
+        for ch in line.chars() {
+            match ch {
+                '{' => depth += 1,
+                '}' => depth = depth.saturating_sub(1),
+                _ => {}
             }
         }
-        Value::String(s) => {
-            for p in extract_ports_from_string(s) {
-                out.insert(p);
+    }
+
+    ports
+}
+
+/// Caps how many JSON nodes one detector run will visit, whatever the depth limit.
+const MAX_JSON_NODES: usize = 100_000;
+
+#[derive(Debug, Clone, Copy)]
+struct JsonScan {
+    max_depth: usize,
+    /// Keep ports whose every mention binds to loopback (`forward_localhost_only`).
+    keep_loopback: bool,
+}
+
+/// Walks external JSON for ports without unbounded recursion: nodes deeper than
+/// `max_depth`, or past `MAX_JSON_NODES` in total, are skipped and only the
+/// ports found so far are returned. Ports only ever seen behind a loopback bind
+/// (`127.0.0.1:8080`, `localhost:8080`) are dropped unless `keep_loopback` is set.
+fn collect_ports_from_json(value: &Value, out: &mut BTreeSet<u16>, scan: JsonScan) {
+    let mut walk = JsonWalk {
+        max_depth: scan.max_depth,
+        nodes: 0,
+        truncated: false,
+        ports: BTreeSet::new(),
+        public: BTreeSet::new(),
+    };
+    walk.visit(value, 0);
+    if walk.truncated {
+        tracing::debug!(
+            max_depth = scan.max_depth,
+            max_nodes = MAX_JSON_NODES,
+            "json too deep or large; returning partial port list"
+        );
+    }
+
+    if scan.keep_loopback {
+        out.extend(walk.ports);
+        return;
+    }
+    let loopback_only: Vec<u16> = walk.ports.difference(&walk.public).copied().collect();
+    if !loopback_only.is_empty() {
+        tracing::debug!(ports = ?loopback_only, "skipping ports bound only to loopback");
+    }
+    out.extend(walk.public);
+}
+
+struct JsonWalk {
+    max_depth: usize,
+    nodes: usize,
+    truncated: bool,
+    ports: BTreeSet<u16>,
+    /// Ports seen at least once without a loopback bind address.
+    public: BTreeSet<u16>,
+}
+
+impl JsonWalk {
+    fn hit(&mut self, port: u16, loopback: bool) {
+        self.ports.insert(port);
+        if !loopback {
+            self.public.insert(port);
+        }
+    }
+
+    fn hit_string(&mut self, s: &str) {
+        let loopback = is_loopback_bind(s);
+        for p in extract_ports_from_string(s) {
+            self.hit(p, loopback);
+        }
+    }
+
+    fn visit(&mut self, value: &Value, depth: usize) {
+        if depth > self.max_depth || self.nodes >= MAX_JSON_NODES {
+            self.truncated = true;
+            return;
+        }
+        self.nodes += 1;
+
+        match value {
+            Value::Object(map) => {
+                for (k, v) in map {
+                    if k.eq_ignore_ascii_case("port") || k.eq_ignore_ascii_case("listen_port") {
+                        if let Some(p) = v.as_u64().and_then(to_valid_port) {
+                            self.hit(p, false);
+                        }
+                    }
+
+                    if k.eq_ignore_ascii_case("listen") || k.eq_ignore_ascii_case("address") {
+                        if let Some(s) = v.as_str() {
+                            self.hit_string(s);
+                        }
+                    }
+
+                    self.visit(v, depth + 1);
+                }
+            }
+            Value::Array(items) => {
+                for item in items {
+                    self.visit(item, depth + 1);
+                }
             }
+            Value::String(s) => self.hit_string(s),
+            _ => {}
         }
-        _ => {}
     }
 }
 
@@ -99,9 +1015,44 @@ fn to_valid_port(v: u64) -> Option<u16> {
     Some(p)
 }
 
+/// Whether an address string like `127.0.0.1:8080`, `localhost:80`, or
+/// `tcp/[::1]:9000` names a loopback host.
+fn is_loopback_bind(input: &str) -> bool {
+    let rest = input.split_once("://").map_or(input, |(_, rest)| rest);
+    let rest = rest.split_once('/').map_or(rest, |(network, addr)| {
+        if network.starts_with("tcp") || network.starts_with("udp") {
+            addr
+        } else {
+            network
+        }
+    });
+    let Some((host, _)) = rest.rsplit_once(':') else {
+        return false;
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    host.eq_ignore_ascii_case("localhost")
+        || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
 fn extract_ports_from_string(input: &str) -> Vec<u16> {
     let mut out = Vec::new();
 
+    // `[::1]:8080`, `[2001:db8::1]:80/path`: the address's own colons aren't port
+    // separators, so only what follows `]:` can be the port.
+    if let Some(bracket) = input
+        .find('[')
+        .and_then(|open| Some(open + input[open..].find(']')?))
+    {
+        let digits: String = input[bracket + 1..]
+            .strip_prefix(':')
+            .unwrap_or_default()
+            .chars()
+            .take_while(char::is_ascii_digit)
+            .collect();
+        out.extend(digits.parse::<u64>().ok().and_then(to_valid_port));
+        return out;
+    }
+
     if let Some(stripped) = input.strip_prefix(':') {
         if let Ok(p) = stripped.parse::<u16>() {
             if p != 0 {
@@ -123,3 +1074,94 @@ fn extract_ports_from_string(input: &str) -> Vec<u16> {
 
     out
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCAN: JsonScan = JsonScan {
+        max_depth: 64,
+        keep_loopback: false,
+    };
+
+    fn pm2_ports(raw: &str) -> BTreeSet<u16> {
+        let value = parse_noisy_json(raw.as_bytes()).unwrap();
+        let mut ports = BTreeSet::new();
+        collect_pm2_env_ports(&value, &mut ports);
+        collect_ports_from_json(&value, &mut ports, SCAN);
+        ports
+    }
+
+    #[test]
+    fn pm2_jlist_survives_banners_around_the_json() {
+        let raw = "\
+>>>> In-memory PM2 is out-of-date, do:
+>>>> $ pm2 update
+In memory PM2 version: 5.3.0
+Local PM2 version: 5.4.2
+
+[{\"name\":\"api\",\"pm2_env\":{\"env\":{\"PORT\":\"3000\",\"NODE_ENV\":\"dev\"}}},\
+{\"name\":\"web\",\"pm2_env\":{\"env\":{\"VITE_PORT\":5173,\"HOME\":\"/home/me\"}}}]
+[PM2][WARN] Current process list is not synchronized with saved list.
+";
+        assert_eq!(pm2_ports(raw), BTreeSet::from([3000, 5173]));
+    }
+
+    /// `{"listen": ":<shallow>", "next": {"next": ... {"port": <deep>}}}`
+    fn nested(depth: usize, shallow: u16, deep: u16) -> Value {
+        let mut value = serde_json::json!({ "port": deep });
+        for _ in 0..depth {
+            value = serde_json::json!({ "next": value });
+        }
+        serde_json::json!({ "listen": format!(":{shallow}"), "next": value })
+    }
+
+    #[test]
+    fn deeply_nested_json_returns_the_ports_above_the_depth_limit() {
+        let mut ports = BTreeSet::new();
+        collect_ports_from_json(&nested(1_000, 2019, 8443), &mut ports, SCAN);
+        assert_eq!(ports, BTreeSet::from([2019]));
+
+        let mut ports = BTreeSet::new();
+        collect_ports_from_json(&nested(10, 2019, 8443), &mut ports, SCAN);
+        assert_eq!(ports, BTreeSet::from([2019, 8443]));
+    }
+
+    #[test]
+    fn json_scan_stops_after_max_nodes() {
+        let wide: Vec<Value> = (0..MAX_JSON_NODES as u64 + 10)
+            .map(|i| serde_json::json!({ "port": 1 + i % 60_000 }))
+            .collect();
+        let mut ports = BTreeSet::new();
+        collect_ports_from_json(&Value::Array(wide), &mut ports, SCAN);
+        // Each entry costs two nodes (the object and its number), so only
+        // about half are reached before the cap, short of all 60,000 ports.
+        assert!(!ports.is_empty() && ports.len() < 60_000);
+    }
+
+    #[test]
+    fn bracketed_ipv6_listens_take_the_port_after_the_bracket() {
+        assert_eq!(extract_ports_from_string("[::1]:8080"), [8080]);
+        assert_eq!(extract_ports_from_string("[::]:443"), [443]);
+        assert_eq!(extract_ports_from_string("[2001:db8::1]:80"), [80]);
+        assert_eq!(extract_ports_from_string("tcp/[::]:9000/"), [9000]);
+        assert!(extract_ports_from_string("[2001:db8::1]").is_empty());
+        assert!(extract_ports_from_string("[::1]:0").is_empty());
+        // Plain forms still work.
+        assert_eq!(extract_ports_from_string(":2015"), [2015]);
+        assert_eq!(extract_ports_from_string("0.0.0.0:3000"), [3000]);
+    }
+
+    #[test]
+    fn bracketed_loopback_is_recognized() {
+        assert!(is_loopback_bind("[::1]:8080"));
+        assert!(is_loopback_bind("tcp/[::1]:8080"));
+        assert!(!is_loopback_bind("[::]:443"));
+        assert!(!is_loopback_bind("[2001:db8::1]:80"));
+    }
+
+    #[test]
+    fn pm2_jlist_without_json_is_an_error() {
+        assert!(parse_noisy_json(b"[PM2] Spawning PM2 daemon\n").is_err());
+    }
+}
@@ -0,0 +1,280 @@
+use crate::plan::Forward;
+use crate::windows::PortproxyRule;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fmt;
+
+#[derive(Debug, Default)]
+pub struct RuleDiff {
+    pub missing: Vec<Forward>,
+    pub stale: Vec<PortproxyRule>,
+    pub unmanaged: Vec<PortproxyRule>,
+}
+
+impl RuleDiff {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.stale.is_empty() && self.unmanaged.is_empty()
+    }
+}
+
+/// Compares the desired forwards against the live netsh rules.
+pub fn compute(desired: &[Forward], actual: &[PortproxyRule]) -> RuleDiff {
+    let mut diff = RuleDiff::default();
+
+    for forward in desired {
+        let existing: Vec<&PortproxyRule> = actual
+            .iter()
+            .filter(|rule| listens_like(rule, forward))
+            .collect();
+
+        if existing.is_empty() {
+            diff.missing.push(forward.clone());
+            continue;
+        }
+
+        for rule in existing {
+            if !matches(rule, forward) {
+                diff.stale.push(rule.clone());
+                diff.missing.push(forward.clone());
+            }
+        }
+    }
+
+    for rule in actual {
+        let managed = desired.iter().any(|forward| listens_like(rule, forward));
+        if !managed {
+            diff.unmanaged.push(rule.clone());
+        }
+    }
+
+    diff
+}
+
+/// Listen ports a sync would touch: desired forwards without their exact rule,
+/// and `idle` forwards whose rule is still there. Each port counts once, however
+/// many of its rules are stale.
+pub fn drifted_ports(
+    desired: &[Forward],
+    idle: &[Forward],
+    actual: &[PortproxyRule],
+) -> BTreeSet<u16> {
+    let mut ports: BTreeSet<u16> = compute(desired, actual)
+        .missing
+        .iter()
+        .map(|forward| forward.listen_port)
+        .collect();
+    ports.extend(
+        managed_rules(idle, actual)
+            .iter()
+            .map(|rule| rule.listen_port),
+    );
+    ports
+}
+
+/// How one desired forward looks end to end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum PortHealth {
+    /// The exact rule is in netsh (and answered, if probed).
+    Ok,
+    /// No rule listens on this address and port.
+    Missing,
+    /// A rule listens here but connects somewhere else.
+    Stale,
+    /// The rule is right, but nothing answered through it.
+    Dead,
+}
+
+impl fmt::Display for PortHealth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            PortHealth::Ok => "OK",
+            PortHealth::Missing => "MISSING",
+            PortHealth::Stale => "STALE",
+            PortHealth::Dead => "DEAD",
+        })
+    }
+}
+
+/// Whether `actual` holds the rule for `forward`; never `Dead`, which needs a probe.
+pub fn rule_health(forward: &Forward, actual: &[PortproxyRule]) -> PortHealth {
+    let mut existing = actual
+        .iter()
+        .filter(|rule| listens_like(rule, forward))
+        .peekable();
+    if existing.peek().is_none() {
+        return PortHealth::Missing;
+    }
+    if existing.all(|rule| matches(rule, forward)) {
+        PortHealth::Ok
+    } else {
+        PortHealth::Stale
+    }
+}
+
+fn listens_like(rule: &PortproxyRule, forward: &Forward) -> bool {
+    rule.listen_port == forward.listen_port
+        && rule.listen_address == forward.listen_address.to_string()
+}
+
+/// Live rules on an address and port that `desired` forwards, right or not.
+pub fn managed_rules<'a>(
+    desired: &[Forward],
+    actual: &'a [PortproxyRule],
+) -> Vec<&'a PortproxyRule> {
+    actual
+        .iter()
+        .filter(|rule| desired.iter().any(|forward| listens_like(rule, forward)))
+        .collect()
+}
+
+/// The desired forward that this live rule exactly implements, if any.
+pub fn managing_forward<'a>(rule: &PortproxyRule, desired: &'a [Forward]) -> Option<&'a Forward> {
+    desired
+        .iter()
+        .find(|forward| listens_like(rule, forward) && matches(rule, forward))
+}
+
+/// Whose a live rule is, as `status` labels it.
+#[derive(Debug, Clone, Copy)]
+pub enum Ownership<'a> {
+    /// Exactly one of the desired forwards.
+    Managed(&'a Forward),
+    /// On a listen address and port wsl-port forwards, but connecting elsewhere;
+    /// the next sync rewrites it.
+    Stale,
+    /// Nothing wsl-port forwards listens here: another tool's, or a leftover.
+    Unmanaged,
+}
+
+pub fn ownership<'a>(rule: &PortproxyRule, desired: &'a [Forward]) -> Ownership<'a> {
+    if let Some(forward) = managing_forward(rule, desired) {
+        return Ownership::Managed(forward);
+    }
+    if desired.iter().any(|forward| listens_like(rule, forward)) {
+        Ownership::Stale
+    } else {
+        Ownership::Unmanaged
+    }
+}
+
+fn matches(rule: &PortproxyRule, forward: &Forward) -> bool {
+    rule.connect_address == forward.connect_address.to_string()
+        && rule.connect_port == forward.connect_port
+}
+
+pub fn render(diff: &RuleDiff) -> String {
+    let mut lines = Vec::new();
+
+    for rule in &diff.stale {
+        lines.push(format!("- {}  (stale)", describe(rule)));
+    }
+
+    for forward in &diff.missing {
+        lines.push(format!(
+            "+ {} -> {}  [{}]",
+            forward.listen(),
+            forward.connect(),
+            forward.describe_sources()
+        ));
+    }
+
+    for rule in &diff.unmanaged {
+        lines.push(format!("? {}  (unmanaged)", describe(rule)));
+    }
+
+    lines.join("\n")
+}
+
+pub fn describe(rule: &PortproxyRule) -> String {
+    format!("{} -> {}", rule.listen(), rule.connect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ports::{PortSource, RuleFamily};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    const WSL: Ipv4Addr = Ipv4Addr::new(172, 20, 0, 2);
+
+    fn forward(port: u16) -> Forward {
+        Forward {
+            listen_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            listen_port: port,
+            connect_address: IpAddr::V4(WSL),
+            connect_port: port,
+            sources: BTreeSet::from([PortSource::Manual]),
+        }
+    }
+
+    fn rule(family: RuleFamily, port: u16, connect: &str) -> PortproxyRule {
+        PortproxyRule {
+            family,
+            listen_address: "0.0.0.0".to_string(),
+            listen_port: port,
+            connect_address: connect.to_string(),
+            connect_port: port,
+        }
+    }
+
+    #[test]
+    fn compute_sorts_rules_into_missing_stale_and_unmanaged() {
+        let desired = [forward(3000), forward(8080), forward(9000)];
+        let actual = [
+            rule(RuleFamily::V4ToV4, 3000, "172.20.0.2"),
+            rule(RuleFamily::V4ToV4, 8080, "172.20.0.9"),
+            rule(RuleFamily::V4ToV4, 5432, "172.20.0.2"),
+        ];
+        let diff = compute(&desired, &actual);
+        let missing: Vec<u16> = diff.missing.iter().map(|f| f.listen_port).collect();
+        assert_eq!(missing, [8080, 9000]);
+        assert_eq!(diff.stale, [actual[1].clone()]);
+        assert_eq!(diff.unmanaged, [actual[2].clone()]);
+        assert_eq!(rule_health(&desired[0], &actual), PortHealth::Ok);
+        assert_eq!(rule_health(&desired[1], &actual), PortHealth::Stale);
+        assert_eq!(rule_health(&desired[2], &actual), PortHealth::Missing);
+    }
+
+    #[test]
+    fn drifted_ports_counts_each_port_once() {
+        // Two stale rules on one listen slot (one per netsh family table).
+        let desired = [forward(8080)];
+        let actual = [
+            rule(RuleFamily::V4ToV4, 8080, "172.20.0.9"),
+            rule(RuleFamily::V4ToV6, 8080, "fd00::9"),
+        ];
+        assert_eq!(compute(&desired, &actual).missing.len(), 2);
+        assert_eq!(
+            drifted_ports(&desired, &[], &actual),
+            BTreeSet::from([8080])
+        );
+    }
+
+    #[test]
+    fn drifted_ports_counts_idle_rules_like_sync_check() {
+        let desired = [forward(3000)];
+        let idle = [forward(5173), forward(6006)];
+        let actual = [
+            rule(RuleFamily::V4ToV4, 3000, "172.20.0.2"),
+            rule(RuleFamily::V4ToV4, 5173, "172.20.0.2"),
+        ];
+        // 5173 has no listener but still has a rule; 6006 has neither.
+        assert_eq!(
+            drifted_ports(&desired, &idle, &actual),
+            BTreeSet::from([5173])
+        );
+        assert!(drifted_ports(&desired, &[], &actual[..1]).is_empty());
+    }
+
+    #[test]
+    fn ownership_labels_managed_stale_and_unmanaged() {
+        let desired = [forward(3000), forward(8080)];
+        let ok = rule(RuleFamily::V4ToV4, 3000, "172.20.0.2");
+        let stale = rule(RuleFamily::V4ToV4, 8080, "10.0.0.9");
+        let other = rule(RuleFamily::V4ToV4, 1234, "10.0.0.9");
+        assert!(matches!(ownership(&ok, &desired), Ownership::Managed(f) if f.listen_port == 3000));
+        assert!(matches!(ownership(&stale, &desired), Ownership::Stale));
+        assert!(matches!(ownership(&other, &desired), Ownership::Unmanaged));
+    }
+}
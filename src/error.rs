@@ -0,0 +1,102 @@
+use std::io;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum BridgeError {
+    #[error("powershell.exe could not be launched; is Windows interop enabled?")]
+    PowershellNotFound(#[source] io::Error),
+    #[error("netsh rule for port {port} failed ({status}): {stderr}")]
+    NetshFailed {
+        port: u16,
+        status: ExitStatus,
+        stderr: String,
+    },
+    #[error(
+        "netsh needs an elevated (Run as administrator) shell to change the rule for port {port}"
+    )]
+    NetshElevationRequired { port: u16 },
+    #[error("refusing to pass unsafe value `{0}` to netsh")]
+    UnsafeNetshArgument(String),
+    #[error("Windows interop is disabled by --offline")]
+    Offline,
+    /// `drift` or `sync --check` found ports out of sync; `main` turns it into
+    /// exit status 1 without a message.
+    #[error("{0} port(s) out of sync")]
+    Drift(usize),
+    /// `verify` found rules that aren't OK; handled like `Drift`.
+    #[error("{0} port(s) failed verification")]
+    Unverified(usize),
+    #[error("powershell command failed ({status}): {stderr}")]
+    PowershellFailed { status: ExitStatus, stderr: String },
+    #[error("failed to execute {program}")]
+    Spawn {
+        program: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("{program} timed out after {timeout:?}")]
+    TimedOut { program: String, timeout: Duration },
+    #[error("sync ran past its {budget:?} budget; ports not applied: {skipped:?}")]
+    SyncBudgetExceeded { budget: Duration, skipped: Vec<u16> },
+    #[error("WSL IP lookup failed: {0}")]
+    IpLookupFailed(String),
+    #[error("could not resolve config directory")]
+    ConfigDirUnavailable,
+    #[error("failed reading config from {}", path.display())]
+    ConfigRead {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("failed parsing toml from {}", path.display())]
+    ConfigParse {
+        path: PathBuf,
+        #[source]
+        source: toml::de::Error,
+    },
+    #[error("failed parsing json from {}", path.display())]
+    ConfigParseJson {
+        path: PathBuf,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("min_port {min} is above max_port {max} in {}", path.display())]
+    InvalidPortBounds { path: PathBuf, min: u16, max: u16 },
+    #[error("failed serializing config")]
+    ConfigSerialize(#[from] toml::ser::Error),
+    #[error("failed serializing config")]
+    ConfigSerializeJson(#[source] serde_json::Error),
+    #[error("failed writing config {}", path.display())]
+    ConfigWrite {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+}
+
+impl BridgeError {
+    /// Failures whose command already printed its findings, so they only set the exit status.
+    pub fn already_reported(&self) -> bool {
+        matches!(self, BridgeError::Drift(_) | BridgeError::Unverified(_))
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        matches!(
+            self,
+            BridgeError::TimedOut { .. } | BridgeError::SyncBudgetExceeded { .. }
+        )
+    }
+
+    /// Errors the daemon can ride out by retrying on a later tick.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            BridgeError::TimedOut { .. }
+                | BridgeError::SyncBudgetExceeded { .. }
+                | BridgeError::IpLookupFailed(_)
+        )
+    }
+}
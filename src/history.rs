@@ -0,0 +1,108 @@
+use crate::config::{self, unix_now};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+
+/// Entries kept after trimming; the file is rewritten once it grows past
+/// `MAX_ENTRIES + TRIM_SLACK` so most syncs are a plain append.
+const MAX_ENTRIES: usize = 500;
+const TRIM_SLACK: usize = 100;
+
+/// What prompted a sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Trigger {
+    IpChange,
+    ConfigChange,
+    Manual,
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Trigger::IpChange => "ip-change",
+            Trigger::ConfigChange => "config-change",
+            Trigger::Manual => "manual",
+        })
+    }
+}
+
+/// One successful sync. `added`/`removed` are relative to the previous entry.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub at: u64,
+    pub trigger: Trigger,
+    pub ip: Ipv4Addr,
+    pub ports: BTreeSet<u16>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub added: Vec<u16>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<u16>,
+}
+
+pub fn path(config_dir: &Path) -> PathBuf {
+    config_dir.join("history.jsonl")
+}
+
+pub fn record(path: &Path, trigger: Trigger, ip: Ipv4Addr, ports: BTreeSet<u16>) -> io::Result<()> {
+    let mut entries = read(path)?;
+    let previous = entries.last().map(|entry| &entry.ports);
+    let added = match previous {
+        Some(previous) => ports.difference(previous).copied().collect(),
+        None => ports.iter().copied().collect(),
+    };
+    let removed = previous
+        .map(|previous| previous.difference(&ports).copied().collect())
+        .unwrap_or_default();
+    let entry = Entry {
+        at: unix_now(),
+        trigger,
+        ip,
+        ports,
+        added,
+        removed,
+    };
+
+    if let Some(parent) = path.parent() {
+        config::create_private_dir(parent)?;
+    }
+    if entries.len() < MAX_ENTRIES + TRIM_SLACK {
+        let mut file = config::private_open_options()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        return writeln!(file, "{}", to_line(&entry)?);
+    }
+
+    entries.push(entry);
+    let keep = &entries[entries.len() - MAX_ENTRIES..];
+    let mut contents = String::new();
+    for entry in keep {
+        contents.push_str(&to_line(entry)?);
+        contents.push('\n');
+    }
+    let tmp = path.with_extension("jsonl.tmp");
+    config::create_private_file(&tmp)?.write_all(contents.as_bytes())?;
+    fs::rename(&tmp, path)
+}
+
+/// All readable entries, oldest first; unparsable lines are skipped.
+pub fn read(path: &Path) -> io::Result<Vec<Entry>> {
+    let raw = match fs::read_to_string(path) {
+        Ok(raw) => raw,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    Ok(raw
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+fn to_line(entry: &Entry) -> io::Result<String> {
+    serde_json::to_string(entry).map_err(io::Error::other)
+}
@@ -1,196 +1,3847 @@
 mod config;
 mod detector;
+mod diff;
+mod error;
+mod history;
+mod logging;
+mod metrics;
+mod network;
+mod pacing;
+mod plan;
+mod ports;
+mod process;
+mod relay;
+mod seen;
+mod snapshot;
+mod throttle;
+mod udp;
+mod update;
 mod windows;
 
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
-use std::collections::BTreeSet;
-use std::net::Ipv4Addr;
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use error::BridgeError;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::time::sleep;
+use tokio::time::{sleep_until, Instant};
 
 #[derive(Parser, Debug)]
 #[command(name = "wsl-port")]
 #[command(about = "WSL to Windows portproxy auto-forwarder", version)]
 struct Cli {
+    #[command(flatten)]
+    global: GlobalOpts,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+#[derive(Args, Debug, Clone)]
+struct GlobalOpts {
+    /// Increase log verbosity (-v info, -vv debug, -vvv trace); overrides RUST_LOG
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Log line format: compact (default) or json, one object per line for log aggregators
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Compact)]
+    log_format: LogFormat,
+
+    /// Use this directory for ports.toml instead of ~/.config/wsl-port
+    #[arg(long, global = true, env = "WSL_PORT_CONFIG_DIR", value_name = "DIR")]
+    config_dir: Option<PathBuf>,
+
+    /// Config file syntax, toml or json (YAML is not supported); picks ports.<ext>. Default: whichever exists, else toml
+    #[arg(long, global = true, value_name = "FORMAT")]
+    config_format: Option<config::ConfigFormat>,
+
+    /// Skip pm2/caddy detection and forward manual ports only; stored detected ports are kept as-is
+    #[arg(long, global = true)]
+    no_detect: bool,
+
+    /// Take the WSL IP from this interface (e.g. eth0) instead of `hostname -I`
+    #[arg(long, global = true, value_name = "NAME")]
+    interface: Option<String>,
+
+    /// Never take the WSL IP from this interface (e.g. docker0); repeatable. --interface wins
+    #[arg(long = "exclude-interface", global = true, value_name = "NAME")]
+    exclude_interfaces: Vec<String>,
+
+    /// Roll netsh back to its previous rules if a sync fails partway
+    #[arg(long, global = true)]
+    transactional: bool,
+
+    /// Write the command's report (status, ip, diff, export, ...) to this file instead of stdout
+    #[arg(long, global = true, value_name = "PATH")]
+    output: Option<PathBuf>,
+
+    /// Timeout in milliseconds for the caddy admin API (overrides caddy_timeout_ms)
+    #[arg(long, global = true, value_name = "MS")]
+    connect_timeout: Option<u64>,
+
+    /// Cap on the total time one sync may spend applying rules (e.g. 30s, 2m)
+    #[arg(long, global = true, value_name = "DURATION", value_parser = parse_ttl)]
+    sync_timeout: Option<Duration>,
+
+    /// Abort the whole command after this long, in seconds or with an s/m/h suffix; `daemon` ignores it
+    #[arg(long, global = true, value_name = "DURATION", value_parser = parse_ttl)]
+    timeout: Option<Duration>,
+
+    /// Run this shell command before each sync; non-zero exit skips it (overrides pre_sync_hook)
+    #[arg(long, global = true, value_name = "CMD")]
+    pre_sync: Option<String>,
+
+    /// Run this shell command after each successful sync, with WSL_IP and WSL_PORTS set (overrides post_sync_hook)
+    #[arg(long, global = true, value_name = "CMD")]
+    post_sync: Option<String>,
+
+    /// Apply up to N netsh rules at once; netsh may serialize internally, so higher isn't always faster
+    #[arg(long, global = true, value_name = "N", default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+    max_concurrency: u16,
+
+    /// deletes-first removes every affected rule before adding any; per-port deletes and re-adds one at a time
+    #[arg(long, global = true, value_enum, default_value_t = windows::ApplyOrder::DeletesFirst)]
+    apply_order: windows::ApplyOrder,
+
+    /// Delete and re-add every rule on sync, even ones that are already correct
+    #[arg(long, global = true)]
+    force: bool,
+
+    /// Forward ports below 1024 and `reserved_ports` instead of holding them back
+    #[arg(long, global = true)]
+    allow_privileged: bool,
+
+    /// Forward over IPv4 only, even when WSL also has an IPv6 address
+    #[arg(long, global = true, conflicts_with = "ipv6_only")]
+    ipv4_only: bool,
+
+    /// Forward over IPv6 only; fails when WSL has no global IPv6 address
+    #[arg(long, global = true)]
+    ipv6_only: bool,
+
+    /// Never call PowerShell: status shows config and detection only, and sync/add/remove/daemon refuse
+    #[arg(long, global = true)]
+    offline: bool,
+
+    /// Log every external command (pm2, hostname, PowerShell/netsh, ...) and HTTP GET at info, with its exit status
+    #[arg(long, global = true)]
+    show_commands: bool,
+
+    /// Fail the command when a detector for a forwarded source errors, instead of forwarding without its ports
+    #[arg(long, global = true)]
+    strict: bool,
+}
+
+impl GlobalOpts {
+    /// These options as command-line arguments, so a re-invoked `wsl-port`
+    /// resolves the same config and target. `--output` is left out: a later
+    /// run must not overwrite the caller's report file.
+    fn to_args(&self) -> Vec<std::ffi::OsString> {
+        let GlobalOpts {
+            verbose,
+            log_format,
+            config_dir,
+            config_format,
+            no_detect,
+            interface,
+            exclude_interfaces,
+            transactional,
+            output: _,
+            connect_timeout,
+            sync_timeout,
+            timeout,
+            pre_sync,
+            post_sync,
+            max_concurrency,
+            apply_order,
+            force,
+            allow_privileged,
+            ipv4_only,
+            ipv6_only,
+            offline,
+            show_commands,
+            strict,
+        } = self;
+        let mut args: Vec<std::ffi::OsString> = Vec::new();
+        let mut push = |flag: &str, value: Option<std::ffi::OsString>| {
+            args.push(flag.into());
+            args.extend(value);
+        };
+
+        for _ in 0..*verbose {
+            push("--verbose", None);
+        }
+        if let Some(format) = clap::ValueEnum::to_possible_value(log_format) {
+            push("--log-format", Some(format.get_name().into()));
+        }
+        if let Some(dir) = config_dir {
+            push("--config-dir", Some(dir.into()));
+        }
+        if let Some(format) = config_format {
+            push("--config-format", Some(format.extension().into()));
+        }
+        if let Some(interface) = interface {
+            push("--interface", Some(interface.into()));
+        }
+        for interface in exclude_interfaces {
+            push("--exclude-interface", Some(interface.into()));
+        }
+        if let Some(ms) = connect_timeout {
+            push("--connect-timeout", Some(ms.to_string().into()));
+        }
+        if let Some(limit) = sync_timeout {
+            push("--sync-timeout", Some(limit.as_secs().to_string().into()));
+        }
+        if let Some(limit) = timeout {
+            push("--timeout", Some(limit.as_secs().to_string().into()));
+        }
+        if let Some(hook) = pre_sync {
+            push("--pre-sync", Some(hook.into()));
+        }
+        if let Some(hook) = post_sync {
+            push("--post-sync", Some(hook.into()));
+        }
+        push(
+            "--max-concurrency",
+            Some(max_concurrency.to_string().into()),
+        );
+        if let Some(order) = clap::ValueEnum::to_possible_value(apply_order) {
+            push("--apply-order", Some(order.get_name().into()));
+        }
+        let switches = [
+            (*no_detect, "--no-detect"),
+            (*transactional, "--transactional"),
+            (*force, "--force"),
+            (*allow_privileged, "--allow-privileged"),
+            (*ipv4_only, "--ipv4-only"),
+            (*ipv6_only, "--ipv6-only"),
+            (*offline, "--offline"),
+            (*show_commands, "--show-commands"),
+            (*strict, "--strict"),
+        ];
+        for (on, flag) in switches {
+            if on {
+                push(flag, None);
+            }
+        }
+        args
+    }
+}
+
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Show current IP, configured ports, and netsh mappings
-    Status,
-    /// Add a port to the manual config and sync immediately
-    Add { port: u16 },
-    /// Remove a port from the manual config and sync immediately
-    Remove { port: u16 },
+    Status {
+        /// List every port instead of collapsing runs like 8000-8020
+        #[arg(long)]
+        expand: bool,
+        /// `shell` prints eval-able WSL_IP=... / WSL_PORTS=... assignments, `json` one object
+        #[arg(long, value_enum, default_value_t = StatusFormat::Text)]
+        format: StatusFormat,
+        /// Don't write freshly detected ports back to the config file
+        #[arg(long)]
+        no_save: bool,
+        /// Also list netsh rules wsl-port doesn't manage, labeled unmanaged/external
+        #[arg(long)]
+        include_unmanaged: bool,
+        /// Skip detection and every WSL/Windows probe: show the config as saved, query netsh once, write nothing
+        #[arg(long, conflicts_with_all = ["summary", "format"])]
+        fast: bool,
+        /// Print one line (`WSL <ip> · <n> ports · synced|drift!`) from the saved detected ports, for prompts
+        #[arg(long, conflicts_with_all = ["expand", "format"])]
+        summary: bool,
+    },
+    /// Add a port, a `listen:connect` remap like 8080:3000, or a range like 8000-8050, and sync immediately
+    Add {
+        port: ports::PortSpec,
+        /// Also mark the listen port sticky so `prune` leaves its rule alone
+        #[arg(long)]
+        sticky: bool,
+        /// Open http://localhost:<port> in the Windows browser whenever a sync creates its rule
+        #[arg(long)]
+        open: bool,
+    },
+    /// Forward a port for a limited time, e.g. `temp 8080 10m`
+    Temp {
+        port: u16,
+        /// How long to keep it: seconds, or with an s/m/h/d suffix
+        #[arg(value_parser = parse_ttl)]
+        duration: Duration,
+    },
+    /// Remove a port (a remap's listen port) from the manual config and sync immediately
+    Remove {
+        /// A port, or a range like 8000-8050 to drop all of its manual ports at once
+        port: ports::PortRange,
+        /// Treat PORT as the WSL-side connect port and remove the remaps pointing at it
+        #[arg(long)]
+        by_connect: bool,
+    },
     /// Force immediate re-sync of netsh rules
-    Sync,
+    Sync {
+        /// Only re-apply these managed ports, e.g. `--only 3000,8080`; other rules stay untouched
+        #[arg(long, value_delimiter = ',')]
+        only: Vec<u16>,
+        /// Apply nothing; print the changes a sync would make and exit 1 if there are any
+        #[arg(long)]
+        check: bool,
+        /// Connect every rule to this IPv4 instead of the detected WSL IP (skips IP lookup and connect_mode)
+        #[arg(long, value_name = "IP")]
+        connect_address: Option<Ipv4Addr>,
+    },
+    /// Delete every managed rule, then re-add them all from current detection and IP
+    Restart,
+    /// Show drift between desired ports and the live netsh rules without changing anything
+    Diff,
+    /// Print the netsh changes a sync would make if the WSL IP were ADDR, without applying anything
+    Simulate {
+        #[arg(long, value_name = "ADDR")]
+        ip: Ipv4Addr,
+    },
+    /// Print the netsh commands a sync would run as a script, without running them
+    Script,
+    /// Time repeated full syncs and report min/avg/max and per-port cost
+    Bench {
+        /// How many syncs to time
+        #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+        runs: u32,
+        /// Resolve the rules once, then time recording commands instead of running PowerShell
+        #[arg(long)]
+        mock: bool,
+    },
+    /// Check every desired port end to end and report OK/MISSING/STALE/DEAD; exits 1 unless all are OK
+    Verify {
+        /// Also connect through each rule from Windows and mark silent ones DEAD
+        #[arg(long)]
+        probe: bool,
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print how many rules a sync would change; exits 1 when there is drift
+    Drift,
+    /// Delete netsh rules that don't match the desired state, confirming each one
+    Prune {
+        /// Delete every non-matching rule without prompting
+        #[arg(long)]
+        all: bool,
+        /// Also offer rules on `sticky_ports`, which are skipped by default
+        #[arg(long)]
+        include_sticky: bool,
+    },
+    /// Open http://localhost:<port> in the Windows default browser
+    Open {
+        #[arg(required_unless_present = "all")]
+        port: Option<u16>,
+        /// Open every forwarded port, skipping known non-HTTP ones (ssh, databases, ...)
+        #[arg(long, conflicts_with = "port")]
+        all: bool,
+    },
+    /// Print the WSL address used for forwarding
+    Ip {
+        /// Emit {"ipv4": ..., "ipv6": ...} instead of plain text
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the Windows host's primary LAN IPv4 (what `auto-lan` resolves to)
+    WinIp,
+    /// Show the most recent syncs: when, why, the IP, and which ports changed
+    History {
+        /// How many entries to show
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+        /// Only entries from this long ago (1h, 30m) or since this UTC time (2024-01-01 12:30)
+        #[arg(long, value_name = "WHEN", value_parser = parse_since)]
+        since: Option<u64>,
+        /// Print the entries as JSON lines
+        #[arg(long)]
+        json: bool,
+    },
+    /// Stop the daemon from syncing until `resume`; existing rules stay in place
+    Pause,
+    /// Let a paused daemon sync again
+    Resume,
+    /// Switch between named sets of manual ports
+    Profile {
+        #[command(subcommand)]
+        action: ProfileCommand,
+    },
+    /// Print the resolved state (ports, sources, IP, rules) as TOML
+    Export {
+        /// Emit JSON instead of TOML
+        #[arg(long)]
+        json: bool,
+    },
+    /// Restore manual ports, remaps, and excludes from an export, then sync
+    Import { file: PathBuf },
+    /// Compare the manual ports, remaps, and excludes of two config files
+    CompareConfig {
+        a: PathBuf,
+        b: PathBuf,
+        /// Print the differences as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Inspect or edit the config file
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommand,
+    },
+    /// Print the installed version; --check also asks update_check_url for the latest (never downloads)
+    Version {
+        #[arg(long)]
+        check: bool,
+    },
+    /// Print a shell completion script to stdout
+    Completions { shell: clap_complete::Shell },
+    /// Print a Windows Task Scheduler task that starts the daemon at logon (nothing is installed)
+    WindowsTask {
+        /// Distro to start; defaults to $WSL_DISTRO_NAME
+        #[arg(long)]
+        distro: Option<String>,
+        /// Print a one-line `schtasks /Create` command instead of task XML
+        #[arg(long)]
+        schtasks: bool,
+    },
     /// Run daemon loop and refresh rules on IP/config changes
-    Daemon,
+    Daemon {
+        /// Serve /healthz and /metrics (Prometheus) on this address, e.g. 127.0.0.1:9477
+        #[arg(long)]
+        metrics_addr: Option<SocketAddr>,
+
+        /// Seconds between WSL IP checks
+        #[arg(long, value_name = "SECS", default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+        ip_interval: u64,
+
+        /// Seconds between pm2/caddy detection runs (also reloads the config)
+        #[arg(long, value_name = "SECS", default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+        detect_interval: u64,
+
+        /// Milliseconds a changed WSL IP must stay stable before re-syncing (0 disables)
+        #[arg(long, value_name = "MS", default_value_t = 2000)]
+        ip_debounce_ms: u64,
+
+        /// Every SECS, re-read netsh and re-add any managed rule that went missing (e.g. after sleep/resume)
+        #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+        reconcile_interval: Option<u64>,
+
+        /// Randomize each poll interval by up to ±PCT percent so many daemons don't poll in lockstep (0 disables)
+        #[arg(long, value_name = "PCT", default_value_t = 10, value_parser = clap::value_parser!(u8).range(0..=50))]
+        jitter_pct: u8,
+
+        /// After a minute with no changes, keep doubling the poll intervals up to SECS; any change resets them
+        #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+        max_poll_interval: Option<u64>,
+
+        /// Print `READY` on stdout once the first sync succeeds, for init scripts to wait on
+        #[arg(long)]
+        wait: bool,
+
+        /// Create this file once the first sync succeeds (removed again on startup)
+        #[arg(long, value_name = "PATH")]
+        ready_file: Option<PathBuf>,
+
+        /// Exit after N consecutive failed cycles (config load, save, sync errors); by default it retries forever
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+        give_up_after: Option<u32>,
+    },
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .with_target(false)
-        .compact()
-        .init();
+#[derive(Subcommand, Debug)]
+enum ProfileCommand {
+    /// List profiles and mark the active one
+    List,
+    /// Activate a profile and sync; omit the name to deactivate
+    Use { name: Option<String> },
+    /// Add a port to a profile, creating the profile if needed
+    AddPort { name: String, port: u16 },
+    /// Remove a port from a profile
+    RemovePort { name: String, port: u16 },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum LogFormat {
+    Compact,
+    Json,
+}
 
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum StatusFormat {
+    Text,
+    Shell,
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Open the config in $EDITOR, then validate it and offer to sync
+    Edit,
+    /// Print the resolved config file path without creating anything
+    Path,
+}
+
+#[tokio::main]
+async fn main() -> Result<std::process::ExitCode> {
     let cli = Cli::parse();
 
-    match cli.command {
-        Commands::Status => cmd_status().await,
-        Commands::Add { port } => cmd_add(port).await,
-        Commands::Remove { port } => cmd_remove(port).await,
-        Commands::Sync => cmd_sync().await,
-        Commands::Daemon => cmd_daemon().await,
+    let logs = tracing_subscriber::fmt()
+        .with_env_filter(env_filter(cli.global.verbose, cli.global.show_commands))
+        .with_target(false);
+    match cli.global.log_format {
+        LogFormat::Compact => logs.compact().init(),
+        LogFormat::Json => logs.event_format(logging::JsonFormat).init(),
+    }
+
+    let opts = cli.global;
+    process::set_show_commands(opts.show_commands);
+    let json = cli.command.json_output();
+    let result = if opts.offline && cli.command.needs_interop() {
+        Err(anyhow::anyhow!(
+            "this command changes netsh rules and needs Windows interop; drop --offline to run it"
+        ))
+    } else {
+        match opts.timeout {
+            Some(limit) if !matches!(cli.command, Commands::Daemon { .. }) => {
+                // Dropping the command on timeout also kills its subprocesses.
+                tokio::time::timeout(limit, run_command(&opts, cli.command))
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(anyhow::anyhow!(
+                            "command timed out after {limit:?} (--timeout); rules applied before then stay"
+                        ))
+                    })
+            }
+            _ => run_command(&opts, cli.command).await,
+        }
+    };
+    if let Err(err) = &result {
+        if err
+            .downcast_ref::<BridgeError>()
+            .is_some_and(BridgeError::already_reported)
+        {
+            return Ok(std::process::ExitCode::FAILURE);
+        }
+        if json {
+            eprintln!("{}", json_error(err));
+            return Ok(std::process::ExitCode::FAILURE);
+        }
+    }
+    result.map(|()| std::process::ExitCode::SUCCESS)
+}
+
+async fn run_command(opts: &GlobalOpts, command: Commands) -> Result<()> {
+    match command {
+        Commands::Status {
+            expand,
+            format,
+            no_save,
+            fast,
+            include_unmanaged,
+            summary,
+        } => {
+            if summary {
+                cmd_status_summary(opts).await
+            } else {
+                let view = StatusView {
+                    expand,
+                    format,
+                    no_save,
+                    fast,
+                    include_unmanaged,
+                };
+                cmd_status(opts, view).await
+            }
+        }
+        Commands::Add { port, sticky, open } => cmd_add(opts, port, sticky, open).await,
+        Commands::Temp { port, duration } => cmd_temp(opts, port, duration).await,
+        Commands::Remove { port, by_connect } => cmd_remove(opts, port, by_connect).await,
+        Commands::Sync {
+            only,
+            check,
+            connect_address,
+        } => cmd_sync(opts, only, check, connect_address).await,
+        Commands::Restart => cmd_restart(opts).await,
+        Commands::Diff => cmd_diff(opts).await,
+        Commands::Simulate { ip } => cmd_simulate(opts, ip).await,
+        Commands::Script => cmd_script(opts).await,
+        Commands::Bench { runs, mock } => cmd_bench(opts, runs, mock).await,
+        Commands::Verify { probe, json } => cmd_verify(opts, probe, json).await,
+        Commands::Drift => cmd_drift(opts).await,
+        Commands::Prune {
+            all,
+            include_sticky,
+        } => cmd_prune(opts, all, include_sticky).await,
+        Commands::Open { port, .. } => cmd_open(opts, port).await,
+        Commands::Ip { json } => cmd_ip(opts, json).await,
+        Commands::WinIp => cmd_win_ip(opts).await,
+        Commands::History { limit, since, json } => cmd_history(opts, limit, since, json),
+        Commands::Pause => cmd_pause(opts),
+        Commands::Resume => cmd_resume(opts),
+        Commands::Profile { action } => cmd_profile(opts, action).await,
+        Commands::Export { json } => cmd_export(opts, json).await,
+        Commands::Import { file } => cmd_import(opts, &file).await,
+        Commands::CompareConfig { a, b, json } => cmd_compare_config(opts, &a, &b, json),
+        Commands::Config {
+            action: ConfigCommand::Edit,
+        } => cmd_config_edit(opts).await,
+        Commands::Config {
+            action: ConfigCommand::Path,
+        } => {
+            let path = config::config_path(opts.config_dir.as_deref(), opts.config_format)?;
+            println!("{}", path.display());
+            Ok(())
+        }
+        Commands::Completions { shell } => {
+            let mut command = Cli::command();
+            let name = command.get_name().to_string();
+            clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+            Ok(())
+        }
+        Commands::Version { check } => cmd_version(opts, check).await,
+        Commands::WindowsTask { distro, schtasks } => cmd_windows_task(opts, distro, schtasks),
+        Commands::Daemon {
+            metrics_addr,
+            ip_interval,
+            detect_interval,
+            ip_debounce_ms,
+            reconcile_interval,
+            jitter_pct,
+            max_poll_interval,
+            wait,
+            ready_file,
+            give_up_after,
+        } => {
+            let intervals = DaemonIntervals {
+                ip: Duration::from_secs(ip_interval),
+                detect: Duration::from_secs(detect_interval),
+                ip_debounce: Duration::from_millis(ip_debounce_ms),
+                reconcile: reconcile_interval.map(Duration::from_secs),
+                jitter_pct,
+                max_poll: max_poll_interval.map(Duration::from_secs),
+            };
+            let readiness = Readiness {
+                print: wait,
+                file: ready_file,
+            };
+            cmd_daemon(opts, metrics_addr, intervals, readiness, give_up_after).await
+        }
+    }
+}
+
+impl Commands {
+    /// Whether the command was asked for `--json` output, so failures should be JSON too.
+    fn json_output(&self) -> bool {
+        match self {
+            Commands::Verify { json, .. }
+            | Commands::Ip { json }
+            | Commands::History { json, .. }
+            | Commands::Export { json }
+            | Commands::CompareConfig { json, .. } => *json,
+            Commands::Status { format, .. } => *format == StatusFormat::Json,
+            _ => false,
+        }
+    }
+
+    /// Whether the command exists to change netsh rules, so `--offline` refuses it outright.
+    fn needs_interop(&self) -> bool {
+        matches!(
+            self,
+            Commands::Add { .. }
+                | Commands::Temp { .. }
+                | Commands::Remove { .. }
+                | Commands::Sync { .. }
+                | Commands::Restart
+                | Commands::Prune { .. }
+                | Commands::Daemon { .. }
+        )
+    }
+}
+
+/// `{"error": "<top message>", "context": ["<cause>", ...]}`, outermost first.
+fn json_error(err: &anyhow::Error) -> serde_json::Value {
+    let context: Vec<String> = err.chain().skip(1).map(|cause| cause.to_string()).collect();
+    serde_json::json!({ "error": err.to_string(), "context": context })
+}
+
+async fn cmd_version(opts: &GlobalOpts, check: bool) -> Result<()> {
+    let mut out = primary_output(opts)?;
+    writeln!(out, "wsl-port {}", update::INSTALLED)?;
+    if !check {
+        out.flush()?;
+        return Ok(());
+    }
+    if opts.offline {
+        writeln!(out, "Update check skipped (--offline).")?;
+        out.flush()?;
+        return Ok(());
+    }
+
+    let (_, cfg) = load_config(opts)?;
+    let latest = update::latest_version(cfg.update_check_url(), cfg.command_timeout()).await?;
+    match update::compare(update::INSTALLED, &latest) {
+        Some(std::cmp::Ordering::Greater) => writeln!(
+            out,
+            "Update available: {latest} (installed {}).",
+            update::INSTALLED
+        )?,
+        Some(_) => writeln!(out, "Up to date (latest is {latest}).")?,
+        None => writeln!(out, "Latest published version: {latest}.")?,
+    }
+    out.flush()?;
+    Ok(())
+}
+
+const WINDOWS_TASK_NAME: &str = "wsl-port daemon";
+
+/// The task runs elevated because netsh portproxy changes need admin rights.
+fn cmd_windows_task(opts: &GlobalOpts, distro: Option<String>, schtasks: bool) -> Result<()> {
+    let distro = match distro.or_else(|| std::env::var("WSL_DISTRO_NAME").ok()) {
+        Some(distro) if !distro.is_empty() => distro,
+        _ => anyhow::bail!("can't tell which distro this is; pass --distro <name>"),
+    };
+    let exe = std::env::current_exe().context("failed locating the wsl-port binary")?;
+    // Windows command-line quoting; only needed for names with spaces.
+    let quote = |value: &str| {
+        if value.contains(char::is_whitespace) {
+            format!("\"{value}\"")
+        } else {
+            value.to_string()
+        }
+    };
+    let arguments = format!(
+        "-d {} -- {} daemon",
+        quote(&distro),
+        quote(&exe.display().to_string())
+    );
+    let mut out = primary_output(opts)?;
+
+    if schtasks {
+        writeln!(
+            out,
+            "schtasks /Create /TN \"{WINDOWS_TASK_NAME}\" /SC ONLOGON /RL HIGHEST /F /TR \"wsl.exe {}\"",
+            arguments.replace('"', "\\\"")
+        )?;
+        out.flush()?;
+        return Ok(());
     }
+
+    let escape = |value: &str| {
+        value
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    };
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(out, "<!--")?;
+    writeln!(
+        out,
+        "  Starts `wsl-port daemon` in {} at logon.",
+        escape(&distro)
+    )?;
+    writeln!(
+        out,
+        "  Save as wsl-port.xml, then from an elevated prompt run:"
+    )?;
+    writeln!(
+        out,
+        "    schtasks /Create /TN \"{WINDOWS_TASK_NAME}\" /XML wsl-port.xml"
+    )?;
+    writeln!(
+        out,
+        "  or import it in Task Scheduler (Action > Import Task)."
+    )?;
+    writeln!(out, "-->")?;
+    writeln!(
+        out,
+        r#"<Task version="1.2" xmlns="http://schemas.microsoft.com/windows/2004/02/mit/task">
+  <RegistrationInfo>
+    <Description>Keeps Windows portproxy rules pointed at WSL ({distro})</Description>
+  </RegistrationInfo>
+  <Triggers>
+    <LogonTrigger>
+      <Enabled>true</Enabled>
+    </LogonTrigger>
+  </Triggers>
+  <Principals>
+    <Principal id="Author">
+      <LogonType>InteractiveToken</LogonType>
+      <RunLevel>HighestAvailable</RunLevel>
+    </Principal>
+  </Principals>
+  <Settings>
+    <MultipleInstancesPolicy>IgnoreNew</MultipleInstancesPolicy>
+    <DisallowStartIfOnBatteries>false</DisallowStartIfOnBatteries>
+    <StopIfGoingOnBatteries>false</StopIfGoingOnBatteries>
+    <ExecutionTimeLimit>PT0S</ExecutionTimeLimit>
+    <Hidden>true</Hidden>
+  </Settings>
+  <Actions Context="Author">
+    <Exec>
+      <Command>wsl.exe</Command>
+      <Arguments>{arguments}</Arguments>
+    </Exec>
+  </Actions>
+</Task>"#,
+        distro = escape(&distro),
+        arguments = escape(&arguments),
+    )?;
+    out.flush()?;
+    Ok(())
 }
 
-async fn cmd_status() -> Result<()> {
-    let path = config::config_path()?;
+/// Loads the config and layers CLI overrides on top without persisting them.
+fn load_config(opts: &GlobalOpts) -> Result<(PathBuf, config::PortsConfig)> {
+    let path = config::config_path(opts.config_dir.as_deref(), opts.config_format)?;
     let mut cfg = config::load_or_default(&path)?;
+    cfg.fragments = config::load_fragments(&path)?;
+    if let Some(include) = &cfg.include_file {
+        cfg.included_ports = config::load_include_file(&path, include)?;
+    }
+    cfg.runtime.caddy_timeout_ms = opts.connect_timeout;
+    cfg.runtime.no_detect = opts.no_detect;
+    cfg.runtime.transactional = opts.transactional;
+    cfg.runtime.interface = opts.interface.clone();
+    cfg.runtime.exclude_interfaces = opts.exclude_interfaces.clone();
+    cfg.runtime.sync_timeout = opts.sync_timeout;
+    cfg.runtime.allow_privileged = opts.allow_privileged;
+    cfg.runtime.force = opts.force;
+    cfg.runtime.max_concurrency = usize::from(opts.max_concurrency);
+    cfg.runtime.apply_order = opts.apply_order;
+    cfg.runtime.pre_sync_hook = opts.pre_sync.clone();
+    cfg.runtime.post_sync_hook = opts.post_sync.clone();
+    cfg.runtime.family = match (opts.ipv4_only, opts.ipv6_only) {
+        (true, _) => config::FamilyPolicy::Ipv4Only,
+        (_, true) => config::FamilyPolicy::Ipv6Only,
+        _ => config::FamilyPolicy::Dual,
+    };
+    cfg.runtime.history_path = path.parent().map(history::path);
+    cfg.runtime.seen_path = path.parent().map(seen::path);
+    cfg.runtime.pause_path = path.parent().map(config::pause_path);
+    cfg.runtime.offline = opts.offline;
+    cfg.runtime.strict = opts.strict;
+    Ok((path, cfg))
+}
 
-    let (pm2_ports, caddy_ports) = detector::detect_ports().await;
-    cfg.set_detected_ports(pm2_ports, caddy_ports);
-    config::save(&path, &cfg)?;
+/// A POSIX single-quoted word; `'` is the only character that needs care.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Where a command's main report goes: `--output <path>` or stdout. Logs and
+/// errors always stay on stderr.
+fn primary_output(opts: &GlobalOpts) -> Result<Box<dyn Write>> {
+    let Some(path) = &opts.output else {
+        return Ok(Box::new(std::io::stdout()));
+    };
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("failed creating {}", parent.display()))?;
+    }
+    let file = std::fs::File::create(path)
+        .with_context(|| format!("failed creating output file {}", path.display()))?;
+    Ok(Box::new(std::io::BufWriter::new(file)))
+}
+
+/// Replaces the stored detected ports with a fresh scan unless `--no-detect` is set.
+async fn refresh_detected_ports(cfg: &mut config::PortsConfig) -> Result<()> {
+    refresh_detected_ports_reported(cfg).await.map(drop)
+}
+
+/// `refresh_detected_ports`, also returning how each detector fared (empty when skipped).
+/// Under `--strict` a failed detector for a forwarded source is an error, and the
+/// stored ports are left alone.
+async fn refresh_detected_ports_reported(
+    cfg: &mut config::PortsConfig,
+) -> Result<detector::DetectionReport> {
+    if cfg.runtime.no_detect {
+        return Ok(detector::DetectionReport::new());
+    }
+    let (detection, report) = detector::detect_ports_reported(cfg).await;
+    if cfg.runtime.strict {
+        let failures: Vec<String> = report
+            .iter()
+            .filter(|&(&source, _)| cfg.forwards_source(source))
+            .filter_map(|(source, status)| match status {
+                detector::DetectorStatus::Failed(err) => Some(format!("{source}: {err}")),
+                _ => None,
+            })
+            .collect();
+        if !failures.is_empty() {
+            anyhow::bail!("detection failed (--strict): {}", failures.join("; "));
+        }
+    }
+    cfg.set_detected_ports(detection);
+    record_last_seen(cfg);
+    Ok(report)
+}
+
+/// Stamps the freshly detected ports in the last-seen file; failures only cost the timestamps.
+fn record_last_seen(cfg: &config::PortsConfig) {
+    let Some(path) = &cfg.runtime.seen_path else {
+        return;
+    };
+    if let Err(err) = seen::record(path, &cfg.detected_ports(), config::unix_now()) {
+        tracing::warn!(error = %err, path = %path.display(), "failed to record last-seen ports");
+    }
+}
+
+/// `--show-commands` logs at info, so without `-v` that one module is raised to info.
+fn env_filter(verbose: u8, show_commands: bool) -> tracing_subscriber::EnvFilter {
+    let level = match verbose {
+        0 => {
+            let filter = tracing_subscriber::EnvFilter::from_default_env();
+            if !show_commands {
+                return filter;
+            }
+            return filter.add_directive(
+                "wsl_port::process=info"
+                    .parse()
+                    .expect("static directive is valid"),
+            );
+        }
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
+    };
+    tracing_subscriber::EnvFilter::new(level)
+}
+
+/// `status` flags besides `--summary`.
+struct StatusView {
+    expand: bool,
+    format: StatusFormat,
+    no_save: bool,
+    fast: bool,
+    include_unmanaged: bool,
+}
+
+async fn cmd_status(opts: &GlobalOpts, view: StatusView) -> Result<()> {
+    let StatusView {
+        expand,
+        format,
+        no_save,
+        fast,
+        include_unmanaged,
+    } = view;
+    let list = |ports: &BTreeSet<u16>| ports::display_set(ports, expand);
+    let mut out = primary_output(opts)?;
+    let (path, mut cfg) = load_config(opts)?;
+
+    if fast {
+        return status_fast(&mut out, &path, &cfg, expand, include_unmanaged).await;
+    }
+    let report = refresh_detected_ports_reported(&mut cfg).await?;
+    if !no_save {
+        config::save(&path, &cfg)?;
+    }
 
-    let current_ip = get_wsl_ip().await?;
     let all_ports = cfg.all_ports();
-    let rules = windows::show_portproxy().await.unwrap_or_else(|err| {
-        format!("Could not fetch netsh mappings: {err}")
-    });
+    let addresses = network::get_wsl_ips(
+        cfg.command_timeout(),
+        cfg.interface(),
+        &cfg.exclude_interfaces(),
+    )
+    .await?;
+    if format == StatusFormat::Shell {
+        let ports: Vec<String> = all_ports.iter().map(ToString::to_string).collect();
+        writeln!(out, "WSL_IP={}", shell_quote(&addresses.ipv4.to_string()))?;
+        if let Some(ipv6) = addresses.ipv6 {
+            writeln!(out, "WSL_IPV6={}", shell_quote(&ipv6.to_string()))?;
+        }
+        writeln!(out, "WSL_PORTS={}", shell_quote(&ports.join(" ")))?;
+        out.flush()?;
+        return Ok(());
+    }
+    let now = config::unix_now();
+    let last_seen = cfg
+        .runtime
+        .seen_path
+        .as_deref()
+        .map(seen::load)
+        .unwrap_or_default();
+    let networking = network::networking_mode(cfg.command_timeout()).await;
+    if format == StatusFormat::Json {
+        let last_seen: serde_json::Map<String, serde_json::Value> = last_seen
+            .iter()
+            .map(|(port, &at)| {
+                let entry = serde_json::json!({
+                    "at": at,
+                    "seconds_ago": now.saturating_sub(at),
+                    "ago": seen::describe_age(at, now),
+                });
+                (port.to_string(), entry)
+            })
+            .collect();
+        let value = serde_json::json!({
+            "ip": addresses.ipv4,
+            "ipv6": addresses.ipv6,
+            "config": path,
+            "networking_mode": networking.as_ref().map(|networking| networking.mode.to_string()),
+            "connect_mode": effective_connect_mode(&cfg).await.to_string(),
+            "ports": all_ports,
+            "detected_ports": cfg.detected_ports(),
+            "last_seen": last_seen,
+        });
+        writeln!(out, "{value}")?;
+        out.flush()?;
+        return Ok(());
+    }
+    let ps = windows::PowerShell::new(&cfg);
+    let rules = windows::list_portproxy_rules(&ps).await;
+    let (desired, held) = match resolve_forwards(&cfg).await {
+        Ok(resolved) => (resolved.forwards, resolved.held),
+        Err(err) => {
+            tracing::debug!(error = %err, "could not resolve desired rules for labels");
+            (Vec::new(), Vec::new())
+        }
+    };
+
+    writeln!(out, "WSL IP: {}", addresses.ipv4)?;
+    if let Some(ipv6) = addresses.ipv6 {
+        writeln!(out, "WSL IPv6: {ipv6} (used by v4tov6/v6tov6 rules)")?;
+    }
+    writeln!(out, "Config file: {}", path.display())?;
+    match &networking {
+        Some(networking) => writeln!(
+            out,
+            "WSL networking: {} (from {})",
+            networking.mode, networking.source
+        )?,
+        None => writeln!(out, "WSL networking: unknown (no wslinfo or .wslconfig)")?,
+    }
+    match cfg.connect_mode {
+        Some(mode) => writeln!(out, "Connect mode: {mode} (connect_mode)")?,
+        None => writeln!(
+            out,
+            "Connect mode: {} (from the networking mode; set connect_mode to override)",
+            effective_connect_mode(&cfg).await
+        )?,
+    }
+    let mirrored = networking
+        .as_ref()
+        .is_some_and(|networking| networking.mode == network::NetworkingMode::Mirrored);
+    if mirrored && cfg.connect_mode == Some(config::ConnectMode::Eth0) {
+        writeln!(
+            out,
+            "Warning: connect_mode = \"eth0\" but WSL uses mirrored networking; \"loopback\" (or leaving connect_mode unset) usually works"
+        )?;
+    }
+    if cfg.is_paused() {
+        writeln!(out, "Daemon paused (wsl-port resume to sync again)")?;
+    }
+    writeln!(out, "Manual ports: {}", list(&cfg.manual_ports))?;
+    if !cfg.fragments.manual_ports.is_empty() {
+        writeln!(
+            out,
+            "Ports from ports.d: {}",
+            list(&cfg.fragments.manual_ports)
+        )?;
+    }
+    if let Some(include) = &cfg.include_file {
+        writeln!(
+            out,
+            "Ports from {}: {}",
+            include.display(),
+            list(&cfg.included_ports)
+        )?;
+    }
+    for temp in &cfg.temp_ports {
+        writeln!(
+            out,
+            "Temporary port {} until {}",
+            temp.port,
+            format_unix_utc(temp.expires_at)
+        )?;
+    }
+    if let (Some(name), Some(profile)) = (&cfg.active_profile, cfg.active_profile()) {
+        writeln!(out, "Profile {name} ports: {}", list(&profile.manual_ports))?;
+    }
+    if !cfg.remaps.is_empty() {
+        let remaps: Vec<String> = cfg
+            .remaps
+            .iter()
+            .map(|r| format!("{} -> {}", r.listen, r.connect))
+            .collect();
+        writeln!(out, "Remapped ports: {}", remaps.join(", "))?;
+    }
+    // Says why a detected list is empty (or short) instead of leaving users guessing.
+    let failure = |source| match report.get(&source) {
+        Some(detector::DetectorStatus::Failed(err)) => format!(" (detection failed: {err})"),
+        _ => String::new(),
+    };
+    let pm2_failure = failure(ports::PortSource::Pm2);
+    writeln!(out, "PM2 ports: {}{pm2_failure}", list(&cfg.pm2_ports))?;
+    let caddy_failure = failure(ports::PortSource::Caddy);
+    writeln!(
+        out,
+        "Caddy ports: {}{caddy_failure}",
+        list(&cfg.caddy_ports)
+    )?;
+    if cfg.detect_listeners {
+        writeln!(out, "Listener ports: {}", list(&cfg.listener_ports))?;
+    }
+    if !cfg.dotenv_files.is_empty() {
+        let dotenv_failure = failure(ports::PortSource::Dotenv);
+        writeln!(
+            out,
+            "Dotenv ports: {}{dotenv_failure}",
+            list(&cfg.dotenv_ports)
+        )?;
+    }
+    if cfg.detect_traefik {
+        let traefik_failure = failure(ports::PortSource::Traefik);
+        writeln!(
+            out,
+            "Traefik ports: {}{traefik_failure}",
+            list(&cfg.traefik_ports)
+        )?;
+    }
+    if cfg.detect_dev_servers {
+        writeln!(out, "Dev server ports: {}", list(&cfg.dev_server_ports))?;
+    }
+    if !cfg.compose_dirs.is_empty() {
+        let compose_failure = failure(ports::PortSource::Compose);
+        writeln!(
+            out,
+            "Compose ports: {}{compose_failure}",
+            list(&cfg.compose_ports)
+        )?;
+    }
+    if let Some(url) = &cfg.remote_ports_url {
+        let remote_failure = failure(ports::PortSource::Remote);
+        writeln!(
+            out,
+            "Remote ports ({url}): {}{remote_failure}",
+            list(&cfg.remote_ports)
+        )?;
+    }
+    if cfg.detect_docker {
+        let docker_failure = failure(ports::PortSource::Docker);
+        writeln!(
+            out,
+            "Docker ports (to WSL IP): {}{docker_failure}",
+            list(&cfg.docker_ports)
+        )?;
+    }
+    if !last_seen.is_empty() {
+        let entries: Vec<String> = last_seen
+            .iter()
+            .map(|(port, &at)| format!("{port} ({})", seen::describe_age(at, now)))
+            .collect();
+        writeln!(out, "Last seen listening: {}", entries.join(", "))?;
+    }
+    let listening = detector::listening_processes(cfg.command_timeout()).await;
+    if !listening.is_empty() {
+        let entries: Vec<String> = listening
+            .iter()
+            .map(|(port, process)| match process {
+                Some(name) => format!("{port} ({name})"),
+                None => port.to_string(),
+            })
+            .collect();
+        writeln!(out, "Listening in WSL: {}", entries.join(", "))?;
+    }
+    let containers = detector::container_services(cfg.command_timeout()).await;
+    if !containers.is_empty() {
+        let entries: Vec<String> = containers
+            .iter()
+            .map(|(port, service)| format!("{port} ({service})"))
+            .collect();
+        writeln!(out, "Container ports: {}", entries.join(", "))?;
+    }
+    if cfg.runtime.no_detect {
+        writeln!(
+            out,
+            "Detection skipped (--no-detect); detected ports above are not forwarded."
+        )?;
+    }
+    if !cfg.exclude_ports.is_empty() {
+        writeln!(out, "Excluded detected ports: {}", list(&cfg.exclude_ports))?;
+    }
+    if !cfg.exclude_all.is_empty() {
+        writeln!(out, "Excluded from all sources: {}", list(&cfg.exclude_all))?;
+    }
+    if !cfg.sticky_ports.is_empty() {
+        writeln!(out, "Sticky (kept by prune): {}", list(&cfg.sticky_ports))?;
+    }
+    let ranges = |ranges: &[ports::PortRange]| {
+        ranges
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    if !cfg.exclude_ranges.is_empty() {
+        writeln!(
+            out,
+            "Excluded detected ranges: {}",
+            ranges(&cfg.exclude_ranges)
+        )?;
+    }
+    if !cfg.exclude_all_ranges.is_empty() {
+        writeln!(
+            out,
+            "Excluded ranges from all sources: {}",
+            ranges(&cfg.exclude_all_ranges)
+        )?;
+    }
+    if let Some(sources) = &cfg.sources {
+        let names: Vec<String> = sources.iter().map(ToString::to_string).collect();
+        writeln!(out, "Forwarding only from sources: {}", names.join(", "))?;
+    }
+    writeln!(out, "All forwarded ports: {}", list(&all_ports))?;
+    for shadow in cfg.remap_shadows() {
+        writeln!(out, "Warning: {shadow}")?;
+    }
+    if !held.is_empty() {
+        let ports: BTreeSet<u16> = held.iter().map(|f| f.listen_port).collect();
+        writeln!(
+            out,
+            "Held back as privileged/reserved on Windows (--allow-privileged to forward): {}",
+            list(&ports)
+        )?;
+    }
+    if !cfg.loopback_relay_ports.is_empty() {
+        let ports: BTreeSet<u16> = cfg.loopback_relay_ports.iter().copied().collect();
+        writeln!(
+            out,
+            "Loopback relays (daemon only, WSL IP -> 127.0.0.1): {}",
+            list(&ports)
+        )?;
+    }
+    if !cfg.udp_ports.is_empty() {
+        let relays: Vec<String> = cfg.udp_ports.iter().map(ToString::to_string).collect();
+        writeln!(
+            out,
+            "UDP relays (daemon only, reachable from Windows): {}",
+            relays.join(", ")
+        )?;
+    }
+    for failover in &cfg.failover {
+        let fallbacks: Vec<String> = failover.fallbacks.iter().map(ToString::to_string).collect();
+        let active = desired
+            .iter()
+            .find_map(|forward| match forward.connect_address {
+                IpAddr::V4(address) if forward.listen_port == failover.port => Some(address),
+                _ => None,
+            });
+        let state = match active {
+            Some(address) if failover.fallbacks.contains(&address) => {
+                format!("failed over to {address}")
+            }
+            Some(address) => format!("on primary {address}"),
+            None => "not forwarded".to_string(),
+        };
+        writeln!(
+            out,
+            "Failover {}: {state} (fallbacks {})",
+            failover.port,
+            fallbacks.join(", ")
+        )?;
+    }
+    for (name, target) in &cfg.targets {
+        let via = match (&target.address, &target.distro) {
+            (Some(address), _) => address.to_string(),
+            (None, Some(distro)) => format!("distro {distro}"),
+            (None, None) => "unresolved".to_string(),
+        };
+        writeln!(out, "Target {name} ({via}): {}", list(&target.manual_ports))?;
+    }
+    render_mappings(
+        &mut out,
+        rules,
+        include_unmanaged,
+        |rule| match diff::ownership(rule, &desired) {
+            diff::Ownership::Managed(forward) => Some(format!(
+                "managed by wsl-port: {}",
+                forward.describe_sources()
+            )),
+            diff::Ownership::Stale => {
+                Some("managed port, stale target; next sync rewrites it".to_string())
+            }
+            diff::Ownership::Unmanaged => None,
+        },
+    )?;
 
-    println!("WSL IP: {current_ip}");
-    println!("Config file: {}", path.display());
-    println!("Manual ports: {:?}", cfg.manual_ports);
-    println!("PM2 ports: {:?}", cfg.pm2_ports);
-    println!("Caddy ports: {:?}", cfg.caddy_ports);
-    println!("All forwarded ports: {:?}", all_ports);
-    println!("\nCurrent netsh portproxy mappings:\n{rules}");
+    out.flush()?;
+    Ok(())
+}
+
+/// `status --fast`: the config as last saved plus one `netsh ... show all`.
+/// Nothing else runs (no detection, IP lookup, networking or connect-address
+/// probes), so rules are only matched by listen port, not checked for a stale target.
+async fn status_fast(
+    out: &mut dyn Write,
+    path: &Path,
+    cfg: &config::PortsConfig,
+    expand: bool,
+    include_unmanaged: bool,
+) -> Result<()> {
+    let list = |ports: &BTreeSet<u16>| ports::display_set(ports, expand);
+    let all_ports = cfg.all_ports();
+    writeln!(out, "Config file: {}", path.display())?;
+    if cfg.is_paused() {
+        writeln!(out, "Daemon paused (wsl-port resume to sync again)")?;
+    }
+    writeln!(out, "Manual ports: {}", list(&cfg.effective_manual_ports()))?;
+    if !cfg.remaps.is_empty() {
+        let remaps: Vec<String> = cfg
+            .remaps
+            .iter()
+            .map(|r| format!("{} -> {}", r.listen, r.connect))
+            .collect();
+        writeln!(out, "Remapped ports: {}", remaps.join(", "))?;
+    }
+    writeln!(
+        out,
+        "Detected ports (as last saved): {}",
+        list(&cfg.detected_ports())
+    )?;
+    writeln!(out, "All forwarded ports: {}", list(&all_ports))?;
+    writeln!(
+        out,
+        "Detection and WSL/Windows probes skipped (--fast); drop --fast for IPs, networking mode, and stale-target checks."
+    )?;
 
+    // Where each forwarded port listens on Windows: its offset slot, or itself for remaps.
+    let mut listen_ports = all_ports.clone();
+    listen_ports.extend(
+        all_ports
+            .iter()
+            .filter_map(|&port| plan::offset_port(port, cfg.connect_port_offset)),
+    );
+    let ps = windows::PowerShell::new(cfg);
+    let rules = windows::list_portproxy_rules(&ps).await;
+    render_mappings(out, rules, include_unmanaged, |rule| {
+        listen_ports
+            .contains(&rule.listen_port)
+            .then(|| "managed port; target not checked under --fast".to_string())
+    })?;
+    out.flush()?;
     Ok(())
 }
 
-async fn cmd_add(port: u16) -> Result<()> {
-    ensure_valid_port(port)?;
+/// The `Current netsh portproxy mappings` table of `status`. `label` names
+/// what manages a rule, or `None` for unmanaged ones, which are only counted
+/// unless `include_unmanaged`.
+fn render_mappings(
+    out: &mut dyn Write,
+    rules: Result<Vec<windows::PortproxyRule>, BridgeError>,
+    include_unmanaged: bool,
+    label: impl Fn(&windows::PortproxyRule) -> Option<String>,
+) -> Result<()> {
+    writeln!(out, "\nCurrent netsh portproxy mappings:")?;
+    match rules {
+        Err(BridgeError::Offline) => writeln!(out, "  unavailable (offline)")?,
+        Ok(rules) if rules.is_empty() => writeln!(out, "  (none)")?,
+        Ok(rules) => {
+            let mut hidden = 0;
+            for rule in &rules {
+                let label = match label(rule) {
+                    Some(label) => label,
+                    None if include_unmanaged => "unmanaged/external".to_string(),
+                    None => {
+                        hidden += 1;
+                        continue;
+                    }
+                };
+                writeln!(out, "  {}  [{label}]", diff::describe(rule))?;
+            }
+            if hidden == rules.len() {
+                writeln!(out, "  (none managed)")?;
+            }
+            if hidden > 0 {
+                let noun = if hidden == 1 { "rule" } else { "rules" };
+                writeln!(
+                    out,
+                    "  {hidden} unmanaged {noun} from other tools or leftovers hidden (--include-unmanaged lists them)"
+                )?;
+            }
+        }
+        Err(err) => writeln!(out, "Could not fetch netsh mappings: {err}")?,
+    }
+    Ok(())
+}
 
-    let path = config::config_path()?;
-    let mut cfg = config::load_or_default(&path)?;
+/// Skips detection and reads netsh once, so it stays cheap enough to run on every prompt.
+async fn cmd_status_summary(opts: &GlobalOpts) -> Result<()> {
+    let mut out = primary_output(opts)?;
+    let (_, cfg) = load_config(opts)?;
+    let resolved = resolve_forwards(&cfg).await?;
+    let ps = windows::PowerShell::new(&cfg);
+    let rules = windows::list_portproxy_rules(&ps).await?;
 
-    let inserted = cfg.add_manual_port(port);
-    let (pm2_ports, caddy_ports) = detector::detect_ports().await;
-    cfg.set_detected_ports(pm2_ports, caddy_ports);
+    let drift = diff::compute(&resolved.forwards, &rules);
+    let state = if drift.missing.is_empty() && drift.stale.is_empty() {
+        "synced"
+    } else {
+        "drift!"
+    };
+    let ports: BTreeSet<u16> = resolved.forwards.iter().map(|f| f.listen_port).collect();
+    let noun = if ports.len() == 1 { "port" } else { "ports" };
+    writeln!(
+        out,
+        "WSL {} \u{b7} {} {noun} \u{b7} {state}",
+        resolved.ip,
+        ports.len()
+    )?;
+    out.flush()?;
+    Ok(())
+}
+
+async fn cmd_add(opts: &GlobalOpts, spec: ports::PortSpec, sticky: bool, open: bool) -> Result<()> {
+    let spec = match spec {
+        ports::PortSpec::Remap(remap) => remap,
+        ports::PortSpec::Range(range) => return cmd_add_range(opts, range, sticky, open).await,
+    };
+    let (path, mut cfg) = load_config(opts)?;
+    if sticky {
+        cfg.sticky_ports.insert(spec.listen);
+    }
+    if open {
+        cfg.open_on_sync_ports.insert(spec.listen);
+    }
+
+    let inserted = if spec.listen == spec.connect {
+        cfg.add_manual_port(spec.listen)
+    } else {
+        cfg.add_remap(spec)
+    };
+    refresh_detected_ports(&mut cfg).await?;
     config::save(&path, &cfg)?;
 
-    sync_current_config(&cfg).await?;
+    sync_current_config(&cfg, history::Trigger::ConfigChange).await?;
 
+    let label = if spec.listen == spec.connect {
+        format!("port {}", spec.listen)
+    } else {
+        format!("remap {} -> {}", spec.listen, spec.connect)
+    };
     if inserted {
-        println!("Added port {port} and synced rules.");
+        println!("Added {label} and synced rules.");
     } else {
-        println!("Port {port} already present; synced rules anyway.");
+        println!("Already have {label}; synced rules anyway.");
+    }
+    if cfg.excluded_everywhere(spec.listen) {
+        println!(
+            "Note: port {} is in exclude_all or exclude_all_ranges, so it stays unforwarded until you remove it there.",
+            spec.listen
+        );
+    } else if cfg.is_reserved(spec.listen) && !cfg.runtime.allow_privileged {
+        println!(
+            "Note: port {} is privileged or reserved on Windows, so it's held back; pass --allow-privileged to forward it.",
+            spec.listen
+        );
     }
 
     Ok(())
 }
 
-async fn cmd_remove(port: u16) -> Result<()> {
-    ensure_valid_port(port)?;
-
-    let path = config::config_path()?;
-    let mut cfg = config::load_or_default(&path)?;
-
-    let removed = cfg.remove_manual_port(port);
-    let (pm2_ports, caddy_ports) = detector::detect_ports().await;
-    cfg.set_detected_ports(pm2_ports, caddy_ports);
+/// `add` for a whole range: every port becomes a manual port (saved back as
+/// one `manual_ranges` entry) and the sync applies them as one batch.
+async fn cmd_add_range(
+    opts: &GlobalOpts,
+    range: ports::PortRange,
+    sticky: bool,
+    open: bool,
+) -> Result<()> {
+    let (path, mut cfg) = load_config(opts)?;
+    if sticky {
+        cfg.sticky_ports.extend(range.ports());
+    }
+    if open {
+        cfg.open_on_sync_ports.extend(range.ports());
+    }
+    let added = range
+        .ports()
+        .filter(|&port| cfg.add_manual_port(port))
+        .count();
+    refresh_detected_ports(&mut cfg).await?;
     config::save(&path, &cfg)?;
 
-    sync_current_config(&cfg).await?;
+    sync_current_config(&cfg, history::Trigger::ConfigChange).await?;
 
-    if removed {
-        println!("Removed port {port} and synced rules.");
+    if added == 0 {
+        println!("Already have ports {range}; synced rules anyway.");
     } else {
-        println!("Port {port} was not in manual config; synced rules anyway.");
+        println!(
+            "Added {added} of {} ports in {range} and synced rules.",
+            range.len()
+        );
+    }
+    if range.ports().any(|port| cfg.excluded_everywhere(port)) {
+        println!(
+            "Note: part of {range} is in exclude_all or exclude_all_ranges, so those ports stay unforwarded."
+        );
+    } else if !cfg.runtime.allow_privileged && range.ports().any(|port| cfg.is_reserved(port)) {
+        println!(
+            "Note: part of {range} is privileged or reserved on Windows, so it's held back; pass --allow-privileged to forward it."
+        );
     }
 
     Ok(())
 }
 
-async fn cmd_sync() -> Result<()> {
-    let path = config::config_path()?;
-    let mut cfg = config::load_or_default(&path)?;
-    let (pm2_ports, caddy_ports) = detector::detect_ports().await;
-    cfg.set_detected_ports(pm2_ports, caddy_ports);
+async fn cmd_temp(opts: &GlobalOpts, port: u16, ttl: Duration) -> Result<()> {
+    ensure_valid_port(port)?;
+
+    let (path, mut cfg) = load_config(opts)?;
+    let expires_at = cfg.add_temp_port(port, ttl);
+    refresh_detected_ports(&mut cfg).await?;
     config::save(&path, &cfg)?;
 
-    sync_current_config(&cfg).await?;
-    println!("Sync complete.");
+    sync_current_config(&cfg, history::Trigger::ConfigChange).await?;
+    schedule_expiry_sync(opts, ttl)?;
+
+    println!(
+        "Forwarding port {port} until {} (running daemons also remove it on expiry).",
+        format_unix_utc(expires_at)
+    );
+    Ok(())
+}
+
+/// Without a daemon nothing would notice the expiry, so leave a detached
+/// `sleep && wsl-port sync` behind to clean up.
+fn schedule_expiry_sync(opts: &GlobalOpts, ttl: Duration) -> Result<()> {
+    let exe = std::env::current_exe().context("failed locating wsl-port executable")?;
+    let mut command = std::process::Command::new("nohup");
+    command
+        .arg("sh")
+        .arg("-c")
+        .arg("sleep \"$1\"; shift; exec \"$@\"")
+        .arg("sh")
+        .arg((ttl.as_secs() + 1).to_string())
+        .arg(exe)
+        .args(opts.to_args())
+        .arg("sync")
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null());
+    process::show_spawn(&command);
+    command
+        .spawn()
+        .context("failed scheduling expiry cleanup")?;
     Ok(())
 }
 
-async fn cmd_daemon() -> Result<()> {
-    tracing::info!("starting daemon; poll interval = 5s");
+fn parse_ttl(input: &str) -> Result<Duration, String> {
+    let input = input.trim();
+    let (digits, unit) = match input.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => input.split_at(idx),
+        None => (input, "s"),
+    };
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid duration `{input}`"))?;
+    let unit_secs: u64 = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        _ => return Err(format!("unknown unit in `{input}`; use s, m, h, or d")),
+    };
+    let secs = value
+        .checked_mul(unit_secs)
+        .ok_or_else(|| format!("duration `{input}` is too long"))?;
+    if secs == 0 {
+        return Err("duration must be positive".to_string());
+    }
+    Ok(Duration::from_secs(secs))
+}
 
-    let path = config::config_path()?;
-    let mut last_ip: Option<Ipv4Addr> = None;
-    let mut last_ports: BTreeSet<u16> = BTreeSet::new();
+/// A history cutoff as a unix timestamp: a duration back from now (`1h`, `30m`)
+/// or a UTC date/time (`2024-01-01`, `2024-01-01 12:30`, `2024-01-01T12:30:00Z`).
+fn parse_since(input: &str) -> Result<u64, String> {
+    let input = input.trim();
+    if !input.contains('-') {
+        let ago = parse_ttl(input)?;
+        return Ok(config::unix_now().saturating_sub(ago.as_secs()));
+    }
 
-    loop {
-        let mut cfg = config::load_or_default(&path)?;
-        let (pm2_ports, caddy_ports) = detector::detect_ports().await;
-        cfg.set_detected_ports(pm2_ports, caddy_ports);
-        config::save(&path, &cfg)?;
+    let invalid = || format!("invalid time `{input}`; use e.g. 1h or 2024-01-01 12:30");
+    let trimmed = input.trim_end_matches('Z');
+    let (date, time) = trimmed.split_once(['T', ' ']).unwrap_or((trimmed, "00:00"));
+    let date: Vec<i64> = date
+        .split('-')
+        .map(|part| part.parse().map_err(|_| invalid()))
+        .collect::<Result<_, _>>()?;
+    let time: Vec<u64> = time
+        .split(':')
+        .map(|part| part.parse().map_err(|_| invalid()))
+        .collect::<Result<_, _>>()?;
+    let (&[year, month, day], &[hour, minute, ref seconds @ ..]) = (&date[..], &time[..]) else {
+        return Err(invalid());
+    };
+    let second = match seconds {
+        [] => 0,
+        [second] => *second,
+        _ => return Err(invalid()),
+    };
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return Err(invalid());
+    }
+
+    // Days-from-civil (Howard Hinnant's algorithm), the inverse of `format_unix_utc`.
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era
+        .checked_mul(146_097)
+        .and_then(|days| days.checked_add(doe - 719_468))
+        .and_then(|days| u64::try_from(days).ok())
+        .ok_or_else(invalid)?;
+    days.checked_mul(86_400)
+        .and_then(|secs| secs.checked_add(hour * 3600 + minute * 60 + second))
+        .ok_or_else(invalid)
+}
+
+/// `YYYY-MM-DD HH:MM:SS UTC` for a unix timestamp.
+fn format_unix_utc(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+
+    // Civil-from-days (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+async fn cmd_remove(opts: &GlobalOpts, range: ports::PortRange, by_connect: bool) -> Result<()> {
+    if range.len() > 1 {
+        if by_connect {
+            anyhow::bail!("--by-connect takes a single port, not a range");
+        }
+        return cmd_remove_range(opts, range).await;
+    }
+    let port = range.start;
 
-        let ip = get_wsl_ip().await?;
-        let ports = cfg.all_ports();
+    let (path, mut cfg) = load_config(opts)?;
+    refresh_detected_ports(&mut cfg).await?;
 
-        let changed = last_ip != Some(ip) || last_ports != ports;
-        if changed {
-            let sorted_ports: Vec<u16> = ports.iter().copied().collect();
-            tracing::info!(ip = %ip, ports = ?sorted_ports, "change detected; syncing portproxy rules");
-            windows::apply_portproxy_rules(ip, &sorted_ports).await?;
-            last_ip = Some(ip);
-            last_ports = ports;
+    if by_connect {
+        let remaps = cfg.remove_remaps_by_connect(port);
+        if remaps.is_empty() {
+            anyhow::bail!(
+                "no remap connects to port {port}; without --by-connect, remove takes a listen port"
+            );
+        }
+        config::save(&path, &cfg)?;
+        delete_unforwarded_rules(&cfg, remaps.iter().map(|remap| remap.listen)).await?;
+        sync_current_config(&cfg, history::Trigger::ConfigChange).await?;
+        for remap in &remaps {
+            println!(
+                "Removed remap {} -> {} and synced rules.",
+                remap.listen, remap.connect
+            );
         }
+        return Ok(());
+    }
+
+    let remap = cfg.remove_remap(port);
+    let removed = remap.is_some() | cfg.remove_manual_port(port);
+    let detected = cfg.is_detected_port(port);
+    let suppressed = cfg.remove_adds_exclude() && cfg.suppress_detected_port(port);
+    config::save(&path, &cfg)?;
+
+    delete_unforwarded_rules(&cfg, [port]).await?;
 
-        sleep(Duration::from_secs(5)).await;
+    sync_current_config(&cfg, history::Trigger::ConfigChange).await?;
+
+    let what = match remap {
+        Some(remap) => format!("remap {} -> {}", remap.listen, remap.connect),
+        None => format!("port {port}"),
+    };
+    if removed && suppressed {
+        println!(
+            "Removed {what} and, since it is also auto-detected, suppressed it via exclude_ports (remove_adds_exclude); synced rules."
+        );
+    } else if suppressed {
+        println!(
+            "Port {port} is auto-detected; suppressed it via exclude_ports (remove_adds_exclude) and deleted its rule."
+        );
+    } else if detected && !cfg.remove_adds_exclude() {
+        let prefix = if removed {
+            format!("Removed {what}; it")
+        } else {
+            format!("Port {port}")
+        };
+        println!(
+            "{prefix} is auto-detected and remove_adds_exclude = false, so detection forwards it again; add it to exclude_ports to suppress it."
+        );
+    } else if removed && cfg.all_ports().contains(&port) {
+        println!("Removed {what}; port {port} is still forwarded from another source.");
+    } else if removed {
+        println!("Removed {what} and synced rules.");
+    } else if detected {
+        println!("Port {port} is auto-detected and already in exclude_ports; synced rules anyway.");
+    } else {
+        println!("Port {port} was not in manual config; synced rules anyway.");
     }
+
+    Ok(())
 }
 
-async fn sync_current_config(cfg: &config::PortsConfig) -> Result<()> {
-    let ip = get_wsl_ip().await?;
-    let ports: Vec<u16> = cfg.all_ports().into_iter().collect();
-    windows::apply_portproxy_rules(ip, &ports).await?;
+/// `remove` for a range: drops its manual ports and deletes all of their rules
+/// in batched PowerShell calls. Detected and remapped ports are left alone.
+async fn cmd_remove_range(opts: &GlobalOpts, range: ports::PortRange) -> Result<()> {
+    let (path, mut cfg) = load_config(opts)?;
+    refresh_detected_ports(&mut cfg).await?;
+
+    let removed = range
+        .ports()
+        .filter(|&port| cfg.remove_manual_port(port))
+        .count();
+    config::save(&path, &cfg)?;
+
+    delete_unforwarded_rules(&cfg, range.ports()).await?;
+    sync_current_config(&cfg, history::Trigger::ConfigChange).await?;
+
+    if removed == 0 {
+        println!("No manual ports in {range}; synced rules anyway.");
+    } else {
+        println!("Removed {removed} manual ports in {range} and synced rules.");
+    }
+    let still: BTreeSet<u16> = cfg
+        .all_ports()
+        .into_iter()
+        .filter(|&port| range.contains(port))
+        .collect();
+    if !still.is_empty() {
+        println!(
+            "Still forwarded from other sources: {}",
+            ports::display_set(&still, false)
+        );
+    }
+
     Ok(())
 }
 
-fn ensure_valid_port(port: u16) -> Result<()> {
-    if port == 0 {
-        anyhow::bail!("port 0 is invalid")
+async fn cmd_sync(
+    opts: &GlobalOpts,
+    only: Vec<u16>,
+    check: bool,
+    connect_address: Option<Ipv4Addr>,
+) -> Result<()> {
+    let (path, mut cfg) = load_config(opts)?;
+    cfg.runtime.connect_address = connect_address;
+    refresh_detected_ports(&mut cfg).await?;
+    if check {
+        return sync_check(opts, &cfg, &only).await;
     }
+    if !only.is_empty() {
+        return sync_only(&cfg, &only).await;
+    }
+    let expired = cfg.take_expired_temp_ports();
+    config::save(&path, &cfg)?;
+
+    delete_unforwarded_rules(&cfg, expired).await?;
+    sync_current_config(&cfg, history::Trigger::Manual).await?;
+    println!("Sync complete.");
     Ok(())
 }
 
-async fn get_wsl_ip() -> Result<Ipv4Addr> {
-    let output = tokio::process::Command::new("sh")
-        .arg("-c")
-        .arg("hostname -I")
-        .output()
-        .await
-        .context("failed to run hostname -I")?;
+/// `sync --check`: reports what a sync would change without touching netsh or
+/// the config file, failing with `BridgeError::Drift` when anything would.
+/// Unmanaged rules don't count, since a sync leaves them alone.
+async fn sync_check(opts: &GlobalOpts, cfg: &config::PortsConfig, only: &[u16]) -> Result<()> {
+    let mut out = primary_output(opts)?;
+    let mut resolved = resolve_forwards(cfg).await?;
+    if !only.is_empty() {
+        resolved.forwards.retain(|f| only.contains(&f.listen_port));
+        resolved.idle.retain(|f| only.contains(&f.listen_port));
+    }
+    let ps = windows::PowerShell::new(cfg);
+    let rules = windows::list_portproxy_rules(&ps).await?;
 
-    if !output.status.success() {
-        anyhow::bail!("hostname -I failed with {}", output.status);
+    let drift = diff::RuleDiff {
+        unmanaged: Vec::new(),
+        ..diff::compute(&resolved.forwards, &rules)
+    };
+    let idle = diff::managed_rules(&resolved.idle, &rules);
+    if drift.is_empty() && idle.is_empty() {
+        writeln!(out, "In sync: netsh rules match the desired state.")?;
+        out.flush()?;
+        return Ok(());
     }
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let candidate = stdout
-        .split_whitespace()
-        .find_map(|token| token.parse::<Ipv4Addr>().ok())
-        .context("could not parse IPv4 from hostname -I output")?;
+    if !drift.is_empty() {
+        writeln!(out, "{}", diff::render(&drift))?;
+    }
+    for rule in idle {
+        writeln!(
+            out,
+            "- {}  (nothing listening in WSL)",
+            diff::describe(rule)
+        )?;
+    }
+    out.flush()?;
+    let count = diff::drifted_ports(&resolved.forwards, &resolved.idle, &rules).len();
+    Err(BridgeError::Drift(count).into())
+}
 
-    Ok(candidate)
+/// Re-applies just the rules for `only`, leaving every other rule (and expired
+/// temp ports) alone. Ports outside the managed set are rejected.
+async fn sync_only(cfg: &config::PortsConfig, only: &[u16]) -> Result<()> {
+    let managed = cfg.all_ports();
+    let unmanaged: Vec<u16> = only
+        .iter()
+        .copied()
+        .filter(|port| !managed.contains(port))
+        .collect();
+    if !unmanaged.is_empty() {
+        anyhow::bail!(
+            "not managed, so not synced: {unmanaged:?}; add them first or check `wsl-port status`"
+        );
+    }
+
+    let mut resolved = resolve_forwards(cfg).await?;
+    resolved.forwards.retain(|f| only.contains(&f.listen_port));
+    resolved.idle.retain(|f| only.contains(&f.listen_port));
+    run_pre_sync_hook(cfg, &mut resolved).await?;
+    let ps = windows::PowerShell::new(cfg);
+    let created = apply_resolved(&ps, &resolved, &cfg.runtime).await?;
+    open_created_ports(cfg, &ps, &created).await;
+    println!(
+        "Synced {} rule(s) for ports {only:?}.",
+        resolved.forwards.len()
+    );
+    Ok(())
+}
+
+/// A sync that skips the diff: tears down every rule on a managed listen
+/// address and port, correct or not, then adds the full set back.
+async fn cmd_restart(opts: &GlobalOpts) -> Result<()> {
+    let (path, mut cfg) = load_config(opts)?;
+    refresh_detected_ports(&mut cfg).await?;
+    let expired = cfg.take_expired_temp_ports();
+    config::save(&path, &cfg)?;
+    delete_unforwarded_rules(&cfg, expired).await?;
+
+    let mut resolved = resolve_forwards(&cfg).await?;
+    run_pre_sync_hook(&cfg, &mut resolved).await?;
+    let ps = windows::PowerShell::new(&cfg);
+    let rules = windows::list_portproxy_rules(&ps).await?;
+    let managed: Vec<plan::Forward> = resolved
+        .forwards
+        .iter()
+        .chain(&resolved.idle)
+        .cloned()
+        .collect();
+    let removed = diff::managed_rules(&managed, &rules);
+    for rule in &removed {
+        delete_listed_rule(&ps, rule).await?;
+    }
+
+    // Idle rules are already gone; only the forwards go back in.
+    let fresh = Resolved {
+        idle: Vec::new(),
+        ..resolved.clone()
+    };
+    apply_forwards(&ps, &fresh, None, &cfg.runtime).await?;
+    record_history(&cfg, history::Trigger::Manual, &resolved);
+    run_post_sync_hook(&cfg, &resolved).await;
+    println!(
+        "Restart complete: removed {} rule(s), added {}.",
+        removed.len(),
+        resolved.forwards.len()
+    );
+    Ok(())
+}
+
+async fn cmd_diff(opts: &GlobalOpts) -> Result<()> {
+    let mut out = primary_output(opts)?;
+    let (_, mut cfg) = load_config(opts)?;
+    refresh_detected_ports(&mut cfg).await?;
+
+    let resolved = resolve_forwards(&cfg).await?;
+    let ps = windows::PowerShell::new(&cfg);
+    let rules = windows::list_portproxy_rules(&ps).await?;
+
+    let drift = diff::compute(&resolved.forwards, &rules);
+    if drift.is_empty() {
+        writeln!(out, "No drift: netsh rules match the desired state.")?;
+    } else {
+        writeln!(out, "{}", diff::render(&drift))?;
+    }
+
+    out.flush()?;
+    Ok(())
+}
+
+/// `sync --check` against a made-up WSL IP, for rehearsing what the daemon
+/// would rewrite when the address moves.
+async fn cmd_simulate(opts: &GlobalOpts, ip: Ipv4Addr) -> Result<()> {
+    let mut out = primary_output(opts)?;
+    let (_, mut cfg) = load_config(opts)?;
+    refresh_detected_ports(&mut cfg).await?;
+    let connect_mode = effective_connect_mode(&cfg).await;
+    cfg.runtime.connect_address = Some(match connect_mode {
+        config::ConnectMode::Loopback => Ipv4Addr::LOCALHOST,
+        config::ConnectMode::Eth0 | config::ConnectMode::Auto => ip,
+    });
+
+    let resolved = resolve_forwards(&cfg).await?;
+    let ps = windows::PowerShell::new(&cfg);
+    let rules = windows::list_portproxy_rules(&ps).await?;
+
+    let drift = diff::RuleDiff {
+        unmanaged: Vec::new(),
+        ..diff::compute(&resolved.forwards, &rules)
+    };
+    let idle = diff::managed_rules(&resolved.idle, &rules);
+    writeln!(out, "If the WSL IP were {ip}:")?;
+    if connect_mode == config::ConnectMode::Loopback {
+        writeln!(
+            out,
+            "  connect_mode = loopback, so rules don't depend on the WSL IP."
+        )?;
+    }
+    if drift.is_empty() && idle.is_empty() {
+        writeln!(out, "No changes: netsh rules would stay as they are.")?;
+    } else {
+        if !drift.is_empty() {
+            writeln!(out, "{}", diff::render(&drift))?;
+        }
+        for rule in idle {
+            writeln!(
+                out,
+                "- {}  (nothing listening in WSL)",
+                diff::describe(rule)
+            )?;
+        }
+    }
+
+    out.flush()?;
+    Ok(())
+}
+
+async fn cmd_script(opts: &GlobalOpts) -> Result<()> {
+    let mut out = primary_output(opts)?;
+    let (_, mut cfg) = load_config(opts)?;
+    refresh_detected_ports(&mut cfg).await?;
+
+    let resolved = resolve_forwards(&cfg).await?;
+    let recorder = Arc::new(windows::RecordingRunner::default());
+    // One at a time, so the script lists the commands in order.
+    let runtime = config::RuntimeOverrides {
+        sync_timeout: None,
+        max_concurrency: 1,
+        ..cfg.runtime.clone()
+    };
+    apply_forwards(
+        &windows::PowerShell::with_runner(recorder.clone()),
+        &resolved,
+        None,
+        &runtime,
+    )
+    .await?;
+
+    writeln!(out, "# wsl-port sync script for WSL IP {}", resolved.ip)?;
+    writeln!(
+        out,
+        "# Run from an elevated PowerShell or cmd prompt. Deletes may report"
+    )?;
+    writeln!(
+        out,
+        "# \"element not found\" for rules that don't exist yet."
+    )?;
+    for command in recorder.commands() {
+        writeln!(out, "{command}")?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Each run resolves the rules and re-applies every one of them, like `sync --force`.
+/// With `mock` the rules are resolved once up front, so the timed runs contain
+/// no IP lookups, probes, or other interop: only wsl-port's own apply logic.
+async fn cmd_bench(opts: &GlobalOpts, runs: u32, mock: bool) -> Result<()> {
+    let mut out = primary_output(opts)?;
+    let (_, mut cfg) = load_config(opts)?;
+    refresh_detected_ports(&mut cfg).await?;
+
+    let ps = if mock {
+        windows::PowerShell::with_runner(Arc::new(windows::RecordingRunner::default()))
+    } else {
+        windows::PowerShell::new(&cfg)
+    };
+    let prepared = if mock {
+        Some(resolve_forwards(&cfg).await?)
+    } else {
+        None
+    };
+    let mut timings = Vec::new();
+    let mut rules = 0;
+    for _ in 0..runs {
+        let started = Instant::now();
+        let resolved = match &prepared {
+            Some(resolved) => resolved.clone(),
+            None => resolve_forwards(&cfg).await?,
+        };
+        apply_forwards(&ps, &resolved, None, &cfg.runtime).await?;
+        timings.push(started.elapsed());
+        rules = resolved.forwards.len();
+    }
+
+    let min = timings.iter().min().copied().unwrap_or_default();
+    let max = timings.iter().max().copied().unwrap_or_default();
+    let avg = timings.iter().sum::<Duration>() / runs;
+    let runner = if mock { "mock runner" } else { "PowerShell" };
+    writeln!(out, "{runs} sync(s) of {rules} rule(s) via {runner}")?;
+    writeln!(out, "min {min:.1?}  avg {avg:.1?}  max {max:.1?}")?;
+    if rules > 0 {
+        writeln!(out, "per port {:.1?} (avg)", avg / rules as u32)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+async fn cmd_verify(opts: &GlobalOpts, probe: bool, json: bool) -> Result<()> {
+    let mut out = primary_output(opts)?;
+    let (_, mut cfg) = load_config(opts)?;
+    refresh_detected_ports(&mut cfg).await?;
+
+    let resolved = resolve_forwards(&cfg).await?;
+    let ps = windows::PowerShell::new(&cfg);
+    let rules = windows::list_portproxy_rules(&ps).await?;
+
+    let mut report = Vec::new();
+    for forward in &resolved.forwards {
+        let mut health = diff::rule_health(forward, &rules);
+        if probe && health == diff::PortHealth::Ok {
+            // 0.0.0.0 isn't connectable; loopback reaches a wildcard listener.
+            let address = match forward.listen_address {
+                IpAddr::V4(address) if address.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(address) if address.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                address => address,
+            };
+            if !windows::can_reach(&ps, address, forward.listen_port).await? {
+                health = diff::PortHealth::Dead;
+            }
+        }
+        report.push((forward, health));
+    }
+
+    if json {
+        let entries: Vec<serde_json::Value> = report
+            .iter()
+            .map(|(forward, health)| {
+                serde_json::json!({
+                    "listen": forward.listen().to_string(),
+                    "connect": forward.connect().to_string(),
+                    "sources": forward.sources,
+                    "state": health,
+                })
+            })
+            .collect();
+        writeln!(out, "{}", serde_json::Value::Array(entries))?;
+    } else if report.is_empty() {
+        writeln!(out, "No ports to verify.")?;
+    } else {
+        for (forward, health) in &report {
+            writeln!(
+                out,
+                "{health:<7}  {} -> {}  [{}]",
+                forward.listen(),
+                forward.connect(),
+                forward.describe_sources()
+            )?;
+        }
+    }
+    out.flush()?;
+
+    let failed = report
+        .iter()
+        .filter(|(_, health)| *health != diff::PortHealth::Ok)
+        .count();
+    if failed > 0 {
+        return Err(BridgeError::Unverified(failed).into());
+    }
+    Ok(())
+}
+
+/// Counts what `sync --check` would report, by listen port: ports whose rule is
+/// missing or stale, plus idle ports (`only_when_listening`) that still have one.
+/// Read-only and quick: reuses the detected ports stored by the last run and
+/// queries netsh once.
+async fn cmd_drift(opts: &GlobalOpts) -> Result<()> {
+    let mut out = primary_output(opts)?;
+    let (_, cfg) = load_config(opts)?;
+    let resolved = resolve_forwards(&cfg).await?;
+    let ps = windows::PowerShell::new(&cfg);
+    let rules = windows::list_portproxy_rules(&ps).await?;
+
+    let count = diff::drifted_ports(&resolved.forwards, &resolved.idle, &rules).len();
+    writeln!(out, "{count}")?;
+    out.flush()?;
+    if count > 0 {
+        return Err(BridgeError::Drift(count).into());
+    }
+    Ok(())
+}
+
+async fn cmd_prune(opts: &GlobalOpts, all: bool, include_sticky: bool) -> Result<()> {
+    let (_, mut cfg) = load_config(opts)?;
+    refresh_detected_ports(&mut cfg).await?;
+
+    let resolved = resolve_forwards(&cfg).await?;
+    let ps = windows::PowerShell::new(&cfg);
+    let rules = windows::list_portproxy_rules(&ps).await?;
+
+    let sticky = |rule: &windows::PortproxyRule| {
+        !include_sticky && cfg.sticky_ports.contains(&rule.listen_port)
+    };
+    for rule in &rules {
+        let managed = diff::managing_forward(rule, &resolved.forwards).is_some();
+        let mark = match (managed, sticky(rule)) {
+            (true, _) => "managed",
+            (false, true) => "orphaned, sticky",
+            (false, false) => "orphaned",
+        };
+        println!("{}  ({mark})", diff::describe(rule));
+    }
+
+    let drift = diff::compute(&resolved.forwards, &rules);
+    let candidates: Vec<_> = drift
+        .stale
+        .iter()
+        .chain(&drift.unmanaged)
+        .filter(|rule| !sticky(rule))
+        .collect();
+    if candidates.is_empty() {
+        println!("Nothing to prune.");
+        return Ok(());
+    }
+
+    let mut deleted = 0usize;
+    for rule in candidates {
+        if !all && !confirm(&format!("Delete {}?", diff::describe(rule)), false)? {
+            continue;
+        }
+        delete_listed_rule(&ps, rule).await?;
+        deleted += 1;
+    }
+
+    println!("Pruned {deleted} rule(s).");
+    Ok(())
+}
+
+/// Opens one forwarded port, or every HTTP-like one when `port` is `None`.
+async fn cmd_open(opts: &GlobalOpts, port: Option<u16>) -> Result<()> {
+    let (_, mut cfg) = load_config(opts)?;
+    refresh_detected_ports(&mut cfg).await?;
+    let forwarded = cfg.all_ports();
+
+    let targets: Vec<u16> = match port {
+        Some(port) if forwarded.contains(&port) => vec![port],
+        Some(port) => {
+            anyhow::bail!("port {port} isn't forwarded; add it first with `wsl-port add {port}`")
+        }
+        None => forwarded
+            .into_iter()
+            .filter(|&port| ports::is_http_like(port))
+            .collect(),
+    };
+    if targets.is_empty() {
+        println!("No forwarded HTTP ports to open.");
+        return Ok(());
+    }
+
+    let ps = windows::PowerShell::new(&cfg);
+    let mut failed = 0;
+    for port in targets {
+        let url = format!("http://localhost:{port}");
+        match windows::open_url(&ps, &url).await {
+            Ok(()) => println!("Opened {url}"),
+            Err(err) => {
+                failed += 1;
+                eprintln!("Failed to open {url}: {err}");
+            }
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("{failed} port(s) could not be opened in the browser");
+    }
+    Ok(())
+}
+
+async fn cmd_ip(opts: &GlobalOpts, json: bool) -> Result<()> {
+    let mut out = primary_output(opts)?;
+    let (_, cfg) = load_config(opts)?;
+    let network::WslAddresses { ipv4, ipv6 } = network::get_wsl_ips(
+        cfg.command_timeout(),
+        cfg.interface(),
+        &cfg.exclude_interfaces(),
+    )
+    .await?;
+
+    if json {
+        writeln!(out, "{}", serde_json::json!({ "ipv4": ipv4, "ipv6": ipv6 }))?;
+    } else {
+        writeln!(out, "{ipv4}")?;
+        if let Some(ipv6) = ipv6 {
+            writeln!(out, "{ipv6}")?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+async fn cmd_win_ip(opts: &GlobalOpts) -> Result<()> {
+    let mut out = primary_output(opts)?;
+    let (_, cfg) = load_config(opts)?;
+    let lan = windows::lan_ipv4(&windows::PowerShell::new(&cfg)).await?;
+    writeln!(out, "{lan}")?;
+    out.flush()?;
+    Ok(())
+}
+
+fn cmd_history(opts: &GlobalOpts, limit: usize, since: Option<u64>, json: bool) -> Result<()> {
+    let mut out = primary_output(opts)?;
+    let (_, cfg) = load_config(opts)?;
+    let Some(path) = &cfg.runtime.history_path else {
+        anyhow::bail!("no config directory, so no sync history");
+    };
+    let mut entries = history::read(path)
+        .with_context(|| format!("failed reading sync history {}", path.display()))?;
+    if let Some(since) = since {
+        entries.retain(|entry| entry.at >= since);
+    }
+    if entries.is_empty() && !json {
+        let what = if since.is_some() {
+            "in that window"
+        } else {
+            "yet"
+        };
+        writeln!(out, "No syncs recorded {what}.")?;
+    }
+
+    for entry in &entries[entries.len().saturating_sub(limit)..] {
+        if json {
+            writeln!(out, "{}", serde_json::to_string(entry)?)?;
+            continue;
+        }
+        let mut changes = Vec::new();
+        if !entry.added.is_empty() {
+            changes.push(format!("+{:?}", entry.added));
+        }
+        if !entry.removed.is_empty() {
+            changes.push(format!("-{:?}", entry.removed));
+        }
+        if changes.is_empty() {
+            changes.push("no port changes".to_string());
+        }
+        writeln!(
+            out,
+            "{}  {:<13}  {:<15}  {}",
+            format_unix_utc(entry.at),
+            entry.trigger,
+            entry.ip,
+            changes.join(" ")
+        )?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn cmd_pause(opts: &GlobalOpts) -> Result<()> {
+    let (_, cfg) = load_config(opts)?;
+    let Some(path) = &cfg.runtime.pause_path else {
+        anyhow::bail!("no config directory to keep the pause marker in");
+    };
+    if cfg.is_paused() {
+        println!("Already paused.");
+        return Ok(());
+    }
+    config::create_private_file(path)
+        .and_then(|mut file| writeln!(file, "{}", config::unix_now()))
+        .with_context(|| format!("failed creating {}", path.display()))?;
+    println!(
+        "Paused: the daemon won't sync until `wsl-port resume`. Existing rules stay in place."
+    );
+    Ok(())
+}
+
+fn cmd_resume(opts: &GlobalOpts) -> Result<()> {
+    let (_, cfg) = load_config(opts)?;
+    let Some(path) = &cfg.runtime.pause_path else {
+        println!("Not paused.");
+        return Ok(());
+    };
+    match std::fs::remove_file(path) {
+        Ok(()) => println!("Resumed: the daemon syncs on its next tick."),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => println!("Not paused."),
+        Err(err) => return Err(err).with_context(|| format!("failed removing {}", path.display())),
+    }
+    Ok(())
+}
+
+async fn cmd_profile(opts: &GlobalOpts, action: ProfileCommand) -> Result<()> {
+    let (path, mut cfg) = load_config(opts)?;
+
+    if let ProfileCommand::List = action {
+        let mut out = primary_output(opts)?;
+        if cfg.profiles.is_empty() {
+            writeln!(out, "No profiles configured.")?;
+        }
+        for (name, profile) in &cfg.profiles {
+            let marker = if cfg.active_profile.as_ref() == Some(name) {
+                "*"
+            } else {
+                " "
+            };
+            writeln!(out, "{marker} {name}: {:?}", profile.manual_ports)?;
+        }
+        out.flush()?;
+        return Ok(());
+    }
+
+    refresh_detected_ports(&mut cfg).await?;
+    let before = cfg.all_ports();
+
+    let message = match action {
+        ProfileCommand::List => unreachable!("listed above"),
+        ProfileCommand::Use { name: None } => {
+            cfg.active_profile = None;
+            "Deactivated profile and synced rules.".to_string()
+        }
+        ProfileCommand::Use { name: Some(name) } => {
+            if !cfg.profiles.contains_key(&name) {
+                anyhow::bail!("unknown profile {name}; create it with `profile add-port`");
+            }
+            let message = format!("Activated profile {name} and synced rules.");
+            cfg.active_profile = Some(name);
+            message
+        }
+        ProfileCommand::AddPort { name, port } => {
+            ensure_valid_port(port)?;
+            cfg.exclude_ports.remove(&port);
+            cfg.profiles
+                .entry(name.clone())
+                .or_default()
+                .manual_ports
+                .insert(port);
+            format!("Added port {port} to profile {name}.")
+        }
+        ProfileCommand::RemovePort { name, port } => {
+            let profile = cfg
+                .profiles
+                .get_mut(&name)
+                .with_context(|| format!("unknown profile {name}"))?;
+            if !profile.manual_ports.remove(&port) {
+                anyhow::bail!("port {port} is not in profile {name}");
+            }
+            format!("Removed port {port} from profile {name}.")
+        }
+    };
+    config::save(&path, &cfg)?;
+
+    delete_unforwarded_rules(&cfg, before).await?;
+    sync_current_config(&cfg, history::Trigger::ConfigChange).await?;
+
+    println!("{message}");
+    Ok(())
+}
+
+async fn cmd_export(opts: &GlobalOpts, json: bool) -> Result<()> {
+    let mut out = primary_output(opts)?;
+    let (_, mut cfg) = load_config(opts)?;
+    refresh_detected_ports(&mut cfg).await?;
+
+    let resolved = resolve_forwards(&cfg).await?;
+    let snapshot = snapshot::Snapshot::capture(&cfg, resolved.ip, resolved.forwards);
+    if json {
+        writeln!(out, "{}", serde_json::to_string_pretty(&snapshot)?)?;
+    } else {
+        write!(out, "{}", toml::to_string_pretty(&snapshot)?)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// `+` marks entries only in `b`, `-` entries only in `a`.
+fn cmd_compare_config(opts: &GlobalOpts, a: &Path, b: &Path, json: bool) -> Result<()> {
+    let mut out = primary_output(opts)?;
+    let a = config::load_or_default(a)?;
+    let b = config::load_or_default(b)?;
+
+    let mut deltas = Vec::new();
+    config_delta(
+        &mut deltas,
+        "manual_ports",
+        &a.manual_ports,
+        &b.manual_ports,
+    );
+    config_delta(
+        &mut deltas,
+        "remaps",
+        &a.remaps.iter().collect(),
+        &b.remaps.iter().collect(),
+    );
+    config_delta(
+        &mut deltas,
+        "exclude_ports",
+        &a.exclude_ports,
+        &b.exclude_ports,
+    );
+    config_delta(&mut deltas, "exclude_all", &a.exclude_all, &b.exclude_all);
+    config_delta(
+        &mut deltas,
+        "active_profile",
+        &a.active_profile.iter().collect(),
+        &b.active_profile.iter().collect(),
+    );
+
+    if json {
+        let report: serde_json::Map<String, serde_json::Value> = deltas
+            .into_iter()
+            .map(|delta| {
+                let value = serde_json::json!({ "added": delta.added, "removed": delta.removed });
+                (delta.field.to_string(), value)
+            })
+            .collect();
+        writeln!(out, "{}", serde_json::Value::Object(report))?;
+    } else if deltas.is_empty() {
+        writeln!(out, "No differences in manual ports, remaps, or excludes.")?;
+    } else {
+        for delta in deltas {
+            writeln!(out, "{}:", delta.field)?;
+            let plain = |value: &serde_json::Value| match value.as_str() {
+                Some(text) => text.to_string(),
+                None => value.to_string(),
+            };
+            for entry in &delta.added {
+                writeln!(out, "  + {}", plain(entry))?;
+            }
+            for entry in &delta.removed {
+                writeln!(out, "  - {}", plain(entry))?;
+            }
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+struct ConfigDelta {
+    field: &'static str,
+    added: Vec<serde_json::Value>,
+    removed: Vec<serde_json::Value>,
+}
+
+fn config_delta<T: Ord + serde::Serialize>(
+    deltas: &mut Vec<ConfigDelta>,
+    field: &'static str,
+    a: &BTreeSet<T>,
+    b: &BTreeSet<T>,
+) {
+    let values = |items: std::collections::btree_set::Difference<'_, T>| -> Vec<serde_json::Value> {
+        items
+            .filter_map(|item| serde_json::to_value(item).ok())
+            .collect()
+    };
+    let (added, removed) = (values(b.difference(a)), values(a.difference(b)));
+    if !added.is_empty() || !removed.is_empty() {
+        deltas.push(ConfigDelta {
+            field,
+            added,
+            removed,
+        });
+    }
+}
+
+async fn cmd_import(opts: &GlobalOpts, file: &std::path::Path) -> Result<()> {
+    let raw = std::fs::read_to_string(file)
+        .with_context(|| format!("failed reading {}", file.display()))?;
+    let snapshot: snapshot::Snapshot = if raw.trim_start().starts_with('{') {
+        serde_json::from_str(&raw).with_context(|| format!("invalid JSON in {}", file.display()))?
+    } else {
+        toml::from_str(&raw).with_context(|| format!("invalid TOML in {}", file.display()))?
+    };
+
+    let (path, mut cfg) = load_config(opts)?;
+    snapshot.restore_into(&mut cfg);
+    refresh_detected_ports(&mut cfg).await?;
+    config::save(&path, &cfg)?;
+
+    sync_current_config(&cfg, history::Trigger::ConfigChange).await?;
+    println!("Imported {} and synced rules.", file.display());
+    Ok(())
+}
+
+async fn cmd_config_edit(opts: &GlobalOpts) -> Result<()> {
+    let path = config::config_path(opts.config_dir.as_deref(), opts.config_format)?;
+    if !path.exists() {
+        config::save(&path, &config::PortsConfig::default())?;
+    }
+    let original = std::fs::read_to_string(&path)
+        .with_context(|| format!("failed reading config from {}", path.display()))?;
+
+    loop {
+        open_in_editor(&path).await?;
+
+        match config::load_or_default(&path) {
+            Ok(_) => break,
+            Err(err) => {
+                eprintln!("Config is invalid: {err:#}");
+                if !confirm("Reopen the editor?", true)? {
+                    std::fs::write(&path, &original)
+                        .with_context(|| format!("failed restoring config {}", path.display()))?;
+                    println!("Restored the previous config.");
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    println!("Config is valid.");
+    if confirm("Sync rules now?", false)? {
+        let (path, mut cfg) = load_config(opts)?;
+        refresh_detected_ports(&mut cfg).await?;
+        config::save(&path, &cfg)?;
+        sync_current_config(&cfg, history::Trigger::ConfigChange).await?;
+        println!("Sync complete.");
+    }
+
+    Ok(())
+}
+
+async fn open_in_editor(path: &std::path::Path) -> Result<()> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| {
+            if which("vi") {
+                "vi".to_string()
+            } else {
+                "nano".to_string()
+            }
+        });
+
+    // Run through the shell so editors with arguments (e.g. `code --wait`) work.
+    let mut command = tokio::process::Command::new("sh");
+    command
+        .arg("-c")
+        .arg(format!("{editor} \"$1\""))
+        .arg("sh")
+        .arg(path);
+    process::show_spawn(command.as_std());
+    let status = command.status().await;
+    process::show_exit(command.as_std(), status.as_ref().copied());
+    let status = status.with_context(|| format!("failed to launch editor {editor}"))?;
+
+    if !status.success() {
+        anyhow::bail!("editor {editor} exited with {status}");
+    }
+    Ok(())
+}
+
+fn which(program: &str) -> bool {
+    std::env::var_os("PATH")
+        .map(|paths| std::env::split_paths(&paths).any(|dir| dir.join(program).is_file()))
+        .unwrap_or(false)
+}
+
+fn confirm(prompt: &str, default: bool) -> Result<bool> {
+    let hint = if default { "[Y/n]" } else { "[y/N]" };
+    print!("{prompt} {hint} ");
+    std::io::stdout().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(match answer.trim().to_ascii_lowercase().as_str() {
+        "y" | "yes" => true,
+        "n" | "no" => false,
+        _ => default,
+    })
+}
+
+struct DaemonIntervals {
+    ip: Duration,
+    detect: Duration,
+    /// How long a new WSL IP must hold before it triggers a sync.
+    ip_debounce: Duration,
+    /// How often to check netsh for managed rules lost behind the daemon's back.
+    reconcile: Option<Duration>,
+    /// Random ± percentage applied to every poll wait.
+    jitter_pct: u8,
+    /// Upper bound for stretched polls while nothing changes; `None` keeps them fixed.
+    max_poll: Option<Duration>,
+}
+
+/// How the daemon announces its first successful sync.
+struct Readiness {
+    print: bool,
+    file: Option<PathBuf>,
+}
+
+impl Readiness {
+    /// Clears a ready file left over from an earlier run, so waiting on it
+    /// can't succeed before this run has synced.
+    fn reset(&self) -> Result<()> {
+        if let Some(path) = &self.file {
+            match std::fs::remove_file(path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(err).with_context(|| {
+                        format!("failed removing stale ready file {}", path.display())
+                    });
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn signal(&self) -> Result<()> {
+        if self.print {
+            let mut stdout = std::io::stdout();
+            writeln!(stdout, "READY")?;
+            stdout.flush()?;
+        }
+        if let Some(path) = &self.file {
+            std::fs::write(path, b"")
+                .with_context(|| format!("failed creating ready file {}", path.display()))?;
+        }
+        tracing::info!("first sync complete; daemon ready");
+        Ok(())
+    }
+}
+
+async fn cmd_daemon(
+    opts: &GlobalOpts,
+    metrics_addr: Option<SocketAddr>,
+    intervals: DaemonIntervals,
+    readiness: Readiness,
+    give_up_after: Option<u32>,
+) -> Result<()> {
+    tracing::info!(
+        ip_interval_secs = intervals.ip.as_secs(),
+        detect_interval_secs = intervals.detect.as_secs(),
+        ip_debounce_ms = intervals.ip_debounce.as_millis() as u64,
+        jitter_pct = intervals.jitter_pct,
+        "starting daemon"
+    );
+
+    // A flaky detector must not take down the long-running daemon.
+    if opts.strict {
+        tracing::warn!(
+            "--strict is ignored by the daemon; failed detectors are logged and skipped"
+        );
+    }
+    let opts = &GlobalOpts {
+        strict: false,
+        ..opts.clone()
+    };
+
+    readiness.reset()?;
+    let mut ready = false;
+
+    let daemon_metrics = metrics::SharedMetrics::default();
+    if let Some(addr) = metrics_addr {
+        let listener = metrics::bind(addr).await?;
+        tracing::info!(addr = %addr, "serving /healthz and /metrics");
+        tokio::spawn(metrics::serve(listener, daemon_metrics.clone()));
+    }
+
+    let (_, startup_cfg) = load_config(opts)?;
+    if let Err(err) = network::wait_for_wsl_ip(
+        startup_cfg.command_timeout(),
+        startup_cfg.interface(),
+        &startup_cfg.exclude_interfaces(),
+        startup_cfg.startup_grace(),
+    )
+    .await
+    {
+        tracing::warn!(error = %err, "WSL IP still unavailable after startup grace period; continuing");
+    }
+
+    if let Err(err) = clear_stale_rules(opts).await {
+        tracing::warn!(error = %err, "startup reconciliation failed; continuing");
+    }
+
+    // `None` forces the first tick to apply the full desired set right away.
+    let mut last_forwards: Option<Vec<plan::Forward>> = None;
+    let mut applied_ip: Option<Ipv4Addr> = None;
+    let mut pending_ip: Option<(Ipv4Addr, Instant)> = None;
+    let mut cfg = config::PortsConfig::default();
+    let now = Instant::now();
+    let mut next_detect = now;
+    let mut next_ip = now;
+    let mut ip_errors = throttle::RepeatedErrors::new("ip lookup failed; retrying next tick");
+    let mut sync_errors = throttle::RepeatedErrors::new("sync timed out; retrying next tick");
+    let mut expiry_errors =
+        throttle::RepeatedErrors::new("failed removing expired temporary rules");
+    let mut reconcile_errors = throttle::RepeatedErrors::new("reconcile failed; retrying later");
+    let mut hook_errors = throttle::RepeatedErrors::new("pre-sync hook skipped the sync");
+    let mut next_reconcile = intervals.reconcile.map(|every| now + every);
+    let mut udp_relays = udp::Relays::default();
+    let mut loopback_relays = relay::LoopbackRelays::default();
+    let mut pacing = pacing::Pacing::new(intervals.jitter_pct, intervals.max_poll);
+    let mut removal_grace = plan::RemovalGrace::new(startup_cfg.removal_grace_polls());
+    let mut held: Vec<plan::Forward> = Vec::new();
+
+    let mut paused = false;
+
+    let mut cycle_errors = throttle::RepeatedErrors::new("daemon cycle failed; retrying");
+    let mut consecutive_failures: u32 = 0;
+
+    loop {
+        // Anything that would have ended the daemon fails just this cycle; see `give_up_after`.
+        let cycle = async {
+            let now = Instant::now();
+            // Checked before anything else so a paused daemon doesn't even expire temp ports.
+            if startup_cfg.is_paused() != paused {
+                paused = !paused;
+                if paused {
+                    tracing::info!("paused; skipping syncs until `wsl-port resume`");
+                } else {
+                    tracing::info!("resumed");
+                    pacing.changed(now);
+                }
+            }
+            if paused {
+                sleep_until(now + pacing.interval(intervals.ip, now)).await;
+                return Ok(());
+            }
+            let detected = now >= next_detect;
+            if detected {
+                let (path, mut fresh) = load_config(opts)?;
+                refresh_detected_ports(&mut fresh).await?;
+                let expired = fresh.take_expired_temp_ports();
+                config::save(&path, &fresh)?;
+                if !expired.is_empty() {
+                    tracing::info!(ports = ?expired, "temporary forwards expired; removing rules");
+                    match delete_unforwarded_rules(&fresh, expired).await {
+                        Ok(()) => expiry_errors.clear(),
+                        Err(err) => expiry_errors.warn(&err),
+                    }
+                }
+                cfg = fresh;
+                next_detect = now + pacing.interval(intervals.detect, now);
+            }
+            if now >= next_ip {
+                next_ip = now + pacing.interval(intervals.ip, now);
+            }
+
+            let resolved = match resolve_forwards(&cfg).await {
+                Ok(resolved) => {
+                    ip_errors.clear();
+                    resolved
+                }
+                Err(err) if process::is_transient(&err) => {
+                    ip_errors.warn(&err);
+                    sleep_until(next_ip.min(next_detect)).await;
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            };
+
+            // Hold off on a new IP until it has stayed put for the debounce window;
+            // flipping back to the applied IP inside the window cancels the sync.
+            if applied_ip.is_some_and(|ip| ip != resolved.ip) {
+                let since = match pending_ip {
+                    Some((ip, since)) if ip == resolved.ip => since,
+                    _ => {
+                        tracing::info!(ip = %resolved.ip, "WSL IP changed; waiting for it to settle");
+                        pending_ip = Some((resolved.ip, now));
+                        pacing.changed(now);
+                        next_ip = next_ip.min(now + intervals.ip);
+                        next_detect = next_detect.min(now + intervals.detect);
+                        now
+                    }
+                };
+                let settles_at = since + intervals.ip_debounce;
+                if now < settles_at {
+                    next_ip = next_ip.min(settles_at);
+                    sleep_until(next_ip.min(next_detect)).await;
+                    return Ok(());
+                }
+            }
+            pending_ip = None;
+            udp_relays.reconcile(resolved.ip, &cfg.udp_ports);
+            loopback_relays.reconcile(resolved.ip, &cfg.loopback_relay_ports);
+
+            let mut resolved = resolved;
+            // Only detection polls count toward the grace period; IP polls just keep what's held.
+            if detected {
+                held = removal_grace.hold(
+                    last_forwards.as_deref().unwrap_or_default(),
+                    &resolved.forwards,
+                );
+                if !held.is_empty() {
+                    let ports: Vec<u16> = held.iter().map(|f| f.listen_port).collect();
+                    tracing::debug!(ports = ?ports, "keeping vanished ports through the removal grace period");
+                }
+            }
+            for forward in &held {
+                let present = resolved.forwards.iter().any(|desired| {
+                    desired.listen_address == forward.listen_address
+                        && desired.listen_port == forward.listen_port
+                });
+                if !present {
+                    resolved.forwards.push(forward.clone());
+                }
+            }
+            let mut step = daemon_step(last_forwards.as_deref(), &resolved);
+            if !step.is_noop() && cfg.pre_sync_hook().is_some() {
+                match run_pre_sync_hook(&cfg, &mut resolved).await {
+                    Ok(()) => {
+                        hook_errors.clear();
+                        step = daemon_step(last_forwards.as_deref(), &resolved);
+                    }
+                    Err(veto) => {
+                        hook_errors.warn(&format!("{veto:#}"));
+                        step.forwards.clear();
+                        step.idle.clear();
+                    }
+                }
+            }
+            if !step.is_noop() {
+                let ports: Vec<u16> = step.forwards.iter().map(|f| f.listen_port).collect();
+                let removed: Vec<u16> = step.idle.iter().map(|f| f.listen_port).collect();
+                tracing::info!(ip = %resolved.ip, ports = ?ports, removed = ?removed, "change detected; syncing portproxy rules");
+                pacing.changed(now);
+                let ps = windows::PowerShell::new(&cfg);
+                log_sync_diff(&ps, &resolved, &step).await;
+                let result = apply_resolved(&ps, &step, &cfg.runtime).await;
+                daemon_metrics
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .record_sync(resolved.ip, resolved.forwards.len(), result.is_ok());
+                match result {
+                    Ok(created) => {
+                        sync_errors.clear();
+                        open_created_ports(&cfg, &ps, &created).await;
+                        let trigger = if applied_ip.is_some_and(|ip| ip != resolved.ip) {
+                            history::Trigger::IpChange
+                        } else {
+                            history::Trigger::ConfigChange
+                        };
+                        record_history(&cfg, trigger, &resolved);
+                        run_post_sync_hook(&cfg, &resolved).await;
+                        if let Some(old_ip) = applied_ip.filter(|&ip| ip != resolved.ip) {
+                            if cfg.notify_on_ip_change {
+                                notify_ip_change(&ps, old_ip, resolved.ip).await;
+                            }
+                        }
+                        applied_ip = Some(resolved.ip);
+                        last_forwards = Some(resolved.forwards);
+                        if !ready {
+                            readiness.signal()?;
+                            ready = true;
+                        }
+                    }
+                    Err(err) if err.is_timeout() => sync_errors.warn(&err),
+                    Err(err) => return Err(err.into()),
+                }
+            } else if let (Some(due), Some(every)) = (next_reconcile, intervals.reconcile) {
+                if now >= due {
+                    next_reconcile = Some(now + every);
+                    let ps = windows::PowerShell::new(&cfg);
+                    match reapply_missing(&ps, &resolved.forwards, &cfg.runtime).await {
+                        Ok(reapplied) => {
+                            reconcile_errors.clear();
+                            if !reapplied.is_empty() {
+                                tracing::info!(ports = ?reapplied, "re-added rules missing from netsh");
+                            }
+                        }
+                        Err(err) => reconcile_errors.warn(&err),
+                    }
+                }
+            }
+
+            sleep_until(next_ip.min(next_detect)).await;
+            Ok::<(), anyhow::Error>(())
+        }
+        .await;
+        match cycle {
+            Ok(()) => {
+                consecutive_failures = 0;
+                cycle_errors.clear();
+            }
+            Err(err) => {
+                consecutive_failures = consecutive_failures.saturating_add(1);
+                if give_up_after.is_some_and(|limit| consecutive_failures >= limit) {
+                    return Err(err.context(format!(
+                        "daemon giving up after {consecutive_failures} consecutive failed cycles (--give-up-after)"
+                    )));
+                }
+                cycle_errors.warn(&format!("{err:#}"));
+                let now = Instant::now();
+                sleep_until(now + pacing.interval(intervals.ip, now)).await;
+            }
+        }
+    }
+}
+
+/// Logs how netsh differs from the desired rules right before a daemon sync,
+/// so the log shows what each sync was for. Best effort: a failed netsh read
+/// only skips the line.
+async fn log_sync_diff(ps: &windows::PowerShell, resolved: &Resolved, step: &Resolved) {
+    let rules = match windows::list_portproxy_rules(ps).await {
+        Ok(rules) => rules,
+        Err(err) => {
+            tracing::debug!(error = %err, "could not read netsh rules for the sync diff");
+            return;
+        }
+    };
+    let drift = diff::compute(&resolved.forwards, &rules);
+    let added: Vec<u16> = drift.missing.iter().map(|f| f.listen_port).collect();
+    let stale: Vec<u16> = drift.stale.iter().map(|r| r.listen_port).collect();
+    let removed: Vec<u16> = step.idle.iter().map(|f| f.listen_port).collect();
+    tracing::info!(
+        added = added.len(),
+        removed = removed.len(),
+        stale = stale.len(),
+        added_ports = ?added,
+        removed_ports = ?removed,
+        stale_ports = ?stale,
+        "sync diff"
+    );
+}
+
+/// What one daemon tick applies: everything on the first sync, afterwards
+/// only what changed, so a config edit that adds one port issues one add and
+/// leaves every still-valid rule alone.
+fn daemon_step(last: Option<&[plan::Forward]>, resolved: &Resolved) -> Resolved {
+    match last {
+        Some(last) => {
+            let (forwards, idle) = plan::delta(last, &resolved.forwards);
+            Resolved {
+                ip: resolved.ip,
+                forwards,
+                idle,
+                held: Vec::new(),
+            }
+        }
+        None => resolved.clone(),
+    }
+}
+
+/// Re-adds any of `forwards` that netsh no longer has exactly, returning their listen ports.
+async fn reapply_missing(
+    ps: &windows::PowerShell,
+    forwards: &[plan::Forward],
+    runtime: &config::RuntimeOverrides,
+) -> Result<Vec<u16>, BridgeError> {
+    let rules = windows::list_portproxy_rules(ps).await?;
+    let missing: Vec<plan::Forward> = forwards
+        .iter()
+        .filter(|forward| diff::rule_health(forward, &rules) != diff::PortHealth::Ok)
+        .cloned()
+        .collect();
+    windows::apply_portproxy_rules(
+        ps,
+        &missing,
+        runtime.sync_timeout,
+        runtime.max_concurrency,
+        runtime.apply_order,
+    )
+    .await?;
+    Ok(missing.iter().map(|forward| forward.listen_port).collect())
+}
+
+/// Best effort: a missing notification mechanism is only logged at debug.
+async fn notify_ip_change(ps: &windows::PowerShell, old_ip: Ipv4Addr, new_ip: Ipv4Addr) {
+    let body = format!("WSL IP changed from {old_ip} to {new_ip}; portproxy rules re-synced.");
+    if let Err(err) = windows::notify(ps, "wsl-port", &body).await {
+        tracing::debug!(error = %err, "desktop notification failed");
+    }
+}
+
+/// Deletes rules for managed ports that still point at an old address, e.g.
+/// after the WSL IP changed while the daemon wasn't running.
+async fn clear_stale_rules(opts: &GlobalOpts) -> Result<()> {
+    let (_, mut cfg) = load_config(opts)?;
+    refresh_detected_ports(&mut cfg).await?;
+
+    let resolved = resolve_forwards(&cfg).await?;
+    let ps = windows::PowerShell::new(&cfg);
+    let rules = windows::list_portproxy_rules(&ps).await?;
+    let stale = diff::compute(&resolved.forwards, &rules).stale;
+
+    for rule in &stale {
+        delete_listed_rule(&ps, rule).await?;
+    }
+
+    tracing::info!(
+        cleared = stale.len(),
+        "startup reconciliation cleared stale rules"
+    );
+    Ok(())
+}
+
+async fn delete_listed_rule(ps: &windows::PowerShell, rule: &windows::PortproxyRule) -> Result<()> {
+    let listen_address = rule
+        .listen_address
+        .parse()
+        .with_context(|| format!("unexpected listen address {} in netsh", rule.listen_address))?;
+    windows::delete_portproxy_rule(ps, rule.family, listen_address, rule.listen_port).await?;
+    Ok(())
+}
+
+/// Deletes the rules for any of `ports` that the config no longer forwards.
+async fn delete_unforwarded_rules(
+    cfg: &config::PortsConfig,
+    ports: impl IntoIterator<Item = u16>,
+) -> Result<()> {
+    let forwarded = cfg.all_ports();
+    let ps = windows::PowerShell::new(cfg);
+    let listen_addresses = resolve_listen_addresses(cfg).await?;
+    let families = cfg.families();
+    let mut slots = Vec::new();
+    for port in ports.into_iter().filter(|port| !forwarded.contains(port)) {
+        // A manual or detected port listens at its offset slot; a remap at its own.
+        let mut listen_ports = vec![port];
+        listen_ports
+            .extend(plan::offset_port(port, cfg.connect_port_offset).filter(|&p| p != port));
+        for listen_port in listen_ports {
+            let family = families.of(listen_port);
+            let addresses: Vec<IpAddr> = if family.listens_v6() {
+                vec![IpAddr::V6(Ipv6Addr::UNSPECIFIED)]
+            } else {
+                listen_addresses.iter().copied().map(IpAddr::V4).collect()
+            };
+            slots.extend(
+                addresses
+                    .into_iter()
+                    .map(|listen_address| (family, listen_address, listen_port)),
+            );
+        }
+    }
+    windows::delete_portproxy_rules(&ps, &slots).await?;
+    Ok(())
+}
+
+async fn sync_current_config(cfg: &config::PortsConfig, trigger: history::Trigger) -> Result<()> {
+    let mut resolved = resolve_forwards(cfg).await?;
+    run_pre_sync_hook(cfg, &mut resolved).await?;
+    let ps = windows::PowerShell::new(cfg);
+    let created = apply_resolved(&ps, &resolved, &cfg.runtime).await?;
+    open_created_ports(cfg, &ps, &created).await;
+    record_history(cfg, trigger, &resolved);
+    run_post_sync_hook(cfg, &resolved).await;
+    Ok(())
+}
+
+/// Runs `pre_sync_hook` with `WSL_IP` and `WSL_PORTS` set. A non-zero exit,
+/// a timeout, or output that isn't a port list vetoes the sync (the error).
+/// If the hook prints ports, only those are forwarded; the rest of `resolved`
+/// moves to `idle` so their rules are removed.
+async fn run_pre_sync_hook(cfg: &config::PortsConfig, resolved: &mut Resolved) -> Result<()> {
+    let Some(hook) = cfg.pre_sync_hook() else {
+        return Ok(());
+    };
+    let output =
+        process::output_with_timeout(&mut hook_command(hook, resolved), cfg.command_timeout())
+            .await
+            .context("pre-sync hook failed, skipping sync")?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let detail = match stderr.trim() {
+            "" => String::new(),
+            stderr => format!(": {stderr}"),
+        };
+        anyhow::bail!(
+            "pre-sync hook exited with {}, skipping sync{detail}",
+            output.status
+        );
+    }
+
+    let keep = stdout
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|token| !token.is_empty())
+        .map(|token| token.parse::<u16>().ok().filter(|&port| port != 0))
+        .collect::<Option<BTreeSet<u16>>>();
+    let Some(keep) = keep else {
+        anyhow::bail!(
+            "pre-sync hook printed `{}`, which isn't a port list; skipping sync",
+            stdout.trim()
+        );
+    };
+    if keep.is_empty() {
+        return Ok(());
+    }
+
+    let known: BTreeSet<u16> = resolved.forwards.iter().map(|f| f.listen_port).collect();
+    let unknown: Vec<u16> = keep.difference(&known).copied().collect();
+    if !unknown.is_empty() {
+        tracing::warn!(ports = ?unknown, "pre-sync hook listed ports that aren't being forwarded; ignoring them");
+    }
+    let (kept, dropped): (Vec<_>, Vec<_>) = resolved
+        .forwards
+        .drain(..)
+        .partition(|f| keep.contains(&f.listen_port));
+    resolved.forwards = kept;
+    resolved.idle.extend(dropped);
+    Ok(())
+}
+
+/// `sh -c <hook>` with the sync's IP and sorted listen ports in the environment.
+fn hook_command(hook: &str, resolved: &Resolved) -> tokio::process::Command {
+    let ports: BTreeSet<u16> = resolved.forwards.iter().map(|f| f.listen_port).collect();
+    let ports: Vec<String> = ports.iter().map(ToString::to_string).collect();
+    let mut command = tokio::process::Command::new("sh");
+    command
+        .arg("-c")
+        .arg(hook)
+        .env("WSL_IP", resolved.ip.to_string())
+        .env("WSL_PORTS", ports.join(" "));
+    command
+}
+
+/// Best effort like `record_history`: a failing or hung hook is logged, and
+/// the sync it follows still counts as successful.
+async fn run_post_sync_hook(cfg: &config::PortsConfig, resolved: &Resolved) {
+    let Some(hook) = cfg.post_sync_hook() else {
+        return;
+    };
+    match process::output_with_timeout(&mut hook_command(hook, resolved), cfg.command_timeout())
+        .await
+    {
+        Ok(output) => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if output.status.success() {
+                tracing::info!(hook, stdout = %stdout.trim(), "post-sync hook finished");
+            } else {
+                tracing::warn!(hook, status = %output.status, stdout = %stdout.trim(), stderr = %stderr.trim(), "post-sync hook failed");
+            }
+        }
+        Err(err) => tracing::warn!(hook, error = %err, "post-sync hook failed"),
+    }
+}
+
+/// Best effort: a history write failure never fails the sync itself.
+fn record_history(cfg: &config::PortsConfig, trigger: history::Trigger, resolved: &Resolved) {
+    let Some(path) = &cfg.runtime.history_path else {
+        return;
+    };
+    let ports = resolved.forwards.iter().map(|f| f.listen_port).collect();
+    if let Err(err) = history::record(path, trigger, resolved.ip, ports) {
+        tracing::warn!(error = %err, path = %path.display(), "failed writing sync history");
+    }
+}
+
+#[derive(Clone)]
+struct Resolved {
+    ip: Ipv4Addr,
+    forwards: Vec<plan::Forward>,
+    /// Rules withheld because nothing in WSL is listening on their port.
+    idle: Vec<plan::Forward>,
+    /// Rules never applied (or deleted) because their listen port is reserved.
+    held: Vec<plan::Forward>,
+}
+
+impl Resolved {
+    /// Nothing to add and nothing to delete.
+    fn is_noop(&self) -> bool {
+        self.forwards.is_empty() && self.idle.is_empty()
+    }
+}
+
+async fn resolve_forwards(cfg: &config::PortsConfig) -> Result<Resolved> {
+    let families = cfg.families();
+    let needs_v6 = families.default.connects_v6()
+        || families.by_port.values().any(|family| family.connects_v6());
+    let file_ip = match (cfg.runtime.connect_address, &cfg.connect_address_file) {
+        (None, Some(path)) => network::read_address_file(path),
+        _ => None,
+    };
+    let (ip, ipv6) = if let Some(address) = cfg.runtime.connect_address {
+        (address, None)
+    } else if needs_v6 {
+        // The file only knows the IPv4, so IPv6 still comes from the lookup.
+        let addresses = network::get_wsl_ips(
+            cfg.command_timeout(),
+            cfg.interface(),
+            &cfg.exclude_interfaces(),
+        )
+        .await?;
+        (file_ip.unwrap_or(addresses.ipv4), addresses.ipv6)
+    } else if let Some(ip) = file_ip {
+        (ip, None)
+    } else {
+        let ip = network::get_wsl_ip(
+            cfg.command_timeout(),
+            cfg.interface(),
+            &cfg.exclude_interfaces(),
+        )
+        .await?;
+        (ip, None)
+    };
+    if cfg.runtime.family == config::FamilyPolicy::Ipv6Only && ipv6.is_none() {
+        anyhow::bail!("--ipv6-only is set, but WSL has no global IPv6 address");
+    }
+    let connect = match cfg.runtime.connect_address {
+        Some(address) => address,
+        None => connect_address(cfg, ip).await,
+    };
+    // Loopback connect mode means loopback for both families.
+    let connect_v6 = if connect.is_loopback() {
+        Some(Ipv6Addr::LOCALHOST)
+    } else {
+        ipv6
+    };
+    let mut forwards = desired_forwards(cfg, connect, connect_v6).await?;
+    apply_failover(cfg, &mut forwards, connect).await;
+    for shadow in cfg.remap_shadows() {
+        tracing::warn!("{shadow}; exclude the detected port or drop the remap to resolve it");
+    }
+
+    let (held, forwards): (Vec<_>, Vec<_>) = forwards
+        .into_iter()
+        .partition(|forward| !cfg.runtime.allow_privileged && cfg.is_reserved(forward.listen_port));
+    if !held.is_empty() {
+        let ports: BTreeSet<u16> = held.iter().map(|f| f.listen_port).collect();
+        let ports: Vec<u16> = ports.into_iter().collect();
+        tracing::warn!(ports = ?ports, "holding back privileged or reserved Windows ports; pass --allow-privileged to forward them");
+    }
+
+    if !cfg.only_when_listening {
+        return Ok(Resolved {
+            ip,
+            forwards,
+            idle: Vec::new(),
+            held,
+        });
+    }
+
+    let listening = network::listening_ports().context("failed reading /proc/net/tcp")?;
+    // Only rules pointing at this distro can be checked against our own socket table.
+    let local = |address: IpAddr| address == connect || connect_v6.is_some_and(|v6| address == v6);
+    let (forwards, idle): (Vec<_>, Vec<_>) = forwards.into_iter().partition(|forward| {
+        !local(forward.connect_address) || listening.contains(&forward.connect_port)
+    });
+    if !idle.is_empty() {
+        let ports: Vec<u16> = idle.iter().map(|f| f.connect_port).collect();
+        tracing::debug!(ports = ?ports, "skipping ports with no listener in WSL");
+    }
+
+    Ok(Resolved {
+        ip,
+        forwards,
+        idle,
+        held,
+    })
+}
+
+/// How long a failover candidate gets to accept a TCP connection.
+const FAILOVER_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Repoints each `failover` port's rules that connect to `primary` at the
+/// first candidate (primary, then the fallbacks) accepting a TCP connection on
+/// the connect port. When none answers the rule stays on the primary.
+async fn apply_failover(
+    cfg: &config::PortsConfig,
+    forwards: &mut [plan::Forward],
+    primary: Ipv4Addr,
+) {
+    for failover in &cfg.failover {
+        let rules = forwards.iter_mut().filter(|forward| {
+            forward.listen_port == failover.port && forward.connect_address == IpAddr::V4(primary)
+        });
+        for forward in rules {
+            let mut chosen = None;
+            for candidate in std::iter::once(primary).chain(failover.fallbacks.iter().copied()) {
+                let target = SocketAddr::new(IpAddr::V4(candidate), forward.connect_port);
+                if network::accepts_tcp(target, FAILOVER_CHECK_TIMEOUT).await {
+                    chosen = Some(candidate);
+                    break;
+                }
+                tracing::debug!(%target, "failover candidate not accepting connections");
+            }
+            match chosen {
+                Some(address) if address != primary => {
+                    tracing::debug!(port = failover.port, %address, "primary unreachable; failing over");
+                    forward.connect_address = IpAddr::V4(address);
+                }
+                Some(_) => {}
+                None => {
+                    tracing::debug!(
+                        port = failover.port,
+                        "no failover candidate reachable; keeping the primary"
+                    )
+                }
+            }
+        }
+    }
+}
+
+/// Last `connect_mode = "auto"` decision, keyed by the WSL IP it was probed for.
+static PROBED_CONNECT: Mutex<Option<(Ipv4Addr, Ipv4Addr)>> = Mutex::new(None);
+
+/// The Windows LAN address behind `listen_addresses = ["auto-lan"]`, looked up
+/// once per process and dropped whenever a sync fails.
+static LAN_ADDRESS: Mutex<Option<Ipv4Addr>> = Mutex::new(None);
+
+async fn resolve_listen_addresses(cfg: &config::PortsConfig) -> Result<Vec<Ipv4Addr>> {
+    let interfaces = resolve_listen_interfaces(cfg).await?;
+    if !cfg.wants_lan_address() {
+        return Ok(cfg.listen_addresses(None, &interfaces));
+    }
+
+    let cached = *LAN_ADDRESS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let lan = match cached {
+        Some(lan) => lan,
+        None => {
+            let lan = windows::lan_ipv4(&windows::PowerShell::new(cfg))
+                .await
+                .context("failed resolving listen address auto-lan")?;
+            tracing::info!(address = %lan, "resolved auto-lan listen address");
+            *LAN_ADDRESS
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(lan);
+            lan
+        }
+    };
+    Ok(cfg.listen_addresses(Some(lan), &interfaces))
+}
+
+/// Looks up every named listen interface afresh, since their addresses can
+/// change between syncs. With `listen_interface_fallback`, one that can't be
+/// resolved listens on 0.0.0.0 instead of failing the sync.
+async fn resolve_listen_interfaces(
+    cfg: &config::PortsConfig,
+) -> Result<BTreeMap<String, Ipv4Addr>> {
+    let mut resolved = BTreeMap::new();
+    let names: Vec<&str> = cfg.listen_interfaces().collect();
+    if names.is_empty() {
+        return Ok(resolved);
+    }
+    let ps = windows::PowerShell::new(cfg);
+    for name in names {
+        let address = match windows::interface_ipv4(&ps, name).await {
+            Ok(address) => address,
+            Err(err) if cfg.listen_interface_fallback => {
+                tracing::warn!(interface = name, error = %err, "can't resolve listen interface; listening on 0.0.0.0 instead");
+                Ipv4Addr::UNSPECIFIED
+            }
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("failed resolving listen interface `{name}`"))
+            }
+        };
+        resolved.insert(name.to_string(), address);
+    }
+    Ok(resolved)
+}
+
+/// `connect_mode` as configured, or chosen from the WSL networking mode when
+/// unset: loopback under mirrored networking, eth0 otherwise.
+async fn effective_connect_mode(cfg: &config::PortsConfig) -> config::ConnectMode {
+    if let Some(mode) = cfg.connect_mode {
+        return mode;
+    }
+    match network::networking_mode(cfg.command_timeout()).await {
+        Some(networking) if networking.mode == network::NetworkingMode::Mirrored => {
+            config::ConnectMode::Loopback
+        }
+        _ => config::ConnectMode::Eth0,
+    }
+}
+
+async fn connect_address(cfg: &config::PortsConfig, ip: Ipv4Addr) -> Ipv4Addr {
+    match effective_connect_mode(cfg).await {
+        config::ConnectMode::Eth0 => ip,
+        config::ConnectMode::Loopback => Ipv4Addr::LOCALHOST,
+        config::ConnectMode::Auto => probe_connect_address(cfg, ip).await,
+    }
+}
+
+/// Tries eth0 then loopback from the Windows side against a port something in
+/// WSL is listening on; falls back to eth0 when nothing answers.
+async fn probe_connect_address(cfg: &config::PortsConfig, ip: Ipv4Addr) -> Ipv4Addr {
+    let cached = *PROBED_CONNECT
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some((probed_ip, chosen)) = cached {
+        if probed_ip == ip {
+            return chosen;
+        }
+    }
+
+    let listening = network::listening_ports().unwrap_or_default();
+    let wanted = cfg.all_ports();
+    let probe_port = listening
+        .iter()
+        .find(|port| wanted.contains(port))
+        .or_else(|| listening.iter().next())
+        .copied();
+    let Some(port) = probe_port else {
+        tracing::info!(address = %ip, "no listening port to probe; using eth0 connect address");
+        return ip;
+    };
+
+    let ps = windows::PowerShell::new(cfg);
+    for (candidate, label) in [(ip, "eth0"), (Ipv4Addr::LOCALHOST, "loopback")] {
+        match windows::can_reach(&ps, IpAddr::V4(candidate), port).await {
+            Ok(true) => {
+                tracing::info!(address = %candidate, port, "{label} reachable from Windows; using it as connect address");
+                *PROBED_CONNECT
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some((ip, candidate));
+                return candidate;
+            }
+            Ok(false) => {
+                tracing::debug!(address = %candidate, port, "{label} not reachable from Windows")
+            }
+            Err(err) => tracing::debug!(error = %err, address = %candidate, "connect probe failed"),
+        }
+    }
+
+    tracing::info!(address = %ip, port, "connect probe inconclusive; falling back to eth0");
+    ip
+}
+
+/// The LAN address may have changed under a failing sync; look it up afresh next time.
+fn forget_lan_address() {
+    *LAN_ADDRESS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = None;
+}
+
+/// Applies `resolved` and returns the listen ports whose rules it created
+/// from nothing (always empty under `--force` without `--transactional`,
+/// since no snapshot is taken to compare against).
+async fn apply_resolved(
+    ps: &windows::PowerShell,
+    resolved: &Resolved,
+    runtime: &config::RuntimeOverrides,
+) -> Result<BTreeSet<u16>, BridgeError> {
+    // One `show` serves both the rollback snapshot and skipping correct rules.
+    let before = if runtime.transactional || !runtime.force {
+        Some(windows::list_portproxy_rules(ps).await?)
+    } else {
+        None
+    };
+    let existing = before.as_deref().filter(|_| !runtime.force);
+    let result = apply_forwards(ps, resolved, existing, runtime).await;
+    if result.is_err() {
+        forget_lan_address();
+    }
+    let created: BTreeSet<u16> = match (&result, &before) {
+        (Ok(()), Some(before)) => resolved
+            .forwards
+            .iter()
+            .filter(|forward| diff::rule_health(forward, before) == diff::PortHealth::Missing)
+            .map(|forward| forward.listen_port)
+            .collect(),
+        _ => BTreeSet::new(),
+    };
+    let Some(before) = before.filter(|_| runtime.transactional) else {
+        return result.map(|()| created);
+    };
+    if let Err(err) = &result {
+        tracing::warn!(error = %err, "sync failed; rolling back to the previous netsh rules");
+        let touched: Vec<(ports::RuleFamily, IpAddr, u16)> = resolved
+            .forwards
+            .iter()
+            .chain(&resolved.idle)
+            .map(|f| (f.family(), f.listen_address, f.listen_port))
+            .collect();
+        if let Err(rollback_err) = windows::restore_portproxy_rules(ps, &before, &touched).await {
+            tracing::error!(error = %rollback_err, "rollback failed; netsh rules may be inconsistent");
+        }
+    }
+    result.map(|()| created)
+}
+
+/// Opens the browser for each freshly created rule on `open_on_sync_ports`.
+/// Failures are only logged; they never fail the sync.
+async fn open_created_ports(
+    cfg: &config::PortsConfig,
+    ps: &windows::PowerShell,
+    created: &BTreeSet<u16>,
+) {
+    for port in created.intersection(&cfg.open_on_sync_ports) {
+        let url = format!("http://localhost:{port}");
+        match windows::open_url(ps, &url).await {
+            Ok(()) => tracing::info!(%url, "opened new rule in the browser"),
+            Err(err) => tracing::warn!(%url, error = %err, "could not open the browser"),
+        }
+    }
+}
+
+/// Applies `resolved`, leaving out forwards whose exact rule is already among
+/// `existing` so unchanged ports don't see a delete/re-add blip. Rules for
+/// idle ports are deleted before the adds under `ApplyOrder::DeletesFirst`,
+/// after them otherwise.
+async fn apply_forwards(
+    ps: &windows::PowerShell,
+    resolved: &Resolved,
+    existing: Option<&[windows::PortproxyRule]>,
+    runtime: &config::RuntimeOverrides,
+) -> Result<(), BridgeError> {
+    let pending: Vec<plan::Forward> = match existing {
+        Some(rules) => resolved
+            .forwards
+            .iter()
+            .filter(|forward| diff::rule_health(forward, rules) != diff::PortHealth::Ok)
+            .cloned()
+            .collect(),
+        None => resolved.forwards.clone(),
+    };
+    let unchanged = resolved.forwards.len() - pending.len();
+    if unchanged > 0 {
+        tracing::debug!(unchanged, "skipping rules that are already correct");
+    }
+    let deletes_first = runtime.apply_order == windows::ApplyOrder::DeletesFirst;
+    if deletes_first {
+        delete_idle_rules(ps, &resolved.idle).await?;
+    }
+    windows::apply_portproxy_rules(
+        ps,
+        &pending,
+        runtime.sync_timeout,
+        runtime.max_concurrency,
+        runtime.apply_order,
+    )
+    .await?;
+    if !deletes_first {
+        delete_idle_rules(ps, &resolved.idle).await?;
+    }
+    Ok(())
+}
+
+async fn delete_idle_rules(
+    ps: &windows::PowerShell,
+    idle: &[plan::Forward],
+) -> Result<(), BridgeError> {
+    for forward in idle {
+        windows::delete_portproxy_rule(
+            ps,
+            forward.family(),
+            forward.listen_address,
+            forward.listen_port,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Builds the full rule set: this distro's ports to `ip`, then each extra target's ports.
+async fn desired_forwards(
+    cfg: &config::PortsConfig,
+    ip: Ipv4Addr,
+    ipv6: Option<Ipv6Addr>,
+) -> Result<Vec<plan::Forward>> {
+    let mut targets = vec![plan::TargetPorts {
+        name: "default",
+        address: ip,
+        address_v6: ipv6,
+        remaps: cfg.effective_remaps(),
+        manual: cfg.effective_manual_ports().into_iter().collect(),
+        detected: cfg.detected_ports().into_iter().collect(),
+        sources: cfg.port_sources(),
+        listen_offset: cfg.connect_port_offset,
+    }];
+
+    for (name, target) in &cfg.targets {
+        let address = network::resolve_target_address(target, cfg.command_timeout())
+            .await
+            .with_context(|| format!("failed resolving address for target {name}"))?;
+        targets.push(plan::TargetPorts {
+            name,
+            address,
+            address_v6: None,
+            remaps: Vec::new(),
+            manual: target.manual_ports.iter().copied().collect(),
+            detected: Vec::new(),
+            sources: target
+                .manual_ports
+                .iter()
+                .map(|&port| (port, BTreeSet::from([ports::PortSource::Manual])))
+                .collect(),
+            listen_offset: cfg.connect_port_offset,
+        });
+    }
+
+    Ok(plan::build(
+        &targets,
+        &resolve_listen_addresses(cfg).await?,
+        &cfg.families(),
+    ))
+}
+
+fn ensure_valid_port(port: u16) -> Result<()> {
+    if port == 0 {
+        anyhow::bail!("port 0 is invalid")
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn global_opts_survive_to_args() {
+        let cli = Cli::parse_from([
+            "wsl-port",
+            "-vv",
+            "--config-dir",
+            "/tmp/cfg",
+            "--config-format",
+            "json",
+            "--interface",
+            "eth1",
+            "--exclude-interface",
+            "docker0",
+            "--sync-timeout",
+            "30s",
+            "--apply-order",
+            "per-port",
+            "--max-concurrency",
+            "2",
+            "--ipv4-only",
+            "--strict",
+            "--output",
+            "/tmp/report.txt",
+            "sync",
+        ]);
+        let args = cli.global.to_args();
+        let again = Cli::parse_from(
+            std::iter::once("wsl-port".into())
+                .chain(args)
+                .chain(std::iter::once("sync".into())),
+        );
+        let (a, b) = (cli.global, again.global);
+        assert_eq!(b.verbose, 2);
+        assert_eq!(b.config_dir, a.config_dir);
+        assert_eq!(b.config_format, Some(config::ConfigFormat::Json));
+        assert_eq!(b.interface.as_deref(), Some("eth1"));
+        assert_eq!(b.exclude_interfaces, ["docker0"]);
+        assert_eq!(b.sync_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(b.apply_order, windows::ApplyOrder::PerPort);
+        assert_eq!(b.max_concurrency, 2);
+        assert!(b.ipv4_only && b.strict);
+        assert_eq!(b.output, None);
+    }
+
+    #[test]
+    fn parse_ttl_reads_units() {
+        assert_eq!(parse_ttl("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_ttl("10m"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_ttl("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_ttl("1d"), Ok(Duration::from_secs(86_400)));
+        assert!(parse_ttl("0s").is_err());
+        assert!(parse_ttl("5w").is_err());
+    }
+
+    #[test]
+    fn parse_since_reads_utc_times() {
+        assert_eq!(parse_since("1970-01-01"), Ok(0));
+        assert_eq!(parse_since("2024-01-01 12:30"), Ok(1_704_112_200));
+        assert_eq!(parse_since("2024-01-01T12:30:05Z"), Ok(1_704_112_205));
+        assert!(parse_since("2024-13-01").is_err());
+        assert!(parse_since("1969-12-31").is_err());
+    }
+
+    #[test]
+    fn parse_since_rejects_overflow() {
+        assert!(parse_since("99999999999999999d").is_err());
+        assert!(parse_since("9223372036854775807-01-01").is_err());
+        assert!(parse_since("99999999999999-01-01").is_err());
+    }
+
+    #[test]
+    fn parse_ttl_rejects_overflow() {
+        assert!(parse_ttl("99999999999999999d").is_err());
+        assert!(parse_ttl("99999999999999999999").is_err());
+    }
+
+    const WSL: Ipv4Addr = Ipv4Addr::new(172, 20, 0, 2);
+    const SHOW: &str = "netsh interface portproxy show all";
+
+    /// Records commands like `RecordingRunner`, but answers `show all` with
+    /// a table of `existing` rules the way netsh prints it.
+    struct StubWindows {
+        existing: Vec<u16>,
+        commands: Mutex<Vec<String>>,
+    }
+
+    impl StubWindows {
+        fn new(existing: &[u16]) -> Arc<Self> {
+            Arc::new(StubWindows {
+                existing: existing.to_vec(),
+                commands: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn commands(&self) -> Vec<String> {
+            self.commands.lock().unwrap().clone()
+        }
+    }
+
+    impl windows::RuleRunner for StubWindows {
+        fn run<'a>(&'a self, command: &'a str) -> windows::RunFuture<'a> {
+            use std::os::unix::process::ExitStatusExt;
+
+            self.commands.lock().unwrap().push(command.to_string());
+            let mut stdout = String::new();
+            if command == SHOW {
+                stdout.push_str("\nListen on ipv4:             Connect to ipv4:\n\n");
+                stdout.push_str("Address         Port        Address         Port\n");
+                stdout.push_str("--------------- ----------  --------------- ----------\n");
+                for port in &self.existing {
+                    stdout.push_str(&format!("0.0.0.0         {port:<11} {WSL:<15} {port}\n"));
+                }
+            }
+            Box::pin(async move {
+                Ok(std::process::Output {
+                    status: std::process::ExitStatus::from_raw(0),
+                    stdout: stdout.into_bytes(),
+                    stderr: Vec::new(),
+                })
+            })
+        }
+    }
+
+    fn forward(port: u16) -> plan::Forward {
+        plan::Forward {
+            listen_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            listen_port: port,
+            connect_address: IpAddr::V4(WSL),
+            connect_port: port,
+            sources: BTreeSet::new(),
+        }
+    }
+
+    fn resolved(forwards: Vec<plan::Forward>, idle: Vec<plan::Forward>) -> Resolved {
+        Resolved {
+            ip: WSL,
+            forwards,
+            idle,
+            held: Vec::new(),
+        }
+    }
+
+    /// Runs `apply_resolved` against Windows already holding `existing` and
+    /// returns every command but the `show`s.
+    async fn sync(
+        existing: &[u16],
+        step: &Resolved,
+        runtime: &config::RuntimeOverrides,
+    ) -> Vec<String> {
+        let stub = StubWindows::new(existing);
+        let ps = windows::PowerShell::with_runner(stub.clone());
+        apply_resolved(&ps, step, runtime).await.unwrap();
+        let mut commands = stub.commands();
+        commands.retain(|command| command != SHOW);
+        commands
+    }
+
+    #[tokio::test]
+    async fn daemon_resync_adds_only_the_new_port() {
+        let last = vec![forward(3000), forward(8080)];
+        let desired = vec![forward(3000), forward(5173), forward(8080)];
+        let (forwards, idle) = plan::delta(&last, &desired);
+        let commands = sync(
+            &[3000, 8080],
+            &resolved(forwards, idle),
+            &Default::default(),
+        )
+        .await;
+
+        let adds: Vec<&String> = commands.iter().filter(|c| c.contains(" add ")).collect();
+        assert_eq!(
+            adds,
+            [
+                "netsh interface portproxy add v4tov4 listenport=5173 listenaddress=0.0.0.0 \
+              connectport=5173 connectaddress=172.20.0.2"
+            ]
+        );
+        assert!(
+            commands.iter().all(|c| c.contains("listenport=5173")),
+            "a still-valid rule was touched: {commands:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn unchanged_port_issues_no_commands() {
+        let step = resolved(vec![forward(3000)], Vec::new());
+        assert!(sync(&[3000], &step, &Default::default()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn force_redoes_unchanged_ports() {
+        let step = resolved(vec![forward(3000)], Vec::new());
+        let runtime = config::RuntimeOverrides {
+            force: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            sync(&[3000], &step, &runtime).await,
+            [
+                "netsh interface portproxy delete v4tov4 listenport=3000 listenaddress=0.0.0.0",
+                "netsh interface portproxy add v4tov4 listenport=3000 listenaddress=0.0.0.0 \
+                 connectport=3000 connectaddress=172.20.0.2",
+            ]
+        );
+    }
+
+    /// Runs `hook` as the pre-sync hook over forwards for 3000 and 8080.
+    async fn pre_sync(hook: &str) -> (Result<()>, Resolved) {
+        let cfg = config::PortsConfig {
+            pre_sync_hook: Some(hook.to_string()),
+            ..Default::default()
+        };
+        let mut step = resolved(vec![forward(3000), forward(8080)], Vec::new());
+        let result = run_pre_sync_hook(&cfg, &mut step).await;
+        (result, step)
+    }
+
+    fn listen_ports(forwards: &[plan::Forward]) -> Vec<u16> {
+        forwards.iter().map(|f| f.listen_port).collect()
+    }
+
+    #[tokio::test]
+    async fn failing_pre_sync_hook_vetoes_the_sync() {
+        let (result, step) = pre_sync("echo deploying >&2; exit 3").await;
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("skipping sync: deploying"), "{err}");
+        assert_eq!(listen_ports(&step.forwards), [3000, 8080]);
+
+        let (result, _) = pre_sync("echo not-a-port").await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn pre_sync_hook_sees_the_ports_and_can_narrow_them() {
+        let (result, step) =
+            pre_sync(r#"test "$WSL_PORTS" = "3000 8080" && test "$WSL_IP" = 172.20.0.2"#).await;
+        result.unwrap();
+        assert_eq!(listen_ports(&step.forwards), [3000, 8080]);
+
+        let (result, step) = pre_sync("echo 8080").await;
+        result.unwrap();
+        assert_eq!(listen_ports(&step.forwards), [8080]);
+        assert_eq!(listen_ports(&step.idle), [3000]);
+    }
 }
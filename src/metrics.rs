@@ -0,0 +1,152 @@
+use crate::config::unix_now;
+use anyhow::{Context, Result};
+use std::fmt::Write as _;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+#[derive(Debug, Default)]
+pub struct DaemonMetrics {
+    pub sync_count: u64,
+    pub sync_failures: u64,
+    pub last_sync_unix: Option<u64>,
+    pub last_sync_ok: bool,
+    pub port_count: usize,
+    pub last_ip: Option<Ipv4Addr>,
+}
+
+pub type SharedMetrics = Arc<Mutex<DaemonMetrics>>;
+
+impl DaemonMetrics {
+    pub fn record_sync(&mut self, ip: Ipv4Addr, port_count: usize, ok: bool) {
+        if ok {
+            self.sync_count += 1;
+            self.last_sync_unix = Some(unix_now());
+            self.port_count = port_count;
+            self.last_ip = Some(ip);
+        } else {
+            self.sync_failures += 1;
+        }
+        self.last_sync_ok = ok;
+    }
+
+    fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+        let syncs = self.sync_count.to_string();
+        let failures = self.sync_failures.to_string();
+        let last_sync = self.last_sync_unix.unwrap_or(0).to_string();
+        let ports = self.port_count.to_string();
+
+        write_metric(
+            &mut out,
+            "wsl_port_syncs_total",
+            "counter",
+            "Completed portproxy syncs.",
+            "",
+            &syncs,
+        );
+        write_metric(
+            &mut out,
+            "wsl_port_sync_failures_total",
+            "counter",
+            "Failed portproxy syncs.",
+            "",
+            &failures,
+        );
+        write_metric(
+            &mut out,
+            "wsl_port_last_sync_timestamp_seconds",
+            "gauge",
+            "Unix time of the last successful sync.",
+            "",
+            &last_sync,
+        );
+        write_metric(
+            &mut out,
+            "wsl_port_forwarded_ports",
+            "gauge",
+            "Ports forwarded by the last successful sync.",
+            "",
+            &ports,
+        );
+        if let Some(ip) = self.last_ip {
+            let labels = format!("{{ip=\"{ip}\"}}");
+            write_metric(
+                &mut out,
+                "wsl_port_wsl_ip_info",
+                "gauge",
+                "WSL IP used by the last successful sync.",
+                &labels,
+                "1",
+            );
+        }
+        out
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, labels: &str, value: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    let _ = writeln!(out, "{name}{labels} {value}");
+}
+
+pub async fn bind(addr: SocketAddr) -> Result<TcpListener> {
+    TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind metrics endpoint on {addr}"))
+}
+
+pub async fn serve(listener: TcpListener, metrics: SharedMetrics) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                tracing::debug!(error = %err, "metrics accept failed");
+                continue;
+            }
+        };
+
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle(stream, &metrics).await {
+                tracing::debug!(error = %err, "metrics request failed");
+            }
+        });
+    }
+}
+
+async fn handle(mut stream: TcpStream, metrics: &SharedMetrics) -> Result<()> {
+    let mut buf = [0u8; 1024];
+    let n = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+
+    let (status, content_type, body) = {
+        let metrics = metrics
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        match path {
+            "/healthz" if metrics.last_sync_ok => ("200 OK", "text/plain", "ok\n".to_string()),
+            "/healthz" => (
+                "503 Service Unavailable",
+                "text/plain",
+                "last sync failed or has not run\n".to_string(),
+            ),
+            "/metrics" => (
+                "200 OK",
+                "text/plain; version=0.0.4",
+                metrics.render_prometheus(),
+            ),
+            _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        }
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
@@ -0,0 +1,386 @@
+use crate::config::TargetConfig;
+use crate::error::BridgeError;
+use crate::process;
+use crate::windows;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+
+const MAX_IP_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Both address families from one `hostname -I` call. The IPv6 address is only
+/// forwarded to by rules whose family connects over IPv6.
+#[derive(Debug, Clone, Copy)]
+pub struct WslAddresses {
+    pub ipv4: Ipv4Addr,
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+/// The IPv4 to forward to: the pinned interface's if one is set, otherwise
+/// the first address from `hostname -I` not belonging to an `exclude`d interface.
+pub async fn get_wsl_ip(
+    timeout: Duration,
+    interface: Option<&str>,
+    exclude: &[&str],
+) -> Result<Ipv4Addr, BridgeError> {
+    Ok(get_wsl_ips(timeout, interface, exclude).await?.ipv4)
+}
+
+/// The IPv4 a boot script left in `path` (first whitespace-separated token),
+/// or `None` if the file is missing or doesn't hold one, so the caller can
+/// fall back to asking `hostname -I`.
+pub fn read_address_file(path: &Path) -> Option<Ipv4Addr> {
+    let raw = match fs::read_to_string(path) {
+        Ok(raw) => raw,
+        // Expected before the boot script has run, so not worth a warning every poll.
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            tracing::debug!(path = %path.display(), "no connect_address_file yet; looking up the WSL IP instead");
+            return None;
+        }
+        Err(err) => {
+            tracing::warn!(path = %path.display(), error = %err, "can't read connect_address_file; looking up the WSL IP instead");
+            return None;
+        }
+    };
+    let ip = first_ipv4(&raw);
+    if ip.is_none() {
+        tracing::warn!(path = %path.display(), "connect_address_file holds no IPv4; looking up the WSL IP instead");
+    }
+    ip
+}
+
+/// Retries `get_wsl_ip` with exponential backoff for up to `grace`, covering
+/// the window at boot where networking isn't up yet and `hostname -I` is empty.
+pub async fn wait_for_wsl_ip(
+    timeout: Duration,
+    interface: Option<&str>,
+    exclude: &[&str],
+    grace: Duration,
+) -> Result<Ipv4Addr, BridgeError> {
+    let deadline = Instant::now() + grace;
+    let mut delay = Duration::from_millis(500);
+
+    loop {
+        match get_wsl_ip(timeout, interface, exclude).await {
+            Ok(ip) => return Ok(ip),
+            Err(err) if err.is_transient() && Instant::now() < deadline => {
+                let wait = delay.min(deadline.saturating_duration_since(Instant::now()));
+                tracing::debug!(error = %err, retry_in_ms = wait.as_millis() as u64, "WSL IP not ready yet");
+                tokio::time::sleep(wait).await;
+                delay = (delay * 2).min(MAX_IP_RETRY_DELAY);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// An explicit `interface` wins; `exclude` only filters `hostname -I`.
+pub async fn get_wsl_ips(
+    timeout: Duration,
+    interface: Option<&str>,
+    exclude: &[&str],
+) -> Result<WslAddresses, BridgeError> {
+    if let Some(name) = interface {
+        return interface_addresses(name, timeout).await;
+    }
+
+    let mut raw = hostname_addresses(timeout).await?;
+    if !exclude.is_empty() {
+        let mut excluded = BTreeSet::new();
+        for name in exclude {
+            // An excluded interface that isn't up (docker0 before Docker starts) has nothing to hide.
+            if let Some(locals) = interface_locals(name, timeout).await? {
+                excluded.extend(locals.into_iter().map(|(_, ip)| ip));
+            }
+        }
+        raw = raw
+            .split_whitespace()
+            .filter(|token| {
+                token
+                    .parse::<IpAddr>()
+                    .map_or(true, |ip| !excluded.contains(&ip))
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+    }
+
+    let ipv4 = first_ipv4(&raw).ok_or_else(|| {
+        let reason = if exclude.is_empty() {
+            "could not parse IPv4 from hostname -I output".to_string()
+        } else {
+            format!(
+                "no IPv4 in hostname -I output outside excluded interfaces {}",
+                exclude.join(", ")
+            )
+        };
+        BridgeError::IpLookupFailed(reason)
+    })?;
+    Ok(WslAddresses {
+        ipv4,
+        ipv6: first_global_ipv6(&raw),
+    })
+}
+
+/// Reads one interface's addresses from `ip -json addr show <name>`.
+async fn interface_addresses(name: &str, timeout: Duration) -> Result<WslAddresses, BridgeError> {
+    let locals = interface_locals(name, timeout)
+        .await?
+        .ok_or_else(|| BridgeError::IpLookupFailed(format!("interface {name} does not exist")))?;
+
+    let ipv4 = locals.iter().find_map(|&(family, ip)| match (family, ip) {
+        (AddressFamily::Inet, IpAddr::V4(ip)) => Some(ip),
+        _ => None,
+    });
+    let ipv6 = locals.iter().find_map(|&(family, ip)| match (family, ip) {
+        (AddressFamily::Inet6, IpAddr::V6(ip))
+            if !ip.is_loopback() && !ip.is_unicast_link_local() =>
+        {
+            Some(ip)
+        }
+        _ => None,
+    });
+
+    let ipv4 = ipv4.ok_or_else(|| {
+        BridgeError::IpLookupFailed(format!("interface {name} has no IPv4 address"))
+    })?;
+    Ok(WslAddresses { ipv4, ipv6 })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AddressFamily {
+    Inet,
+    Inet6,
+}
+
+/// Every address on `name` per `ip -json addr show`, or `None` if there is no such interface.
+async fn interface_locals(
+    name: &str,
+    timeout: Duration,
+) -> Result<Option<Vec<(AddressFamily, IpAddr)>>, BridgeError> {
+    let mut command = Command::new("ip");
+    command.args(["-json", "addr", "show", "dev", name]);
+    let output = process::output_with_timeout(&mut command, timeout).await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.contains("does not exist") {
+            return Ok(None);
+        }
+        return Err(BridgeError::IpLookupFailed(format!(
+            "ip addr show {name} failed with {}: {}",
+            output.status,
+            stderr.trim()
+        )));
+    }
+
+    let parsed: serde_json::Value = serde_json::from_slice(&output.stdout).map_err(|err| {
+        BridgeError::IpLookupFailed(format!("invalid ip -json output for {name}: {err}"))
+    })?;
+    let locals = parsed
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|link| link.get("addr_info")?.as_array())
+        .flatten()
+        .filter_map(|info| {
+            let family = match info.get("family")?.as_str()? {
+                "inet" => AddressFamily::Inet,
+                "inet6" => AddressFamily::Inet6,
+                _ => return None,
+            };
+            let local = info.get("local")?.as_str()?.parse().ok()?;
+            Some((family, local))
+        })
+        .collect();
+    Ok(Some(locals))
+}
+
+fn first_global_ipv6(output: &str) -> Option<Ipv6Addr> {
+    output
+        .split_whitespace()
+        .filter_map(|token| token.parse::<Ipv6Addr>().ok())
+        .find(|ip| !ip.is_loopback() && !ip.is_unicast_link_local())
+}
+
+async fn hostname_addresses(timeout: Duration) -> Result<String, BridgeError> {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg("hostname -I");
+    let output = process::output_with_timeout(&mut command, timeout).await?;
+
+    if !output.status.success() {
+        return Err(BridgeError::IpLookupFailed(format!(
+            "hostname -I failed with {}",
+            output.status
+        )));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Resolves a target's connect address, asking the named distro via `wsl.exe` if needed.
+pub async fn resolve_target_address(
+    target: &TargetConfig,
+    timeout: Duration,
+) -> Result<Ipv4Addr, BridgeError> {
+    if let Some(address) = target.address {
+        return Ok(address);
+    }
+
+    let Some(distro) = target.distro.as_deref() else {
+        return Err(BridgeError::IpLookupFailed(
+            "target has neither `address` nor `distro` configured".to_string(),
+        ));
+    };
+
+    let mut command = Command::new("wsl.exe");
+    command.args(["-d", distro, "--", "hostname", "-I"]);
+    let output = process::output_with_timeout(&mut command, timeout).await?;
+
+    if !output.status.success() {
+        return Err(BridgeError::IpLookupFailed(format!(
+            "wsl.exe -d {distro} hostname -I failed with {}",
+            output.status
+        )));
+    }
+
+    first_ipv4(&String::from_utf8_lossy(&output.stdout)).ok_or_else(|| {
+        BridgeError::IpLookupFailed(format!("could not parse IPv4 for distro {distro}"))
+    })
+}
+
+fn first_ipv4(output: &str) -> Option<Ipv4Addr> {
+    output
+        .split_whitespace()
+        .find_map(|token| token.parse::<Ipv4Addr>().ok())
+}
+
+/// WSL2's `networkingMode`, which decides where Windows reaches WSL services.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetworkingMode {
+    /// The default: WSL sits behind a NAT with its own `hostname -I` address.
+    Nat,
+    /// Windows and WSL share interfaces, including loopback.
+    Mirrored,
+    /// Anything else (`virtioproxy`, `none`, ...), as written.
+    Other(String),
+}
+
+impl NetworkingMode {
+    fn parse(raw: &str) -> Self {
+        match raw.trim().to_ascii_lowercase().as_str() {
+            "nat" | "" => NetworkingMode::Nat,
+            "mirrored" => NetworkingMode::Mirrored,
+            other => NetworkingMode::Other(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for NetworkingMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkingMode::Nat => f.pad("nat"),
+            NetworkingMode::Mirrored => f.pad("mirrored"),
+            NetworkingMode::Other(mode) => f.pad(mode),
+        }
+    }
+}
+
+/// The networking mode and where it was read from.
+#[derive(Debug, Clone)]
+pub struct Networking {
+    pub mode: NetworkingMode,
+    pub source: String,
+}
+
+/// Resolved once per process: changing the mode takes a `wsl --shutdown`,
+/// which ends this process too.
+static NETWORKING: OnceLock<Option<Networking>> = OnceLock::new();
+
+/// Asks `wslinfo --networking-mode` (newer WSL), then reads `networkingMode`
+/// from `[wsl2]` in the Windows-side `%USERPROFILE%\.wslconfig`; a missing
+/// file or key means NAT. `None` when neither can be consulted.
+pub async fn networking_mode(timeout: Duration) -> Option<Networking> {
+    if let Some(cached) = NETWORKING.get() {
+        return cached.clone();
+    }
+    let detected = detect_networking_mode(timeout).await;
+    match &detected {
+        Some(networking) => {
+            tracing::debug!(mode = %networking.mode, source = %networking.source, "detected WSL networking mode")
+        }
+        None => tracing::debug!("could not determine the WSL networking mode"),
+    }
+    NETWORKING.get_or_init(|| detected).clone()
+}
+
+async fn detect_networking_mode(timeout: Duration) -> Option<Networking> {
+    let mut command = Command::new("wslinfo");
+    command.arg("--networking-mode");
+    match process::output_with_timeout(&mut command, timeout).await {
+        Ok(output) if output.status.success() => {
+            return Some(Networking {
+                mode: NetworkingMode::parse(&String::from_utf8_lossy(&output.stdout)),
+                source: "wslinfo".to_string(),
+            });
+        }
+        Ok(output) => tracing::debug!(status = %output.status, "wslinfo --networking-mode failed"),
+        Err(err) => tracing::debug!(error = %err, "wslinfo unavailable"),
+    }
+
+    let path = windows::user_profile_dir(timeout).await?.join(".wslconfig");
+    let mode = match fs::read_to_string(&path) {
+        Ok(contents) => windows::ini_value(&contents, "wsl2", "networkingMode")
+            .map_or(NetworkingMode::Nat, |raw| NetworkingMode::parse(&raw)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => NetworkingMode::Nat,
+        Err(err) => {
+            tracing::debug!(error = %err, path = %path.display(), "could not read .wslconfig");
+            return None;
+        }
+    };
+    Some(Networking {
+        mode,
+        source: path.display().to_string(),
+    })
+}
+
+/// Whether `address` accepts a TCP connection within `timeout`.
+pub async fn accepts_tcp(address: SocketAddr, timeout: Duration) -> bool {
+    matches!(
+        tokio::time::timeout(timeout, tokio::net::TcpStream::connect(address)).await,
+        Ok(Ok(_))
+    )
+}
+
+/// TCP ports with a socket in LISTEN state, read from `/proc/net/tcp` and `/proc/net/tcp6`.
+pub fn listening_ports() -> io::Result<BTreeSet<u16>> {
+    let mut ports = BTreeSet::new();
+    for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        match fs::read_to_string(table) {
+            Ok(raw) => ports.extend(parse_proc_net_listeners(&raw)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(ports)
+}
+
+fn parse_proc_net_listeners(raw: &str) -> impl Iterator<Item = u16> + '_ {
+    const TCP_LISTEN: &str = "0A";
+
+    raw.lines().skip(1).filter_map(|line| {
+        let mut fields = line.split_whitespace();
+        let local = fields.nth(1)?;
+        let state = fields.nth(1)?;
+        if state != TCP_LISTEN {
+            return None;
+        }
+        let (_, port_hex) = local.rsplit_once(':')?;
+        u16::from_str_radix(port_hex, 16).ok().filter(|&p| p != 0)
+    })
+}
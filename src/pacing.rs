@@ -0,0 +1,67 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+
+/// How long the daemon must go without a change before polls start stretching.
+const QUIET_AFTER: Duration = Duration::from_secs(60);
+
+/// Spreads and stretches the daemon's poll intervals. Every wait gets up to
+/// ±`jitter_pct` percent of noise so copies started together drift apart.
+/// With a `max`, each further `QUIET_AFTER` without a change doubles the base
+/// interval, capped at `max`; any change snaps it back.
+#[derive(Debug)]
+pub struct Pacing {
+    jitter_pct: u8,
+    max: Option<Duration>,
+    quiet_since: Instant,
+    state: u64,
+}
+
+impl Pacing {
+    pub fn new(jitter_pct: u8, max: Option<Duration>) -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Pacing {
+            jitter_pct,
+            max,
+            quiet_since: Instant::now(),
+            // xorshift never leaves zero, so force a bit on.
+            state: (nanos ^ u64::from(std::process::id()).rotate_left(32)) | 1,
+        }
+    }
+
+    /// Records a sync or IP change, resetting the interval to its base.
+    pub fn changed(&mut self, now: Instant) {
+        self.quiet_since = now;
+    }
+
+    /// The wait before the next poll whose configured interval is `base`.
+    pub fn interval(&mut self, base: Duration, now: Instant) -> Duration {
+        let mut interval = base;
+        if let Some(max) = self.max.filter(|&max| max > base) {
+            let quiet = now.duration_since(self.quiet_since);
+            let doublings = (quiet.as_secs() / QUIET_AFTER.as_secs()).min(16) as u32;
+            interval = base.saturating_mul(1 << doublings).min(max);
+        }
+        self.jitter(interval)
+    }
+
+    fn jitter(&mut self, interval: Duration) -> Duration {
+        if self.jitter_pct == 0 {
+            return interval;
+        }
+        // Uniform in [-pct, +pct] percent, in thousandths of the interval.
+        let span = u64::from(self.jitter_pct) * 20 + 1;
+        let offset = (self.next_random() % span) as i64 - i64::from(self.jitter_pct) * 10;
+        let millis = interval.as_millis() as i64;
+        Duration::from_millis((millis + millis * offset / 1000).max(1) as u64)
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+}
@@ -0,0 +1,388 @@
+use crate::ports::{PortRemap, PortSource, RuleFamily};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Forward {
+    pub listen_address: IpAddr,
+    pub listen_port: u16,
+    pub connect_address: IpAddr,
+    pub connect_port: u16,
+    /// Which config entries or detectors asked for this listen port.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub sources: BTreeSet<PortSource>,
+}
+
+impl Forward {
+    /// Same netsh rule, ignoring provenance.
+    pub fn same_rule(&self, other: &Forward) -> bool {
+        self.listen_address == other.listen_address
+            && self.listen_port == other.listen_port
+            && self.connect_address == other.connect_address
+            && self.connect_port == other.connect_port
+    }
+
+    /// `address:port`, with IPv6 addresses bracketed.
+    pub fn listen(&self) -> SocketAddr {
+        SocketAddr::new(self.listen_address, self.listen_port)
+    }
+
+    pub fn connect(&self) -> SocketAddr {
+        SocketAddr::new(self.connect_address, self.connect_port)
+    }
+
+    /// The netsh family implied by the two addresses.
+    pub fn family(&self) -> RuleFamily {
+        RuleFamily::new(
+            self.listen_address.is_ipv6(),
+            self.connect_address.is_ipv6(),
+        )
+    }
+
+    pub fn describe_sources(&self) -> String {
+        let names: Vec<String> = self.sources.iter().map(ToString::to_string).collect();
+        names.join(", ")
+    }
+}
+
+/// What moving from the `applied` forwards to `desired` takes: forwards that
+/// are new or now point elsewhere, and applied ones whose listen slot is gone.
+pub fn delta(applied: &[Forward], desired: &[Forward]) -> (Vec<Forward>, Vec<Forward>) {
+    let changed = desired
+        .iter()
+        .filter(|forward| !applied.iter().any(|old| old.same_rule(forward)))
+        .cloned()
+        .collect();
+    let dropped = applied
+        .iter()
+        .filter(|old| {
+            !desired.iter().any(|forward| {
+                forward.listen_address == old.listen_address
+                    && forward.listen_port == old.listen_port
+            })
+        })
+        .cloned()
+        .collect();
+    (changed, dropped)
+}
+
+/// Keeps detected forwards that just vanished for a few more detection polls,
+/// so a pm2 app that drops out of `jlist` while it restarts keeps its rule.
+#[derive(Debug)]
+pub struct RemovalGrace {
+    polls: u32,
+    /// Consecutive polls each held listen slot has been missing.
+    absent: BTreeMap<(IpAddr, u16), u32>,
+}
+
+impl RemovalGrace {
+    /// `polls` is how many consecutive polls a port must be missing before
+    /// its rule goes; `1` removes it on the first miss.
+    pub fn new(polls: u32) -> Self {
+        RemovalGrace {
+            polls: polls.max(1),
+            absent: BTreeMap::new(),
+        }
+    }
+
+    /// Counts one detection poll and returns the `applied` forwards that are
+    /// missing from `desired` but still inside their grace window. Ports added
+    /// by hand are never held, so `remove` takes effect right away.
+    pub fn hold(&mut self, applied: &[Forward], desired: &[Forward]) -> Vec<Forward> {
+        let mut held = Vec::new();
+        let mut absent = BTreeMap::new();
+        for old in applied {
+            let slot = (old.listen_address, old.listen_port);
+            let detected_only =
+                !old.sources.is_empty() && old.sources.iter().all(|source| source.is_detected());
+            let present = desired.iter().any(|forward| {
+                forward.listen_address == old.listen_address
+                    && forward.listen_port == old.listen_port
+            });
+            if present || !detected_only {
+                continue;
+            }
+            let polls = self.absent.get(&slot).copied().unwrap_or(0) + 1;
+            if polls < self.polls {
+                absent.insert(slot, polls);
+                held.push(old.clone());
+            }
+        }
+        self.absent = absent;
+        held
+    }
+}
+
+/// Which netsh family each listen port's rule uses.
+#[derive(Debug, Clone, Default)]
+pub struct Families {
+    pub by_port: BTreeMap<u16, RuleFamily>,
+    /// For ports not in `by_port`.
+    pub default: RuleFamily,
+}
+
+impl Families {
+    pub fn of(&self, listen_port: u16) -> RuleFamily {
+        self.by_port
+            .get(&listen_port)
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+pub struct TargetPorts<'a> {
+    pub name: &'a str,
+    pub address: Ipv4Addr,
+    /// Needed by rules that connect over IPv6; those are skipped without it.
+    pub address_v6: Option<Ipv6Addr>,
+    pub remaps: Vec<PortRemap>,
+    pub manual: Vec<u16>,
+    pub detected: Vec<u16>,
+    pub sources: BTreeMap<u16, BTreeSet<PortSource>>,
+    /// Added to manual and detected ports to get their Windows listen port;
+    /// remaps already name both ends and ignore it.
+    pub listen_offset: i32,
+}
+
+/// An entry `build` dropped because a higher-precedence one already claimed
+/// its listen port with a different target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    pub listen_port: u16,
+    pub kept: SocketAddr,
+    pub dropped: SocketAddr,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: kept {}, dropped {}",
+            self.listen_port, self.kept, self.dropped
+        )
+    }
+}
+
+/// Merges every target's ports into one rule set keyed by listen port, then
+/// emits one rule per listen address for each claimed port. Duplicates
+/// collapse, port 0 is dropped, and all conflicts are logged in one warning.
+///
+/// Rules that listen on IPv6 listen on `::` only, whatever `listen_addresses` says.
+pub fn build(
+    targets: &[TargetPorts],
+    listen_addresses: &[Ipv4Addr],
+    families: &Families,
+) -> Vec<Forward> {
+    let (claimed, conflicts) = claim(targets, families);
+    if !conflicts.is_empty() {
+        let conflicts: Vec<String> = conflicts.iter().map(ToString::to_string).collect();
+        tracing::warn!(
+            conflicts = %conflicts.join("; "),
+            "resolved conflicting rules for listen ports; kept the higher-precedence entry for each"
+        );
+    }
+
+    claimed
+        .into_values()
+        .flat_map(|forward| {
+            let listen: Vec<IpAddr> = if families.of(forward.listen_port).listens_v6() {
+                vec![IpAddr::V6(Ipv6Addr::UNSPECIFIED)]
+            } else {
+                listen_addresses.iter().copied().map(IpAddr::V4).collect()
+            };
+            listen.into_iter().map(move |listen_address| Forward {
+                listen_address,
+                ..forward.clone()
+            })
+        })
+        .collect()
+}
+
+/// The one forward each listen port ends up with, sorted by port, plus the
+/// entries that lost a conflict.
+///
+/// Precedence when two entries want the same listen port (after
+/// `listen_offset` is applied): explicit remaps beat manual ports, which beat
+/// detected ones; within the same kind earlier targets win (this distro first,
+/// then named targets in order), and within one target the earlier entry wins.
+/// Entries that agree with the winner are plain duplicates, not conflicts.
+fn claim(targets: &[TargetPorts], families: &Families) -> (BTreeMap<u16, Forward>, Vec<Conflict>) {
+    let mut claimed: BTreeMap<u16, Forward> = BTreeMap::new();
+    let mut conflicts = Vec::new();
+
+    // (listen port, connect port, port the sources are keyed by)
+    let offset = |t: &TargetPorts, ports: &[u16]| -> Vec<(u16, u16, u16)> {
+        ports
+            .iter()
+            .filter(|&&p| {
+                if p == 0 {
+                    tracing::warn!(target = t.name, "port 0 is invalid; skipping");
+                }
+                p != 0
+            })
+            .filter_map(|&p| match offset_port(p, t.listen_offset) {
+                Some(listen) => Some((listen, p, p)),
+                None => {
+                    tracing::warn!(
+                        port = p,
+                        offset = t.listen_offset,
+                        target = t.name,
+                        "connect_port_offset pushes port out of range; skipping"
+                    );
+                    None
+                }
+            })
+            .collect()
+    };
+    let remaps = targets.iter().map(|t| {
+        let pairs = t
+            .remaps
+            .iter()
+            .map(|r| (r.listen, r.connect, r.listen))
+            .collect();
+        (t, pairs)
+    });
+    let manual = targets.iter().map(|t| (t, offset(t, &t.manual)));
+    let detected = targets.iter().map(|t| (t, offset(t, &t.detected)));
+
+    for (target, pairs) in remaps.chain(manual).chain(detected) {
+        for (port, connect_port, key) in pairs {
+            let family = families.of(port);
+            let connect_address = match (family.connects_v6(), target.address_v6) {
+                (false, _) => IpAddr::V4(target.address),
+                (true, Some(address_v6)) => IpAddr::V6(address_v6),
+                (true, None) => {
+                    tracing::warn!(
+                        port,
+                        %family,
+                        target = target.name,
+                        "rule connects over IPv6 but the target has no IPv6 address; skipping"
+                    );
+                    continue;
+                }
+            };
+            let forward = Forward {
+                listen_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                listen_port: port,
+                connect_address,
+                connect_port,
+                sources: target.sources.get(&key).cloned().unwrap_or_default(),
+            };
+
+            match claimed.get(&port) {
+                Some(existing) if !existing.same_rule(&forward) => conflicts.push(Conflict {
+                    listen_port: port,
+                    kept: existing.connect(),
+                    dropped: forward.connect(),
+                }),
+                Some(_) => {}
+                None => {
+                    claimed.insert(port, forward);
+                }
+            }
+        }
+    }
+    (claimed, conflicts)
+}
+
+/// `port + offset`, or `None` if that leaves 1..=65535.
+pub fn offset_port(port: u16, offset: i32) -> Option<u16> {
+    u16::try_from(i32::from(port) + offset)
+        .ok()
+        .filter(|&p| p != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WSL: Ipv4Addr = Ipv4Addr::new(172, 20, 0, 2);
+
+    fn target<'a>(remaps: &[&str], manual: &[u16], detected: &[u16]) -> TargetPorts<'a> {
+        TargetPorts {
+            name: "default",
+            address: WSL,
+            address_v6: None,
+            remaps: remaps.iter().map(|remap| remap.parse().unwrap()).collect(),
+            manual: manual.to_vec(),
+            detected: detected.to_vec(),
+            sources: BTreeMap::new(),
+            listen_offset: 0,
+        }
+    }
+
+    fn pairs(forwards: &[Forward]) -> Vec<(u16, u16)> {
+        forwards
+            .iter()
+            .map(|forward| (forward.listen_port, forward.connect_port))
+            .collect()
+    }
+
+    #[test]
+    fn conflicting_remaps_resolve_to_the_first_and_beat_manual_ports() {
+        let targets = [target(&["8080:3000", "8080:4000"], &[8080, 3000], &[3000])];
+        let (claimed, conflicts) = claim(&targets, &Families::default());
+        let forwards: Vec<Forward> = claimed.into_values().collect();
+        assert_eq!(pairs(&forwards), [(3000, 3000), (8080, 3000)]);
+        // The second remap and the manual 8080 lose; the detected 3000 is a
+        // plain duplicate of the manual one.
+        let dropped: Vec<(u16, u16)> = conflicts
+            .iter()
+            .map(|conflict| (conflict.listen_port, conflict.dropped.port()))
+            .collect();
+        assert_eq!(dropped, [(8080, 4000), (8080, 8080)]);
+        assert!(conflicts
+            .iter()
+            .all(|conflict| conflict.kept == SocketAddr::new(IpAddr::V4(WSL), 3000)));
+    }
+
+    #[test]
+    fn manual_ports_beat_detected_ones_then_earlier_targets_win() {
+        let db = Ipv4Addr::new(172, 20, 0, 3);
+        let mut second = target(&[], &[5432, 6379], &[]);
+        second.name = "db";
+        second.address = db;
+        let targets = [target(&[], &[6379], &[5432]), second];
+        let (claimed, conflicts) = claim(&targets, &Families::default());
+        let connect: Vec<(u16, IpAddr)> = claimed
+            .values()
+            .map(|forward| (forward.listen_port, forward.connect_address))
+            .collect();
+        // db's manual 5432 outranks this distro's detected one; both want
+        // 6379 by hand, so this distro, listed first, keeps it.
+        assert_eq!(connect, [(5432, IpAddr::V4(db)), (6379, IpAddr::V4(WSL))]);
+        let dropped: Vec<SocketAddr> = conflicts.iter().map(|conflict| conflict.dropped).collect();
+        assert_eq!(
+            dropped,
+            [
+                SocketAddr::new(IpAddr::V4(db), 6379),
+                SocketAddr::new(IpAddr::V4(WSL), 5432),
+            ]
+        );
+    }
+
+    #[test]
+    fn build_dedupes_sorts_and_drops_port_zero() {
+        let targets = [target(&[], &[9000, 0, 3000, 3000], &[5173, 3000])];
+        let forwards = build(&targets, &[Ipv4Addr::UNSPECIFIED], &Families::default());
+        assert_eq!(pairs(&forwards), [(3000, 3000), (5173, 5173), (9000, 9000)]);
+    }
+
+    #[test]
+    fn listen_offset_moves_manual_but_not_remapped_ports() {
+        let mut target = target(&["8080:3000"], &[3000], &[]);
+        target.listen_offset = 10_000;
+        let forwards = build(&[target], &[Ipv4Addr::UNSPECIFIED], &Families::default());
+        assert_eq!(pairs(&forwards), [(8080, 3000), (13000, 3000)]);
+    }
+
+    #[test]
+    fn build_emits_one_rule_per_listen_address() {
+        let listen = [Ipv4Addr::UNSPECIFIED, Ipv4Addr::new(192, 168, 1, 10)];
+        let forwards = build(&[target(&[], &[3000], &[])], &listen, &Families::default());
+        let addresses: Vec<IpAddr> = forwards.iter().map(|f| f.listen_address).collect();
+        assert_eq!(addresses, listen.map(IpAddr::V4));
+    }
+}
@@ -0,0 +1,508 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
+
+/// Runs shorter than this stay as individual ports when serialized.
+const MIN_RANGE_LEN: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn len(&self) -> usize {
+        usize::from(self.end - self.start) + 1
+    }
+
+    pub fn ports(&self) -> impl Iterator<Item = u16> {
+        self.start..=self.end
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    /// Accepts `"8000-8010"` or a single port like `"8000"`.
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let parse = |s: &str| {
+            s.trim()
+                .parse::<u16>()
+                .ok()
+                .filter(|&p| p != 0)
+                .ok_or_else(|| format!("invalid port `{}` in range `{input}`", s.trim()))
+        };
+
+        let (start, end) = match input.split_once('-') {
+            Some((start, end)) => (parse(start)?, parse(end)?),
+            None => {
+                let port = parse(input)?;
+                (port, port)
+            }
+        };
+
+        if start > end {
+            return Err(format!("range `{input}` starts after it ends"));
+        }
+        Ok(PortRange { start, end })
+    }
+}
+
+impl Serialize for PortRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        raw.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Ports a detector may report, written as a comma-separated list of ports
+/// and ranges like `"3000-3999,8080"`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortFilter(Vec<PortRange>);
+
+impl PortFilter {
+    pub fn allows(&self, port: u16) -> bool {
+        self.0.iter().any(|range| range.contains(port))
+    }
+}
+
+impl fmt::Display for PortFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ranges: Vec<String> = self.0.iter().map(ToString::to_string).collect();
+        f.write_str(&ranges.join(","))
+    }
+}
+
+impl FromStr for PortFilter {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let ranges = input
+            .split(',')
+            .filter(|part| !part.trim().is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<PortRange>, _>>()?;
+        if ranges.is_empty() {
+            return Err(format!("port filter `{input}` has no ports"));
+        }
+        Ok(PortFilter(ranges))
+    }
+}
+
+impl Serialize for PortFilter {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PortFilter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        raw.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Well-known ports of databases, brokers, and other services a browser can't talk to.
+const NON_HTTP_PORTS: [u16; 12] = [
+    21, 22, 25, 53, 1433, 3306, 5432, 5672, 6379, 9092, 11211, 27017,
+];
+
+/// Whether a port plausibly serves HTTP, i.e. isn't a known non-HTTP service port.
+pub fn is_http_like(port: u16) -> bool {
+    !NON_HTTP_PORTS.contains(&port)
+}
+
+/// Where a forwarded port came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PortSource {
+    Remap,
+    Manual,
+    Profile,
+    Temp,
+    Pm2,
+    Caddy,
+    Listener,
+    Dotenv,
+    Traefik,
+    #[serde(rename = "devserver")]
+    DevServer,
+    Docker,
+    Compose,
+    Remote,
+}
+
+impl PortSource {
+    /// Whether a detector reported the port, as opposed to the user adding it.
+    pub fn is_detected(self) -> bool {
+        matches!(
+            self,
+            PortSource::Pm2
+                | PortSource::Caddy
+                | PortSource::Listener
+                | PortSource::Dotenv
+                | PortSource::Traefik
+                | PortSource::DevServer
+                | PortSource::Docker
+                | PortSource::Compose
+                | PortSource::Remote
+        )
+    }
+}
+
+impl fmt::Display for PortSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PortSource::Remap => "remap",
+            PortSource::Manual => "manual",
+            PortSource::Profile => "profile",
+            PortSource::Temp => "temp",
+            PortSource::Pm2 => "pm2",
+            PortSource::Caddy => "caddy",
+            PortSource::Listener => "listener",
+            PortSource::Dotenv => "dotenv",
+            PortSource::Traefik => "traefik",
+            PortSource::DevServer => "devserver",
+            PortSource::Docker => "docker",
+            PortSource::Compose => "compose",
+            PortSource::Remote => "remote",
+        })
+    }
+}
+
+/// A netsh portproxy rule family: the IP version Windows listens on, then the
+/// one it connects to. Written as netsh spells it, e.g. `"v6tov4"`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RuleFamily {
+    #[default]
+    V4ToV4,
+    V4ToV6,
+    V6ToV4,
+    V6ToV6,
+}
+
+impl RuleFamily {
+    pub fn new(listen_v6: bool, connect_v6: bool) -> Self {
+        match (listen_v6, connect_v6) {
+            (false, false) => RuleFamily::V4ToV4,
+            (false, true) => RuleFamily::V4ToV6,
+            (true, false) => RuleFamily::V6ToV4,
+            (true, true) => RuleFamily::V6ToV6,
+        }
+    }
+
+    pub fn listens_v6(self) -> bool {
+        matches!(self, RuleFamily::V6ToV4 | RuleFamily::V6ToV6)
+    }
+
+    pub fn connects_v6(self) -> bool {
+        matches!(self, RuleFamily::V4ToV6 | RuleFamily::V6ToV6)
+    }
+}
+
+impl fmt::Display for RuleFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RuleFamily::V4ToV4 => "v4tov4",
+            RuleFamily::V4ToV6 => "v4tov6",
+            RuleFamily::V6ToV4 => "v6tov4",
+            RuleFamily::V6ToV6 => "v6tov6",
+        })
+    }
+}
+
+impl FromStr for RuleFamily {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input.trim().to_ascii_lowercase().as_str() {
+            "v4tov4" => Ok(RuleFamily::V4ToV4),
+            "v4tov6" => Ok(RuleFamily::V4ToV6),
+            "v6tov4" => Ok(RuleFamily::V6ToV4),
+            "v6tov6" => Ok(RuleFamily::V6ToV6),
+            _ => Err(format!(
+                "unknown rule family `{input}`; expected v4tov4, v4tov6, v6tov4, or v6tov6"
+            )),
+        }
+    }
+}
+
+impl Serialize for RuleFamily {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for RuleFamily {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        raw.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A listen port forwarded to a different connect port in WSL, written `"8080:3000"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PortRemap {
+    pub listen: u16,
+    pub connect: u16,
+}
+
+impl fmt::Display for PortRemap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.listen, self.connect)
+    }
+}
+
+impl FromStr for PortRemap {
+    type Err = String;
+
+    /// Accepts `"8080:3000"`, or a bare `"8080"` meaning a 1:1 forward. A connect
+    /// port of 0 (`"8080:0"`) also means "same as listen" and is resolved here, so
+    /// a `PortRemap` never holds 0; a listen port of 0 is rejected.
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let parse = |s: &str| {
+            s.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("invalid port `{}` in `{input}`", s.trim()))
+        };
+        let listen_port = |s: &str| {
+            match parse(s)? {
+            0 => Err(format!(
+                "listen port can't be 0 in `{input}` (0 only means \"same as listen\" as a connect port)"
+            )),
+            port => Ok(port),
+        }
+        };
+
+        match input.split_once(':') {
+            Some((listen, connect)) => {
+                let listen = listen_port(listen)?;
+                let connect = match parse(connect)? {
+                    0 => listen,
+                    port => port,
+                };
+                Ok(PortRemap { listen, connect })
+            }
+            None => {
+                let port = listen_port(input)?;
+                Ok(PortRemap {
+                    listen: port,
+                    connect: port,
+                })
+            }
+        }
+    }
+}
+
+impl Serialize for PortRemap {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRemap {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        raw.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// What `add` takes: a port or `listen:connect` remap, or a range like `"8000-8050"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortSpec {
+    Remap(PortRemap),
+    Range(PortRange),
+}
+
+impl FromStr for PortSpec {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        if input.contains('-') {
+            input.parse().map(PortSpec::Range)
+        } else {
+            input.parse().map(PortSpec::Remap)
+        }
+    }
+}
+
+/// Collapses a sorted port set into maximal runs of consecutive ports.
+pub fn collapse(ports: &BTreeSet<u16>) -> Vec<PortRange> {
+    let mut ranges: Vec<PortRange> = Vec::new();
+    for &port in ports {
+        match ranges.last_mut() {
+            Some(last) if last.end.checked_add(1) == Some(port) => last.end = port,
+            _ => ranges.push(PortRange {
+                start: port,
+                end: port,
+            }),
+        }
+    }
+    ranges
+}
+
+/// Formats a port set like `{8000-8020, 9000}`: runs of 3+ as ranges unless
+/// `expand`, which lists every port.
+pub fn display_set(ports: &BTreeSet<u16>, expand: bool) -> String {
+    let items: Vec<String> = if expand {
+        ports.iter().map(ToString::to_string).collect()
+    } else {
+        collapse(ports)
+            .into_iter()
+            .flat_map(|range| {
+                if range.len() >= MIN_RANGE_LEN {
+                    vec![range.to_string()]
+                } else {
+                    range.ports().map(|port| port.to_string()).collect()
+                }
+            })
+            .collect()
+    };
+    format!("{{{}}}", items.join(", "))
+}
+
+/// The manual port set, stored on disk as `manual_ports` plus compact `manual_ranges`.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct ManualPorts(BTreeSet<u16>);
+
+impl Deref for ManualPorts {
+    type Target = BTreeSet<u16>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for ManualPorts {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl fmt::Debug for ManualPorts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ManualPortsRepr {
+    #[serde(default)]
+    manual_ports: Vec<u16>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    manual_ranges: Vec<String>,
+}
+
+impl Serialize for ManualPorts {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut repr = ManualPortsRepr {
+            manual_ports: Vec::new(),
+            manual_ranges: Vec::new(),
+        };
+        for range in collapse(&self.0) {
+            if range.len() >= MIN_RANGE_LEN {
+                repr.manual_ranges.push(range.to_string());
+            } else {
+                repr.manual_ports.extend(range.ports());
+            }
+        }
+        repr.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ManualPorts {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = ManualPortsRepr::deserialize(deserializer)?;
+        let mut ports: BTreeSet<u16> = repr.manual_ports.into_iter().collect();
+        for raw in &repr.manual_ranges {
+            let range: PortRange = raw.parse().map_err(serde::de::Error::custom)?;
+            ports.extend(range.ports());
+        }
+        Ok(ManualPorts(ports))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct Doc {
+        #[serde(flatten)]
+        manual: ManualPorts,
+    }
+
+    fn set(ports: impl IntoIterator<Item = u16>) -> BTreeSet<u16> {
+        ports.into_iter().collect()
+    }
+
+    #[test]
+    fn collapse_merges_runs_and_expands_back() {
+        let ports = set([22, 8000, 8001, 8002, 8003, 9000, 9001, u16::MAX]);
+        let ranges = collapse(&ports);
+        let shown: Vec<String> = ranges.iter().map(ToString::to_string).collect();
+        assert_eq!(shown, ["22", "8000-8003", "9000-9001", "65535"]);
+        assert_eq!(set(ranges.iter().flat_map(PortRange::ports)), ports);
+    }
+
+    #[test]
+    fn display_set_lists_short_runs_port_by_port() {
+        let ports = set([3000, 3001, 8000, 8001, 8002]);
+        assert_eq!(display_set(&ports, false), "{3000, 3001, 8000-8002}");
+        assert_eq!(display_set(&ports, true), "{3000, 3001, 8000, 8001, 8002}");
+    }
+
+    #[test]
+    fn manual_ranges_stay_compact_across_load_and_save() {
+        let raw =
+            "manual_ports = [3000, 8005, 8006]\nmanual_ranges = [\"8000-8004\", \"9000-9500\"]\n";
+        let doc: Doc = toml::from_str(raw).unwrap();
+        assert_eq!(doc.manual.len(), 1 + 7 + 501);
+
+        let saved = toml::to_string(&doc).unwrap();
+        assert_eq!(
+            saved,
+            "manual_ports = [3000]\nmanual_ranges = [\"8000-8006\", \"9000-9500\"]\n"
+        );
+        let reloaded: Doc = toml::from_str(&saved).unwrap();
+        assert!(reloaded.manual == doc.manual);
+        assert_eq!(toml::to_string(&reloaded).unwrap(), saved);
+    }
+
+    #[test]
+    fn port_range_rejects_backwards_and_zero() {
+        assert_eq!(
+            "8000-8010".parse::<PortRange>(),
+            Ok(PortRange {
+                start: 8000,
+                end: 8010
+            })
+        );
+        assert!("8010-8000".parse::<PortRange>().is_err());
+        assert!("0-10".parse::<PortRange>().is_err());
+        assert!("8000-70000".parse::<PortRange>().is_err());
+    }
+}
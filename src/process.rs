@@ -0,0 +1,127 @@
+use crate::error::BridgeError;
+use std::process::{ExitStatus, Output};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+static SHOW_COMMANDS: AtomicBool = AtomicBool::new(false);
+
+/// Flags whose following argument is a secret; `describe` prints `<redacted>` instead.
+const SECRET_FLAGS: &[&str] = &["--password", "--token", "-Password", "-Credential"];
+
+/// `--show-commands`: log every external command and HTTP request at info.
+pub fn set_show_commands(enabled: bool) {
+    SHOW_COMMANDS.store(enabled, Ordering::Relaxed);
+}
+
+fn showing() -> bool {
+    SHOW_COMMANDS.load(Ordering::Relaxed)
+}
+
+/// The program and its arguments on one line, Debug-quoting any argument with
+/// whitespace or quotes. Environment variables and stdin are never included.
+pub fn describe(command: &std::process::Command) -> String {
+    let mut line = command.get_program().to_string_lossy().to_string();
+    let mut redact_next = false;
+    for arg in command.get_args() {
+        let arg = arg.to_string_lossy();
+        line.push(' ');
+        if redact_next {
+            line.push_str("<redacted>");
+        } else if arg.is_empty()
+            || arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'')
+        {
+            line.push_str(&format!("{arg:?}"));
+        } else {
+            line.push_str(&arg);
+        }
+        redact_next = SECRET_FLAGS.contains(&arg.as_ref());
+    }
+    line
+}
+
+/// Logs a command started outside `output_with_timeout` under `--show-commands`.
+pub fn show_spawn(command: &std::process::Command) {
+    if showing() {
+        tracing::info!(command = %describe(command), "running command");
+    }
+}
+
+/// The matching exit line for `show_spawn`.
+pub fn show_exit(command: &std::process::Command, status: Result<ExitStatus, &std::io::Error>) {
+    if !showing() {
+        return;
+    }
+    let command = describe(command);
+    match status {
+        Ok(status) => tracing::info!(%command, %status, "command finished"),
+        Err(err) => tracing::info!(%command, error = %err, "command failed to run"),
+    }
+}
+
+/// Runs a command to completion, killing the child if it outlives `timeout`.
+pub async fn output_with_timeout(
+    command: &mut Command,
+    timeout: Duration,
+) -> Result<Output, BridgeError> {
+    let program = command.as_std().get_program().to_string_lossy().to_string();
+    command.kill_on_drop(true);
+    let shown = showing().then(|| describe(command.as_std()));
+    if let Some(shown) = &shown {
+        tracing::info!(command = %shown, "running command");
+    }
+    let started = Instant::now();
+
+    let result = match tokio::time::timeout(timeout, command.output()).await {
+        Ok(output) => output.map_err(|source| BridgeError::Spawn { program, source }),
+        Err(_) => Err(BridgeError::TimedOut { program, timeout }),
+    };
+    if let Some(shown) = &shown {
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+        match &result {
+            Ok(output) => {
+                tracing::info!(command = %shown, status = %output.status, elapsed_ms, "command finished")
+            }
+            Err(err) => {
+                tracing::info!(command = %shown, error = %err, elapsed_ms, "command failed")
+            }
+        }
+    }
+    result
+}
+
+/// `GET url`, logged with its status under `--show-commands`. A password in
+/// the URL is redacted; headers and bodies are never logged.
+pub async fn http_get(client: &reqwest::Client, url: &str) -> reqwest::Result<reqwest::Response> {
+    if !showing() {
+        return client.get(url).send().await;
+    }
+    let shown = match reqwest::Url::parse(url) {
+        Ok(mut parsed) if parsed.password().is_some() => {
+            let _ = parsed.set_password(Some("redacted"));
+            parsed.to_string()
+        }
+        _ => url.to_string(),
+    };
+    tracing::info!(url = %shown, "GET");
+    let started = Instant::now();
+    let result = client.get(url).send().await;
+    let elapsed_ms = started.elapsed().as_millis() as u64;
+    match &result {
+        Ok(response) => {
+            tracing::info!(url = %shown, status = %response.status(), elapsed_ms, "GET finished")
+        }
+        Err(err) => tracing::info!(url = %shown, error = %err, elapsed_ms, "GET failed"),
+    }
+    result
+}
+
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<BridgeError>()
+            .is_some_and(BridgeError::is_transient)
+    })
+}
@@ -0,0 +1,55 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// TCP relays the daemon is running for loopback-only services, keyed by the
+/// WSL address and port they listen on.
+#[derive(Default)]
+pub struct LoopbackRelays {
+    running: BTreeMap<(Ipv4Addr, u16), JoinHandle<()>>,
+}
+
+impl LoopbackRelays {
+    /// Starts a relay for each of `ports` on `ip` and stops any that no longer
+    /// match, e.g. after a port was removed or the WSL IP changed.
+    pub fn reconcile(&mut self, ip: Ipv4Addr, ports: &[u16]) {
+        self.running.retain(|(relay_ip, port), task| {
+            let keep = *relay_ip == ip && ports.contains(port) && !task.is_finished();
+            if !keep {
+                task.abort();
+            }
+            keep
+        });
+        for &port in ports {
+            self.running.entry((ip, port)).or_insert_with(|| {
+                tokio::spawn(async move {
+                    let listen = SocketAddr::V4(SocketAddrV4::new(ip, port));
+                    tracing::info!(%listen, "starting loopback relay");
+                    if let Err(err) = relay(listen, port).await {
+                        tracing::warn!(error = %err, %listen, "loopback relay stopped");
+                    }
+                })
+            });
+        }
+    }
+}
+
+/// Accepts on `listen` and pipes each connection to `127.0.0.1:port`.
+async fn relay(listen: SocketAddr, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(listen).await?;
+    loop {
+        let (mut inbound, peer) = listener.accept().await?;
+        tokio::spawn(async move {
+            let result = async {
+                let mut outbound = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).await?;
+                tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await
+            }
+            .await;
+            if let Err(err) = result {
+                tracing::debug!(error = %err, %peer, port, "loopback relay connection failed");
+            }
+        });
+    }
+}
@@ -0,0 +1,58 @@
+use crate::config;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Ports not seen for this long are dropped so the file doesn't grow forever.
+const FORGET_AFTER_SECS: u64 = 7 * 86_400;
+
+/// When each detected port was last reported, keyed by port (unix seconds).
+pub type LastSeen = BTreeMap<u16, u64>;
+
+pub fn path(config_dir: &Path) -> PathBuf {
+    config_dir.join("last-seen.json")
+}
+
+/// The stored timestamps; empty when the file is missing or unreadable.
+pub fn load(path: &Path) -> LastSeen {
+    match fs::read_to_string(path) {
+        Ok(raw) => serde_json::from_str(&raw).unwrap_or_else(|err| {
+            tracing::debug!(error = %err, path = %path.display(), "ignoring unreadable last-seen file");
+            LastSeen::new()
+        }),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => LastSeen::new(),
+        Err(err) => {
+            tracing::debug!(error = %err, path = %path.display(), "could not read last-seen file");
+            LastSeen::new()
+        }
+    }
+}
+
+/// Stamps `ports` with `now`, forgets long-gone ports, and writes the file back.
+pub fn record(path: &Path, ports: &BTreeSet<u16>, now: u64) -> io::Result<()> {
+    let mut seen = load(path);
+    seen.retain(|_, &mut at| now.saturating_sub(at) < FORGET_AFTER_SECS);
+    for &port in ports {
+        seen.insert(port, now);
+    }
+
+    if let Some(parent) = path.parent() {
+        config::create_private_dir(parent)?;
+    }
+    let contents = serde_json::to_string(&seen).map_err(io::Error::other)?;
+    let tmp = path.with_extension("json.tmp");
+    config::create_private_file(&tmp)?.write_all(contents.as_bytes())?;
+    fs::rename(&tmp, path)
+}
+
+/// `now`, `45s ago`, `12m ago`, `3h ago`, or `2d ago`.
+pub fn describe_age(at: u64, now: u64) -> String {
+    match now.saturating_sub(at) {
+        0..=4 => "now".to_string(),
+        secs @ 5..=59 => format!("{secs}s ago"),
+        secs @ 60..=3_599 => format!("{}m ago", secs / 60),
+        secs @ 3_600..=86_399 => format!("{}h ago", secs / 3_600),
+        secs => format!("{}d ago", secs / 86_400),
+    }
+}
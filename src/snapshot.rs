@@ -0,0 +1,84 @@
+use crate::config::PortsConfig;
+use crate::plan::Forward;
+use crate::ports::{ManualPorts, PortRemap};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::net::Ipv4Addr;
+
+/// A portable copy of what is being forwarded and why. Only the user-owned
+/// parts (manual ports, remaps, excludes) are restored on import; the rest
+/// is informational.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<Ipv4Addr>,
+    #[serde(flatten)]
+    pub manual_ports: ManualPorts,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remaps: Vec<PortRemap>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub exclude_ports: BTreeSet<u16>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub exclude_all: BTreeSet<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_profile: Option<String>,
+    #[serde(default)]
+    pub detected: DetectedPorts,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub forwards: Vec<Forward>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DetectedPorts {
+    #[serde(default)]
+    pub pm2: BTreeSet<u16>,
+    #[serde(default)]
+    pub caddy: BTreeSet<u16>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub listeners: BTreeSet<u16>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub dotenv: BTreeSet<u16>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub traefik: BTreeSet<u16>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub dev_servers: BTreeSet<u16>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub docker: BTreeSet<u16>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub compose: BTreeSet<u16>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub remote: BTreeSet<u16>,
+}
+
+impl Snapshot {
+    pub fn capture(cfg: &PortsConfig, ip: Ipv4Addr, forwards: Vec<Forward>) -> Self {
+        Snapshot {
+            ip: Some(ip),
+            manual_ports: cfg.manual_ports.clone(),
+            remaps: cfg.remaps.clone(),
+            exclude_ports: cfg.exclude_ports.clone(),
+            exclude_all: cfg.exclude_all.clone(),
+            active_profile: cfg.active_profile.clone(),
+            detected: DetectedPorts {
+                pm2: cfg.pm2_ports.clone(),
+                caddy: cfg.caddy_ports.clone(),
+                listeners: cfg.listener_ports.clone(),
+                dotenv: cfg.dotenv_ports.clone(),
+                traefik: cfg.traefik_ports.clone(),
+                dev_servers: cfg.dev_server_ports.clone(),
+                docker: cfg.docker_ports.clone(),
+                compose: cfg.compose_ports.clone(),
+                remote: cfg.remote_ports.clone(),
+            },
+            forwards,
+        }
+    }
+
+    /// Overwrites the config's manual ports, remaps, and excludes with the snapshot's.
+    pub fn restore_into(self, cfg: &mut PortsConfig) {
+        cfg.manual_ports = self.manual_ports;
+        cfg.remaps = self.remaps;
+        cfg.exclude_ports = self.exclude_ports;
+        cfg.exclude_all = self.exclude_all;
+    }
+}
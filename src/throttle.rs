@@ -0,0 +1,76 @@
+use std::fmt::Display;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How often a still-repeating error is summarized instead of logged again.
+const SUMMARY_WINDOW: Duration = Duration::from_secs(300);
+
+/// Logs an error the first time it appears, then only a periodic count while
+/// the identical error keeps repeating, so a persistent failure doesn't flood
+/// long-running daemon logs.
+#[derive(Debug)]
+pub struct RepeatedErrors {
+    what: &'static str,
+    current: Option<Repeat>,
+}
+
+#[derive(Debug)]
+struct Repeat {
+    message: String,
+    window_start: Instant,
+    repeats: u64,
+    total: u64,
+}
+
+impl RepeatedErrors {
+    pub fn new(what: &'static str) -> Self {
+        RepeatedErrors {
+            what,
+            current: None,
+        }
+    }
+
+    pub fn warn(&mut self, err: &dyn Display) {
+        let message = err.to_string();
+        let now = Instant::now();
+        match &mut self.current {
+            Some(repeat) if repeat.message == message => {
+                repeat.repeats += 1;
+                repeat.total += 1;
+                if now.duration_since(repeat.window_start) >= SUMMARY_WINDOW {
+                    tracing::warn!(
+                        error = %message,
+                        "{}: same error {} times in last {}m",
+                        self.what,
+                        repeat.repeats,
+                        SUMMARY_WINDOW.as_secs() / 60
+                    );
+                    repeat.window_start = now;
+                    repeat.repeats = 0;
+                }
+            }
+            _ => {
+                tracing::warn!(error = %message, "{}", self.what);
+                self.current = Some(Repeat {
+                    message,
+                    window_start: now,
+                    repeats: 0,
+                    total: 1,
+                });
+            }
+        }
+    }
+
+    /// Call once the operation succeeds so the next failure is logged in full.
+    pub fn clear(&mut self) {
+        if let Some(repeat) = self.current.take() {
+            if repeat.total > 1 {
+                tracing::info!(
+                    failures = repeat.total,
+                    "{}: recovered after repeated errors",
+                    self.what
+                );
+            }
+        }
+    }
+}
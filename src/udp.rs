@@ -0,0 +1,104 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::ports::PortRemap;
+
+/// A client's upstream socket is dropped after this long without traffic.
+const SESSION_IDLE: Duration = Duration::from_secs(60);
+const MAX_DATAGRAM: usize = 65_535;
+
+/// The relays the daemon is running, keyed by the WSL address and remap they serve.
+#[derive(Default)]
+pub struct Relays {
+    running: BTreeMap<(Ipv4Addr, PortRemap), JoinHandle<()>>,
+}
+
+impl Relays {
+    /// Starts relays for `wanted` on `ip` and stops any that no longer match,
+    /// e.g. after the WSL IP changed.
+    pub fn reconcile(&mut self, ip: Ipv4Addr, wanted: &[PortRemap]) {
+        self.running.retain(|(relay_ip, remap), task| {
+            let keep = *relay_ip == ip && wanted.contains(remap) && !task.is_finished();
+            if !keep {
+                task.abort();
+            }
+            keep
+        });
+        for &remap in wanted {
+            self.running.entry((ip, remap)).or_insert_with(|| {
+                tokio::spawn(async move {
+                    let listen = SocketAddr::V4(SocketAddrV4::new(ip, remap.listen));
+                    let target =
+                        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, remap.connect));
+                    tracing::info!(%listen, %target, "starting udp relay");
+                    if let Err(err) = relay(listen, target).await {
+                        tracing::warn!(error = %err, %listen, "udp relay stopped");
+                    }
+                })
+            });
+        }
+    }
+}
+
+/// Forwards datagrams from `listen` to `target`, giving each client its own
+/// upstream socket so replies find their way back.
+async fn relay(listen: SocketAddr, target: SocketAddr) -> io::Result<()> {
+    let socket = Arc::new(UdpSocket::bind(listen).await?);
+    let sessions: Arc<Mutex<HashMap<SocketAddr, Arc<UdpSocket>>>> = Arc::default();
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+
+    loop {
+        let (len, client) = socket.recv_from(&mut buf).await?;
+        let upstream = {
+            let mut open = sessions.lock().await;
+            match open.get(&client) {
+                Some(upstream) => upstream.clone(),
+                None => {
+                    let upstream = Arc::new(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?);
+                    upstream.connect(target).await?;
+                    open.insert(client, upstream.clone());
+                    tokio::spawn(replies(
+                        socket.clone(),
+                        upstream.clone(),
+                        client,
+                        sessions.clone(),
+                    ));
+                    upstream
+                }
+            }
+        };
+        if let Err(err) = upstream.send(&buf[..len]).await {
+            tracing::debug!(error = %err, %client, "udp relay send failed");
+        }
+    }
+}
+
+async fn replies(
+    socket: Arc<UdpSocket>,
+    upstream: Arc<UdpSocket>,
+    client: SocketAddr,
+    sessions: Arc<Mutex<HashMap<SocketAddr, Arc<UdpSocket>>>>,
+) {
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        match tokio::time::timeout(SESSION_IDLE, upstream.recv(&mut buf)).await {
+            Ok(Ok(len)) => {
+                if let Err(err) = socket.send_to(&buf[..len], client).await {
+                    tracing::debug!(error = %err, %client, "udp relay reply failed");
+                }
+            }
+            Ok(Err(err)) => {
+                tracing::debug!(error = %err, %client, "udp upstream receive failed");
+                break;
+            }
+            Err(_) => break,
+        }
+    }
+    sessions.lock().await.remove(&client);
+}
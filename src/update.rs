@@ -0,0 +1,59 @@
+use crate::process;
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::time::Duration;
+
+/// crates.io's API entry for this crate; `update_check_url` replaces it.
+pub const DEFAULT_URL: &str = "https://crates.io/api/v1/crates/wsl-port";
+
+pub const INSTALLED: &str = env!("CARGO_PKG_VERSION");
+
+/// The newest version `url` advertises. Understands crates.io
+/// (`crate.max_stable_version`), GitHub releases (`tag_name`), a JSON
+/// `{"version": ...}`, or a plain-text body holding just the version.
+pub async fn latest_version(url: &str, timeout: Duration) -> Result<String> {
+    let client = reqwest::Client::builder()
+        .timeout(timeout)
+        // crates.io and GitHub both reject requests without a user agent.
+        .user_agent(concat!("wsl-port/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .context("failed to build reqwest client")?;
+    let body = process::http_get(&client, url)
+        .await
+        .with_context(|| format!("failed requesting {url}"))?
+        .error_for_status()
+        .context("update check returned error status")?
+        .text()
+        .await
+        .context("failed reading update check response")?;
+    parse_latest(&body).with_context(|| format!("no version found in the response from {url}"))
+}
+
+fn parse_latest(body: &str) -> Option<String> {
+    let version = match serde_json::from_str::<Value>(body) {
+        Ok(value) => [
+            "/crate/max_stable_version",
+            "/crate/max_version",
+            "/tag_name",
+            "/version",
+        ]
+        .iter()
+        .find_map(|pointer| value.pointer(pointer).and_then(Value::as_str))?
+        .to_string(),
+        Err(_) => body.trim().to_string(),
+    };
+    let version = version.trim_start_matches('v');
+    numeric_parts(version).map(|_| version.to_string())
+}
+
+/// How `latest` compares to `installed`, by their dotted numeric parts;
+/// a pre-release suffix (`-rc.1`) is ignored. `None` if either is unparseable.
+pub fn compare(installed: &str, latest: &str) -> Option<Ordering> {
+    Some(numeric_parts(latest)?.cmp(&numeric_parts(installed)?))
+}
+
+fn numeric_parts(version: &str) -> Option<Vec<u64>> {
+    let release = version.trim_start_matches('v').split(['-', '+']).next()?;
+    release.split('.').map(|part| part.parse().ok()).collect()
+}
@@ -1,95 +1,1246 @@
-use anyhow::{Context, Result};
-use std::net::Ipv4Addr;
-use std::path::PathBuf;
+use crate::config::{PortsConfig, SshConfig};
+use crate::error::BridgeError;
+use crate::plan::Forward;
+use crate::ports::RuleFamily;
+use crate::process;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::Output;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 use tokio::process::Command;
+use tokio::task::JoinSet;
 
-fn find_powershell() -> PathBuf {
-    let candidates = [
-        "/mnt/c/Windows/System32/WindowsPowerShell/v1.0/powershell.exe",
-        "/mnt/c/WINDOWS/System32/WindowsPowerShell/v1.0/powershell.exe",
-    ];
-    
-    for path in &candidates {
-        if std::fs::metadata(path).is_ok() {
-            return PathBuf::from(path);
+pub type RunFuture<'a> = Pin<Box<dyn Future<Output = Result<Output, BridgeError>> + Send + 'a>>;
+
+/// Runs one PowerShell command line on the Windows side, however that is reached.
+pub trait RuleRunner: Send + Sync {
+    fn run<'a>(&'a self, command: &'a str) -> RunFuture<'a>;
+}
+
+/// The Windows side every netsh helper talks to: local interop by default,
+/// or SSH when the config has an `[ssh]` table.
+#[derive(Clone)]
+pub struct PowerShell {
+    runner: Arc<dyn RuleRunner>,
+}
+
+impl PowerShell {
+    pub fn new(cfg: &PortsConfig) -> Self {
+        if cfg.runtime.offline {
+            return Self {
+                runner: Arc::new(Offline),
+            };
+        }
+        let timeout = cfg.command_timeout();
+        let runner: Arc<dyn RuleRunner> = match &cfg.ssh {
+            Some(ssh) => Arc::new(SshRunner {
+                ssh: ssh.clone(),
+                timeout,
+            }),
+            None => Arc::new(LocalInterop { timeout }),
+        };
+        Self { runner }
+    }
+
+    pub fn with_runner(runner: Arc<dyn RuleRunner>) -> Self {
+        Self { runner }
+    }
+}
+
+/// Records every command instead of running it, answering each with empty
+/// success, so callers can see exactly what a sync would send to Windows.
+#[derive(Debug, Default)]
+pub struct RecordingRunner {
+    commands: Mutex<Vec<String>>,
+}
+
+impl RecordingRunner {
+    pub fn commands(&self) -> Vec<String> {
+        self.commands
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+}
+
+impl RuleRunner for RecordingRunner {
+    fn run<'a>(&'a self, command: &'a str) -> RunFuture<'a> {
+        use std::os::unix::process::ExitStatusExt;
+
+        self.commands
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(command.to_string());
+        Box::pin(async {
+            Ok(Output {
+                status: std::process::ExitStatus::from_raw(0),
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            })
+        })
+    }
+}
+
+/// Stands in for Windows under `--offline`, refusing every command.
+struct Offline;
+
+impl RuleRunner for Offline {
+    fn run<'a>(&'a self, _command: &'a str) -> RunFuture<'a> {
+        Box::pin(async { Err(BridgeError::Offline) })
+    }
+}
+
+/// `powershell.exe` through WSL interop, located on first use.
+pub struct LocalInterop {
+    timeout: Duration,
+}
+
+impl RuleRunner for LocalInterop {
+    fn run<'a>(&'a self, command: &'a str) -> RunFuture<'a> {
+        Box::pin(async move {
+            let mut cmd = Command::new(find_powershell(self.timeout).await);
+            cmd.arg("-NoProfile")
+                .arg("-NonInteractive")
+                .arg("-Command")
+                .arg(command);
+            process::output_with_timeout(&mut cmd, self.timeout)
+                .await
+                .map_err(|err| match err {
+                    BridgeError::Spawn { source, .. }
+                        if source.kind() == io::ErrorKind::NotFound =>
+                    {
+                        BridgeError::PowershellNotFound(source)
+                    }
+                    other => other,
+                })
+        })
+    }
+}
+
+/// `powershell` on a remote Windows host via `ssh`. The command travels as
+/// `-EncodedCommand`, so the remote shell's quoting rules never apply.
+pub struct SshRunner {
+    ssh: SshConfig,
+    timeout: Duration,
+}
+
+impl RuleRunner for SshRunner {
+    fn run<'a>(&'a self, command: &'a str) -> RunFuture<'a> {
+        Box::pin(async move {
+            let mut cmd = Command::new("ssh");
+            cmd.args(["-o", "BatchMode=yes"]);
+            if let Some(port) = self.ssh.port {
+                cmd.arg("-p").arg(port.to_string());
+            }
+            if let Some(identity) = &self.ssh.identity_file {
+                cmd.arg("-i").arg(identity);
+            }
+            cmd.arg(&self.ssh.host)
+                .args([
+                    "powershell",
+                    "-NoProfile",
+                    "-NonInteractive",
+                    "-EncodedCommand",
+                ])
+                .arg(encode_command(command));
+            process::output_with_timeout(&mut cmd, self.timeout).await
+        })
+    }
+}
+
+/// Base64 of the UTF-16LE bytes, the form `powershell -EncodedCommand` expects.
+fn encode_command(command: &str) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let bytes: Vec<u8> = command.encode_utf16().flat_map(u16::to_le_bytes).collect();
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = (u32::from(chunk[0]) << 16)
+            | (u32::from(*chunk.get(1).unwrap_or(&0)) << 8)
+            | u32::from(*chunk.get(2).unwrap_or(&0));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Resolved once per process; discovery may shell out to `cmd.exe`.
+static POWERSHELL_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+async fn find_powershell(timeout: Duration) -> PathBuf {
+    if let Some(path) = POWERSHELL_PATH.get() {
+        return path.clone();
+    }
+    let path = discover_powershell(timeout).await;
+    tracing::debug!(path = %path.display(), "using powershell");
+    POWERSHELL_PATH.get_or_init(|| path).clone()
+}
+
+/// Prefers PowerShell 7 (`pwsh.exe`) over Windows PowerShell, looking under
+/// the real Windows directory (honoring `%SystemRoot%` and the WSL automount
+/// root), then `PATH`, then the bare `powershell.exe` name.
+async fn discover_powershell(timeout: Duration) -> PathBuf {
+    let mut candidates = Vec::new();
+    if let Some(system_root) = windows_system_root(timeout).await {
+        if let Some(drive_root) = system_root.parent() {
+            candidates.push(drive_root.join("Program Files/PowerShell/7/pwsh.exe"));
+        }
+        candidates.push(system_root.join("System32/WindowsPowerShell/v1.0/powershell.exe"));
+    }
+    let mount_root = automount_root();
+    candidates.push(mount_root.join("c/Program Files/PowerShell/7/pwsh.exe"));
+    candidates.push(mount_root.join("c/Windows/System32/WindowsPowerShell/v1.0/powershell.exe"));
+    candidates.push(mount_root.join("c/WINDOWS/System32/WindowsPowerShell/v1.0/powershell.exe"));
+
+    if let Some(path) = candidates.into_iter().find(|path| path.is_file()) {
+        return path;
+    }
+
+    ["pwsh.exe", "powershell.exe"]
+        .into_iter()
+        .find_map(find_on_path)
+        .unwrap_or_else(|| PathBuf::from("powershell.exe"))
+}
+
+/// `%SystemRoot%` (e.g. `D:\Windows`) translated to its WSL mount path.
+async fn windows_system_root(timeout: Duration) -> Option<PathBuf> {
+    windows_env_path("SystemRoot", timeout).await
+}
+
+/// `%USERPROFILE%` (e.g. `C:\Users\alice`) as a WSL path; where `.wslconfig` lives.
+pub async fn user_profile_dir(timeout: Duration) -> Option<PathBuf> {
+    windows_env_path("USERPROFILE", timeout).await
+}
+
+/// A Windows environment variable holding a drive path, translated to its
+/// WSL mount path under the automount root. `None` when `cmd.exe` fails,
+/// times out, or prints something other than a drive path.
+async fn windows_env_path(var: &str, timeout: Duration) -> Option<PathBuf> {
+    let mut command = Command::new("cmd.exe");
+    command
+        .args(["/c", "echo", &format!("%{var}%")])
+        .current_dir("/")
+        .stderr(std::process::Stdio::null());
+    let output = match process::output_with_timeout(&mut command, timeout).await {
+        Ok(output) => output,
+        Err(err) => {
+            tracing::debug!(error = %err, var, "could not read Windows environment variable");
+            return None;
+        }
+    };
+    windows_drive_path(&automount_root(), &String::from_utf8_lossy(&output.stdout))
+}
+
+/// `D:\Windows` as `<mount_root>/d/Windows`; `None` for anything else,
+/// including an unexpanded `%VAR%`.
+fn windows_drive_path(mount_root: &Path, raw: &str) -> Option<PathBuf> {
+    let (drive, rest) = raw.trim().split_once(":\\")?;
+    if drive.len() != 1 || !drive.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    Some(
+        mount_root
+            .join(drive.to_ascii_lowercase())
+            .join(rest.replace('\\', "/")),
+    )
+}
+
+/// The `[automount] root` from `/etc/wsl.conf`, defaulting to `/mnt/`.
+fn automount_root() -> PathBuf {
+    let conf = std::fs::read_to_string("/etc/wsl.conf").unwrap_or_default();
+    ini_value(&conf, "automount", "root")
+        .filter(|value| !value.is_empty())
+        .map_or_else(|| PathBuf::from("/mnt/"), PathBuf::from)
+}
+
+/// `key` in `[section]` of a `wsl.conf`/`.wslconfig` style file, unquoted.
+/// Section and key names match case-insensitively; `#`/`;` lines are comments.
+pub fn ini_value(contents: &str, section: &str, key: &str) -> Option<String> {
+    let mut in_section = false;
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line
+            .strip_prefix('[')
+            .and_then(|rest| rest.strip_suffix(']'))
+        {
+            in_section = name.trim().eq_ignore_ascii_case(section);
+            continue;
+        }
+        if !in_section {
+            continue;
+        }
+        if let Some((name, value)) = line.split_once('=') {
+            if name.trim().eq_ignore_ascii_case(key) {
+                return Some(value.trim().trim_matches('"').to_string());
+            }
+        }
+    }
+    None
+}
+
+fn find_on_path(program: &str) -> Option<PathBuf> {
+    let paths = std::env::var_os("PATH")?;
+    std::env::split_paths(&paths)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
+
+/// When a sync deletes each rule it is about to re-add (`--apply-order`).
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ApplyOrder {
+    /// Delete every listen port's old rule in batched calls, then add them all,
+    /// so no add runs while another port's old rule is still in place.
+    #[default]
+    DeletesFirst,
+    /// Delete and re-add each port (or batched range) in turn.
+    PerPort,
+}
+
+/// Applies the forwards in ascending listen-port order, running up to
+/// `concurrency` of them at once; `order` picks whether their old rules are
+/// all deleted up front or one by one. With a `budget`, ports not started once
+/// it runs out are skipped and reported, and the sync fails as timed out.
+/// After the first failure nothing new starts; rules already in flight finish
+/// first. Under `ApplyOrder::DeletesFirst`, skipped ports get their old rules
+/// back, so a cut-off sync leaves them as they were, like `PerPort` does.
+pub async fn apply_portproxy_rules(
+    ps: &PowerShell,
+    forwards: &[Forward],
+    budget: Option<Duration>,
+    concurrency: usize,
+    order: ApplyOrder,
+) -> Result<(), BridgeError> {
+    let sync_started = Instant::now();
+    let mut in_flight = JoinSet::new();
+    let mut failure = None;
+
+    let delete_each = order == ApplyOrder::PerPort;
+    let before = if !delete_each && !forwards.is_empty() {
+        let before = list_portproxy_rules(ps).await?;
+        let slots: Vec<(RuleFamily, IpAddr, u16)> = forwards.iter().map(slot).collect();
+        delete_portproxy_rules(ps, &slots).await?;
+        before
+    } else {
+        Vec::new()
+    };
+
+    let units = batch_ranges(forwards);
+    let mut not_started = units.len();
+    for (started, unit) in units.iter().enumerate() {
+        if in_flight.len() >= concurrency.max(1) {
+            if let Some(Err(err)) = join_rule(&mut in_flight).await {
+                failure = Some(err);
+                not_started = started;
+                break;
+            }
+        }
+        if let Some(budget) = budget.filter(|&budget| sync_started.elapsed() >= budget) {
+            let skipped: Vec<u16> = units[started..]
+                .iter()
+                .flatten()
+                .map(|f| f.listen_port)
+                .collect();
+            failure = Some(BridgeError::SyncBudgetExceeded { budget, skipped });
+            not_started = started;
+            break;
         }
+        in_flight.spawn(apply_unit(ps.clone(), unit.clone(), delete_each));
+    }
+    while let Some(result) = join_rule(&mut in_flight).await {
+        if let Err(err) = result {
+            failure.get_or_insert(err);
+        }
+    }
+    if let Some(err) = failure {
+        let skipped: Vec<(RuleFamily, IpAddr, u16)> =
+            units[not_started..].iter().flatten().map(slot).collect();
+        if !delete_each && !skipped.is_empty() {
+            if let Err(restore_err) = restore_portproxy_rules(ps, &before, &skipped).await {
+                tracing::error!(
+                    error = %restore_err,
+                    "could not restore the old rules of ports the sync never reached; they may have no rule"
+                );
+            }
+        }
+        return Err(err);
+    }
+
+    tracing::info!(
+        rules = forwards.len(),
+        concurrency,
+        ?order,
+        elapsed_ms = sync_started.elapsed().as_millis() as u64,
+        "portproxy sync finished"
+    );
+    Ok(())
+}
+
+fn slot(forward: &Forward) -> (RuleFamily, IpAddr, u16) {
+    (
+        forward.family(),
+        forward.listen_address,
+        forward.listen_port,
+    )
+}
+
+/// The next finished rule's result; `None` once nothing is in flight.
+async fn join_rule(
+    in_flight: &mut JoinSet<Result<(), BridgeError>>,
+) -> Option<Result<(), BridgeError>> {
+    let joined = in_flight.join_next().await?;
+    Some(joined.unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic())))
+}
+
+/// Contiguous runs at least this long are applied as one PowerShell script.
+const RANGE_BATCH_MIN: usize = 3;
+/// Caps one script, keeping the failing rule's index within an exit code.
+const RANGE_BATCH_MAX: usize = 100;
+
+/// Splits `forwards` into what one PowerShell call applies: each run of
+/// `RANGE_BATCH_MIN`+ rules with consecutive listen and connect ports (same
+/// family and addresses) is one unit, and every other rule stands alone.
+fn batch_ranges(forwards: &[Forward]) -> Vec<Vec<Forward>> {
+    let mut sorted: Vec<&Forward> = forwards.iter().collect();
+    sorted.sort_by_key(|f| {
+        (
+            f.family(),
+            f.listen_address,
+            f.connect_address,
+            f.listen_port,
+        )
+    });
+
+    let mut units = Vec::new();
+    let mut run: Vec<Forward> = Vec::new();
+    for forward in sorted {
+        let continues = run.last().is_some_and(|last: &Forward| {
+            last.family() == forward.family()
+                && last.listen_address == forward.listen_address
+                && last.connect_address == forward.connect_address
+                && last.listen_port.checked_add(1) == Some(forward.listen_port)
+                && last.connect_port.checked_add(1) == Some(forward.connect_port)
+        });
+        if !continues || run.len() >= RANGE_BATCH_MAX {
+            flush_run(&mut units, std::mem::take(&mut run));
+        }
+        run.push(forward.clone());
+    }
+    flush_run(&mut units, run);
+    units
+}
+
+fn flush_run(units: &mut Vec<Vec<Forward>>, run: Vec<Forward>) {
+    if run.len() >= RANGE_BATCH_MIN {
+        units.push(run);
+    } else {
+        units.extend(run.into_iter().map(|forward| vec![forward]));
+    }
+}
+
+/// `delete` removes each rule's listen port first; off when `ApplyOrder::DeletesFirst` already did.
+async fn apply_unit(ps: PowerShell, unit: Vec<Forward>, delete: bool) -> Result<(), BridgeError> {
+    match <[Forward; 1]>::try_from(unit) {
+        Ok([forward]) => apply_rule(ps, forward, delete).await,
+        Err(range) => apply_range(ps, range, delete).await,
+    }
+}
+
+/// `apply_rule` for a whole range in one PowerShell call. The script stops at
+/// the first failed add, printing its output and exiting with that rule's
+/// 1-based index; rules before it stay applied. Unless netsh asked for
+/// elevation, the rest of the range is then retried one rule at a time so
+/// each failure gets `apply_rule`'s handling.
+async fn apply_range(ps: PowerShell, range: Vec<Forward>, delete: bool) -> Result<(), BridgeError> {
+    let started = Instant::now();
+    let mut script = Vec::with_capacity(range.len());
+    for (index, forward) in range.iter().enumerate() {
+        let delete = if delete {
+            let command = netsh_command(&delete_rule_args(
+                forward.family(),
+                forward.listen_address,
+                forward.listen_port,
+            ))?;
+            format!("{command} | Out-Null; ")
+        } else {
+            String::new()
+        };
+        let add = netsh_command(&add_rule_args(forward))?;
+        script.push(format!(
+            "{delete}$out = {add}; if ($LASTEXITCODE -ne 0) {{ $out; exit {} }}",
+            index + 1
+        ));
+    }
+
+    let output = run_powershell(&ps, &script.join("\n")).await?;
+    if !output.status.success() {
+        // Any other exit (PowerShell itself failing) is blamed on the first rule.
+        let failed = output
+            .status
+            .code()
+            .and_then(|code| usize::try_from(code).ok())
+            .and_then(|code| code.checked_sub(1))
+            .filter(|&index| index < range.len())
+            .unwrap_or(0);
+        if classify_netsh(&output) == NetshOutcome::AccessDenied {
+            return Err(BridgeError::NetshElevationRequired {
+                port: range[failed].listen_port,
+            });
+        }
+        tracing::debug!(
+            port = range[failed].listen_port,
+            output = %netsh_message(&output),
+            "batched range stopped; applying the rest rule by rule"
+        );
+        for forward in &range[failed..] {
+            apply_rule(ps.clone(), forward.clone(), delete).await?;
+        }
+        return Ok(());
+    }
+
+    tracing::debug!(
+        first = %range[0].listen(),
+        last = %range[range.len() - 1].listen(),
+        rules = range.len(),
+        elapsed_ms = started.elapsed().as_millis() as u64,
+        "applied portproxy range"
+    );
+    Ok(())
+}
+
+/// How a netsh call went, read from its exit status and output. netsh prints
+/// its errors on stdout, localized on non-English Windows; only the English
+/// wording is recognized and anything else counts as `Failed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NetshOutcome {
+    Succeeded,
+    /// There was no such rule; expected when deleting before an add.
+    NotFound,
+    /// The rule is already there, so an add left it in place.
+    AlreadyExists,
+    /// netsh needs an elevated shell; retrying won't help.
+    AccessDenied,
+    Failed,
+}
+
+fn classify_netsh(output: &Output) -> NetshOutcome {
+    if output.status.success() {
+        return NetshOutcome::Succeeded;
+    }
+    let message = netsh_message(output).to_ascii_lowercase();
+    if asks_for_elevation(&message) {
+        NetshOutcome::AccessDenied
+    } else if message.contains("element not found")
+        || message.contains("cannot find the file specified")
+    {
+        NetshOutcome::NotFound
+    } else if message.contains("already exists") {
+        NetshOutcome::AlreadyExists
+    } else {
+        NetshOutcome::Failed
+    }
+}
+
+fn asks_for_elevation(lowercase_message: &str) -> bool {
+    lowercase_message.contains("requires elevation")
+        || lowercase_message.contains("access is denied")
+}
+
+/// stderr, or stdout when stderr is empty, which is where netsh writes.
+fn netsh_message(output: &Output) -> String {
+    match stderr_of(output) {
+        stderr if stderr.is_empty() => String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        stderr => stderr,
     }
-    
-    PathBuf::from("powershell.exe")
 }
 
-pub async fn apply_portproxy_rules(wsl_ip: Ipv4Addr, ports: &[u16]) -> Result<()> {
-    let ps = find_powershell();
-    
-    for &port in ports {
-        let delete_cmd = format!(
-            "netsh interface portproxy delete v4tov4 listenport={} listenaddress=0.0.0.0",
-            port
+/// Deletes the old rule (unless `delete` is off), then adds the new one. A missing old rule is the
+/// normal case; an add that finds its rule already there counts as applied;
+/// any other add failure is retried once before giving up.
+async fn apply_rule(ps: PowerShell, forward: Forward, delete: bool) -> Result<(), BridgeError> {
+    let started = Instant::now();
+    if delete {
+        delete_portproxy_rule(
+            &ps,
+            forward.family(),
+            forward.listen_address,
+            forward.listen_port,
+        )
+        .await?;
+    }
+
+    let add_cmd = netsh_command(&add_rule_args(&forward))?;
+    let mut output = run_powershell(&ps, &add_cmd).await?;
+    if classify_netsh(&output) == NetshOutcome::Failed {
+        tracing::debug!(
+            listen = %forward.listen(),
+            output = %netsh_message(&output),
+            "netsh add failed; retrying once"
         );
-        // Ignore delete errors (rule might not exist)
-        let _ = run_powershell(&ps, &delete_cmd).await;
+        output = run_powershell(&ps, &add_cmd).await?;
+    }
+    match classify_netsh(&output) {
+        NetshOutcome::Succeeded => {}
+        NetshOutcome::AlreadyExists => {
+            tracing::debug!(listen = %forward.listen(), "rule already exists; leaving it")
+        }
+        NetshOutcome::AccessDenied => {
+            return Err(BridgeError::NetshElevationRequired {
+                port: forward.listen_port,
+            })
+        }
+        NetshOutcome::NotFound | NetshOutcome::Failed => {
+            return Err(BridgeError::NetshFailed {
+                port: forward.listen_port,
+                status: output.status,
+                stderr: netsh_message(&output),
+            })
+        }
+    }
+
+    tracing::debug!(
+        listen = %forward.listen(),
+        sources = %forward.describe_sources(),
+        elapsed_ms = started.elapsed().as_millis() as u64,
+        "applied portproxy rule"
+    );
+    Ok(())
+}
+
+/// Deletes one rule. A rule that isn't there is fine, and so is any other
+/// netsh failure (logged; a following add reports real trouble), except a
+/// missing elevation, which no later step can fix.
+pub async fn delete_portproxy_rule(
+    ps: &PowerShell,
+    family: RuleFamily,
+    listen_address: IpAddr,
+    listen_port: u16,
+) -> Result<(), BridgeError> {
+    let delete_cmd = netsh_command(&delete_rule_args(family, listen_address, listen_port))?;
+    let output = run_powershell(ps, &delete_cmd).await?;
+    match classify_netsh(&output) {
+        NetshOutcome::Succeeded | NetshOutcome::NotFound | NetshOutcome::AlreadyExists => Ok(()),
+        NetshOutcome::AccessDenied => {
+            Err(BridgeError::NetshElevationRequired { port: listen_port })
+        }
+        NetshOutcome::Failed => {
+            tracing::debug!(
+                listen = %SocketAddr::new(listen_address, listen_port),
+                output = %netsh_message(&output),
+                "netsh delete failed; continuing"
+            );
+            Ok(())
+        }
+    }
+}
+
+/// `delete_portproxy_rule` for many slots, sent `RANGE_BATCH_MAX` to a
+/// PowerShell call. Missing rules are fine; failing to run PowerShell or a
+/// netsh call asking for elevation is an error.
+pub async fn delete_portproxy_rules(
+    ps: &PowerShell,
+    slots: &[(RuleFamily, IpAddr, u16)],
+) -> Result<(), BridgeError> {
+    for chunk in slots.chunks(RANGE_BATCH_MAX) {
+        let script = chunk
+            .iter()
+            .map(|&(family, address, port)| netsh_command(&delete_rule_args(family, address, port)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let output = run_powershell(ps, &script.join("\n")).await?;
+        // Only the last call's exit code survives, so look at the text instead.
+        let message = String::from_utf8_lossy(&output.stdout).to_ascii_lowercase();
+        if asks_for_elevation(&message) {
+            return Err(BridgeError::NetshElevationRequired { port: chunk[0].2 });
+        }
+    }
+    Ok(())
+}
+
+fn add_rule_args(forward: &Forward) -> Vec<String> {
+    vec![
+        "interface".to_string(),
+        "portproxy".to_string(),
+        "add".to_string(),
+        forward.family().to_string(),
+        format!("listenport={}", forward.listen_port),
+        format!("listenaddress={}", forward.listen_address),
+        format!("connectport={}", forward.connect_port),
+        format!("connectaddress={}", forward.connect_address),
+    ]
+}
+
+/// Like `add_rule_args`, but for a rule read back from netsh, whose fields are
+/// plain strings and rely on `netsh_command` validation.
+fn restore_rule_args(rule: &PortproxyRule) -> Vec<String> {
+    vec![
+        "interface".to_string(),
+        "portproxy".to_string(),
+        "add".to_string(),
+        rule.family.to_string(),
+        format!("listenport={}", rule.listen_port),
+        format!("listenaddress={}", rule.listen_address),
+        format!("connectport={}", rule.connect_port),
+        format!("connectaddress={}", rule.connect_address),
+    ]
+}
+
+fn delete_rule_args(family: RuleFamily, listen_address: IpAddr, listen_port: u16) -> Vec<String> {
+    vec![
+        "interface".to_string(),
+        "portproxy".to_string(),
+        "delete".to_string(),
+        family.to_string(),
+        format!("listenport={listen_port}"),
+        format!("listenaddress={listen_address}"),
+    ]
+}
+
+/// Joins netsh arguments into a PowerShell command line. Every argument must
+/// stick to a conservative allowlist, so nothing (quotes, `;`, `$`, spaces)
+/// can escape into PowerShell syntax, even once hostnames become configurable.
+fn netsh_command(args: &[String]) -> Result<String, BridgeError> {
+    if let Some(bad) = args.iter().find(|arg| !is_safe_netsh_arg(arg)) {
+        return Err(BridgeError::UnsafeNetshArgument(bad.clone()));
+    }
+    Ok(format!("netsh {}", args.join(" ")))
+}
 
-        let add_cmd = format!(
-            "netsh interface portproxy add v4tov4 listenport={} listenaddress=0.0.0.0 connectport={} connectaddress={}",
-            port, port, wsl_ip
+fn is_safe_netsh_arg(arg: &str) -> bool {
+    !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | ':' | '=' | '-' | '_'))
+}
+
+/// Puts the listen slots in `touched` back the way `before` had them: every
+/// touched slot is cleared, then any rule that previously lived there is re-added.
+pub async fn restore_portproxy_rules(
+    ps: &PowerShell,
+    before: &[PortproxyRule],
+    touched: &[(RuleFamily, IpAddr, u16)],
+) -> Result<(), BridgeError> {
+    for &(family, listen_address, listen_port) in touched {
+        tracing::info!(listen = %SocketAddr::new(listen_address, listen_port), "rollback: deleting rule");
+        delete_portproxy_rule(ps, family, listen_address, listen_port).await?;
+    }
+
+    for rule in before {
+        let was_touched = touched.iter().any(|&(family, address, port)| {
+            family == rule.family
+                && port == rule.listen_port
+                && address.to_string() == rule.listen_address
+        });
+        if !was_touched {
+            continue;
+        }
+        tracing::info!(
+            listen = %rule.listen(),
+            connect = %rule.connect(),
+            "rollback: restoring rule"
         );
-        run_powershell(&ps, &add_cmd).await?;
+        let output = run_powershell(ps, &netsh_command(&restore_rule_args(rule))?).await?;
+        if !output.status.success() {
+            return Err(BridgeError::NetshFailed {
+                port: rule.listen_port,
+                status: output.status,
+                stderr: stderr_of(&output),
+            });
+        }
     }
+    Ok(())
+}
 
+/// Shows a Windows toast via BurntToast when installed, else a `msg.exe` popup.
+pub async fn notify(ps: &PowerShell, title: &str, body: &str) -> Result<(), BridgeError> {
+    let (title, body) = (quote_ps(title), quote_ps(body));
+    let script = format!(
+        "if (Get-Module -ListAvailable -Name BurntToast) {{ \
+         New-BurntToastNotification -Text {title}, {body} }} \
+         else {{ msg.exe * \"$({title}): $({body})\" }}"
+    );
+    run_powershell_capture(ps, &script).await?;
     Ok(())
 }
 
-pub async fn show_portproxy() -> Result<String> {
-    let ps = find_powershell();
-    run_powershell_capture(&ps, "netsh interface portproxy show v4tov4").await
+/// Opens `url` in the Windows default browser.
+pub async fn open_url(ps: &PowerShell, url: &str) -> Result<(), BridgeError> {
+    run_powershell_capture(ps, &format!("Start-Process {}", quote_ps(url))).await?;
+    Ok(())
 }
 
-async fn run_powershell(powershell_path: &PathBuf, command: &str) -> Result<()> {
-    let output = Command::new(powershell_path)
-        .arg("-NoProfile")
-        .arg("-NonInteractive")
-        .arg("-Command")
-        .arg(command)
-        .output()
-        .await
-        .with_context(|| format!("failed to launch powershell for command: {command}"))?;
+/// A PowerShell single-quoted string literal; `'` is the only special character.
+fn quote_ps(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "''"))
+}
 
-    if output.status.success() {
-        return Ok(());
+/// Whether Windows can open a TCP connection to `address:port` within a second.
+pub async fn can_reach(ps: &PowerShell, address: IpAddr, port: u16) -> Result<bool, BridgeError> {
+    let script = format!(
+        "$c = New-Object System.Net.Sockets.TcpClient; \
+         try {{ $c.ConnectAsync('{address}', {port}).Wait(1000) }} catch {{ $false }} \
+         finally {{ $c.Dispose() }}"
+    );
+    let output = run_powershell_capture(ps, &script).await?;
+    Ok(output.trim().eq_ignore_ascii_case("true"))
+}
+
+/// The IPv4 of the Windows interface carrying the lowest-metric default route.
+pub async fn lan_ipv4(ps: &PowerShell) -> Result<Ipv4Addr, BridgeError> {
+    let script = "$route = Get-NetRoute -DestinationPrefix 0.0.0.0/0 | \
+                  Sort-Object RouteMetric | Select-Object -First 1; \
+                  Get-NetIPAddress -AddressFamily IPv4 -InterfaceIndex $route.InterfaceIndex | \
+                  Select-Object -First 1 -ExpandProperty IPAddress";
+    let output = run_powershell_capture(ps, script).await?;
+    output.trim().parse().map_err(|_| {
+        BridgeError::IpLookupFailed(format!(
+            "unexpected Windows LAN address `{}`",
+            output.trim()
+        ))
+    })
+}
+
+/// The IPv4 currently assigned to the Windows interface with this alias
+/// (e.g. `Ethernet`, `vEthernet (WSL)`).
+pub async fn interface_ipv4(ps: &PowerShell, alias: &str) -> Result<Ipv4Addr, BridgeError> {
+    let script = format!(
+        "Get-NetIPAddress -AddressFamily IPv4 -InterfaceAlias {} -ErrorAction Stop | \
+         Select-Object -First 1 -ExpandProperty IPAddress",
+        quote_ps(alias)
+    );
+    let output = run_powershell_capture(ps, &script).await?;
+    output.trim().parse().map_err(|_| {
+        BridgeError::IpLookupFailed(format!("Windows interface `{alias}` has no IPv4 address"))
+    })
+}
+
+pub async fn show_portproxy(ps: &PowerShell) -> Result<String, BridgeError> {
+    run_powershell_capture(ps, "netsh interface portproxy show all").await
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortproxyRule {
+    pub family: RuleFamily,
+    pub listen_address: String,
+    pub listen_port: u16,
+    pub connect_address: String,
+    pub connect_port: u16,
+}
+
+impl PortproxyRule {
+    /// `address:port` as netsh printed the address, bracketed when it is IPv6.
+    pub fn listen(&self) -> String {
+        endpoint(&self.listen_address, self.listen_port)
     }
 
-    let stderr = String::from_utf8_lossy(&output.stderr);
+    pub fn connect(&self) -> String {
+        endpoint(&self.connect_address, self.connect_port)
+    }
+}
 
-    // Delete fails if rule doesn't exist - that's ok
-    if command.contains("portproxy delete") {
-        return Ok(());
+fn endpoint(address: &str, port: u16) -> String {
+    if address.contains(':') {
+        format!("[{address}]:{port}")
+    } else {
+        format!("{address}:{port}")
     }
+}
 
-    anyhow::bail!(
-        "powershell command failed ({}): {}",
-        output.status,
-        stderr.trim()
-    )
+pub async fn list_portproxy_rules(ps: &PowerShell) -> Result<Vec<PortproxyRule>, BridgeError> {
+    let raw = show_portproxy(ps).await?;
+    Ok(parse_portproxy_rules(&raw))
+}
+
+/// Parses the tables printed by `netsh interface portproxy show all`, taking
+/// each table's family from its `Listen on ipvX: Connect to ipvY:` heading.
+fn parse_portproxy_rules(raw: &str) -> Vec<PortproxyRule> {
+    let mut family = RuleFamily::V4ToV4;
+    raw.lines()
+        .filter_map(|line| {
+            let heading = line.to_ascii_lowercase();
+            if let Some((listen, connect)) = heading.split_once("connect to") {
+                family = RuleFamily::new(listen.contains("ipv6"), connect.contains("ipv6"));
+                return None;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [listen_address, listen_port, connect_address, connect_port] = fields[..] else {
+                return None;
+            };
+            Some(PortproxyRule {
+                family,
+                listen_address: listen_address.to_string(),
+                listen_port: listen_port.parse().ok()?,
+                connect_address: connect_address.to_string(),
+                connect_port: connect_port.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+async fn run_powershell(ps: &PowerShell, command: &str) -> Result<Output, BridgeError> {
+    ps.runner.run(command).await
 }
 
-async fn run_powershell_capture(powershell_path: &PathBuf, command: &str) -> Result<String> {
-    let output = Command::new(powershell_path)
-        .arg("-NoProfile")
-        .arg("-NonInteractive")
-        .arg("-Command")
-        .arg(command)
-        .output()
-        .await
-        .with_context(|| format!("failed to launch powershell for command: {command}"))?;
+async fn run_powershell_capture(ps: &PowerShell, command: &str) -> Result<String, BridgeError> {
+    let output = run_powershell(ps, command).await?;
 
     if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!(
-            "powershell command failed ({}): {}",
-            output.status,
-            stderr.trim()
-        );
+        return Err(BridgeError::PowershellFailed {
+            status: output.status,
+            stderr: stderr_of(&output),
+        });
     }
 
     Ok(String::from_utf8_lossy(&output.stdout).to_string())
 }
+
+fn stderr_of(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WSL: Ipv4Addr = Ipv4Addr::new(172, 20, 0, 2);
+
+    fn forward(listen_port: u16, connect_port: u16) -> Forward {
+        Forward {
+            listen_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            listen_port,
+            connect_address: IpAddr::V4(WSL),
+            connect_port,
+            sources: Default::default(),
+        }
+    }
+
+    /// Applies `forwards` one at a time through a `RecordingRunner`.
+    async fn recorded(forwards: &[Forward], order: ApplyOrder) -> Vec<String> {
+        let runner = Arc::new(RecordingRunner::default());
+        let ps = PowerShell::with_runner(runner.clone());
+        apply_portproxy_rules(&ps, forwards, None, 1, order)
+            .await
+            .unwrap();
+        runner.commands()
+    }
+
+    fn rule(connect_address: &str) -> PortproxyRule {
+        PortproxyRule {
+            family: RuleFamily::V4ToV4,
+            listen_address: "0.0.0.0".to_string(),
+            listen_port: 8080,
+            connect_address: connect_address.to_string(),
+            connect_port: 8080,
+        }
+    }
+
+    #[test]
+    fn netsh_command_joins_safe_arguments() {
+        let command = netsh_command(&restore_rule_args(&rule("wsl-host.local"))).unwrap();
+        assert_eq!(
+            command,
+            "netsh interface portproxy add v4tov4 listenport=8080 listenaddress=0.0.0.0 \
+             connectport=8080 connectaddress=wsl-host.local"
+        );
+    }
+
+    #[test]
+    fn netsh_command_rejects_hostnames_that_break_out() {
+        for hostname in [
+            "evil.com; Remove-Item -Recurse C:\\",
+            "x`$(Start-Process calc)",
+            "host' ; calc; '",
+            "a|b",
+            "a\nnetsh interface portproxy reset",
+        ] {
+            let err = netsh_command(&restore_rule_args(&rule(hostname))).unwrap_err();
+            assert!(
+                matches!(err, BridgeError::UnsafeNetshArgument(_)),
+                "{hostname:?} got through: {err}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn per_port_sync_deletes_then_adds_each_rule() {
+        let commands = recorded(
+            &[forward(3000, 3000), forward(8080, 5173)],
+            ApplyOrder::PerPort,
+        )
+        .await;
+        assert_eq!(
+            commands,
+            [
+                "netsh interface portproxy delete v4tov4 listenport=3000 listenaddress=0.0.0.0",
+                "netsh interface portproxy add v4tov4 listenport=3000 listenaddress=0.0.0.0 \
+                 connectport=3000 connectaddress=172.20.0.2",
+                "netsh interface portproxy delete v4tov4 listenport=8080 listenaddress=0.0.0.0",
+                "netsh interface portproxy add v4tov4 listenport=8080 listenaddress=0.0.0.0 \
+                 connectport=5173 connectaddress=172.20.0.2",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn deletes_first_batches_the_deletes_and_ranges() {
+        let forwards: Vec<Forward> = [9000, 8001, 8000, 8002]
+            .into_iter()
+            .map(|port| forward(port, port))
+            .collect();
+        let commands = recorded(&forwards, ApplyOrder::DeletesFirst).await;
+        let delete = |port: u16| {
+            format!(
+                "netsh interface portproxy delete v4tov4 listenport={port} listenaddress=0.0.0.0"
+            )
+        };
+        let add = |port: u16| {
+            format!(
+                "netsh interface portproxy add v4tov4 listenport={port} listenaddress=0.0.0.0 \
+                 connectport={port} connectaddress=172.20.0.2"
+            )
+        };
+        let range: Vec<String> = [8000, 8001, 8002]
+            .into_iter()
+            .enumerate()
+            .map(|(index, port)| {
+                format!(
+                    "$out = {}; if ($LASTEXITCODE -ne 0) {{ $out; exit {} }}",
+                    add(port),
+                    index + 1
+                )
+            })
+            .collect();
+        assert_eq!(
+            commands,
+            [
+                "netsh interface portproxy show all".to_string(),
+                [9000, 8001, 8000, 8002].map(delete).join("\n"),
+                range.join("\n"),
+                add(9000),
+            ]
+        );
+    }
+
+    fn netsh_output(code: i32, stdout: &str) -> Output {
+        use std::os::unix::process::ExitStatusExt;
+
+        Output {
+            status: std::process::ExitStatus::from_raw(code << 8),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn classify_netsh_reads_captured_outputs() {
+        let cases = [
+            (0, "\r\n", NetshOutcome::Succeeded),
+            (
+                1,
+                "The system cannot find the file specified.\r\n\r\n",
+                NetshOutcome::NotFound,
+            ),
+            (1, "Element not found.\r\n\r\n", NetshOutcome::NotFound),
+            (
+                1,
+                "The requested operation requires elevation (Run as administrator).\r\n\r\n",
+                NetshOutcome::AccessDenied,
+            ),
+            (1, "Access is denied.\r\n", NetshOutcome::AccessDenied),
+            (
+                1,
+                "The object already exists.\r\n\r\n",
+                NetshOutcome::AlreadyExists,
+            ),
+            (
+                1,
+                "The parameter is incorrect.\r\n\r\n",
+                NetshOutcome::Failed,
+            ),
+            (1, "", NetshOutcome::Failed),
+        ];
+        for (code, stdout, expected) in cases {
+            assert_eq!(
+                classify_netsh(&netsh_output(code, stdout)),
+                expected,
+                "{stdout:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn classify_netsh_prefers_stderr() {
+        let mut output = netsh_output(1, "Element not found.");
+        output.stderr = b"powershell.exe : Access is denied.".to_vec();
+        assert_eq!(classify_netsh(&output), NetshOutcome::AccessDenied);
+    }
+
+    #[test]
+    fn parse_portproxy_rules_reads_every_table() {
+        let raw = "\r
+Listen on ipv4:             Connect to ipv4:\r
+\r
+Address         Port        Address         Port\r
+--------------- ----------  --------------- ----------\r
+0.0.0.0         3000        172.20.0.2      3000\r
+192.168.1.10    8080        172.20.0.2      5173\r
+\r
+Listen on ipv6:             Connect to ipv4:\r
+\r
+Address         Port        Address         Port\r
+--------------- ----------  --------------- ----------\r
+::              443         172.20.0.2      8443\r
+\r
+";
+        let rules = parse_portproxy_rules(raw);
+        let summary: Vec<(RuleFamily, String, String)> = rules
+            .iter()
+            .map(|rule| (rule.family, rule.listen(), rule.connect()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (
+                    RuleFamily::V4ToV4,
+                    "0.0.0.0:3000".into(),
+                    "172.20.0.2:3000".into()
+                ),
+                (
+                    RuleFamily::V4ToV4,
+                    "192.168.1.10:8080".into(),
+                    "172.20.0.2:5173".into()
+                ),
+                (
+                    RuleFamily::V6ToV4,
+                    "[::]:443".into(),
+                    "172.20.0.2:8443".into()
+                ),
+            ]
+        );
+        assert!(parse_portproxy_rules("").is_empty());
+    }
+
+    fn unit_ports(units: &[Vec<Forward>]) -> Vec<Vec<u16>> {
+        units
+            .iter()
+            .map(|unit| unit.iter().map(|f| f.listen_port).collect())
+            .collect()
+    }
+
+    #[test]
+    fn batch_ranges_groups_consecutive_runs_of_three_or_more() {
+        let forwards: Vec<Forward> = [8002, 3000, 8000, 8001, 9000, 9001, 8080]
+            .into_iter()
+            .map(|port| forward(port, port))
+            .chain([forward(8003, 9999)])
+            .collect();
+        assert_eq!(
+            unit_ports(&batch_ranges(&forwards)),
+            [
+                vec![3000],
+                vec![8000, 8001, 8002],
+                vec![8003],
+                vec![8080],
+                vec![9000],
+                vec![9001]
+            ]
+        );
+    }
+
+    #[test]
+    fn batch_ranges_caps_each_script() {
+        let forwards: Vec<Forward> = (1..=250).map(|port| forward(port, port)).collect();
+        let sizes: Vec<usize> = batch_ranges(&forwards).iter().map(Vec::len).collect();
+        assert_eq!(sizes, [RANGE_BATCH_MAX, RANGE_BATCH_MAX, 50]);
+    }
+
+    const OLD_WSL: Ipv4Addr = Ipv4Addr::new(172, 20, 0, 9);
+
+    /// Windows holding rules from an earlier WSL address for `existing`,
+    /// where adding a rule for `failing` always fails.
+    struct StaleWindows {
+        existing: Vec<u16>,
+        failing: Option<u16>,
+        commands: Mutex<Vec<String>>,
+    }
+
+    impl RuleRunner for StaleWindows {
+        fn run<'a>(&'a self, command: &'a str) -> RunFuture<'a> {
+            self.commands.lock().unwrap().push(command.to_string());
+            let mut output = netsh_output(0, "");
+            if command == "netsh interface portproxy show all" {
+                let mut table = String::from("Listen on ipv4:             Connect to ipv4:\n\n");
+                for port in &self.existing {
+                    table.push_str(&format!(
+                        "0.0.0.0         {port:<11} {OLD_WSL:<15} {port}\n"
+                    ));
+                }
+                output.stdout = table.into_bytes();
+            } else if let Some(port) = self.failing {
+                if command.starts_with(&format!(
+                    "netsh interface portproxy add v4tov4 listenport={port} "
+                )) {
+                    output = netsh_output(1, "The parameter is incorrect.");
+                }
+            }
+            Box::pin(async move { Ok(output) })
+        }
+    }
+
+    /// Syncs 3000, 5000 and 6000 deletes-first against `StaleWindows` and
+    /// returns the commands sent after the initial deletes.
+    async fn cut_off(failing: Option<u16>, budget: Option<Duration>) -> Vec<String> {
+        let windows = Arc::new(StaleWindows {
+            existing: vec![3000, 5000, 6000],
+            failing,
+            commands: Mutex::new(Vec::new()),
+        });
+        let ps = PowerShell::with_runner(windows.clone());
+        let forwards = [
+            forward(3000, 3000),
+            forward(5000, 5000),
+            forward(6000, 6000),
+        ];
+        assert!(
+            apply_portproxy_rules(&ps, &forwards, budget, 1, ApplyOrder::DeletesFirst)
+                .await
+                .is_err()
+        );
+        let mut commands = windows.commands.lock().unwrap().clone();
+        commands.split_off(2)
+    }
+
+    fn old_rule(port: u16) -> String {
+        format!(
+            "netsh interface portproxy add v4tov4 listenport={port} listenaddress=0.0.0.0 \
+             connectport={port} connectaddress=172.20.0.9"
+        )
+    }
+
+    #[tokio::test]
+    async fn failed_deletes_first_sync_restores_the_ports_it_never_reached() {
+        let commands = cut_off(Some(3000), None).await;
+        let adds: Vec<&String> = commands.iter().filter(|c| c.contains(" add ")).collect();
+        // 3000 is tried twice and fails; 5000 and 6000 never start and get
+        // their old rules back.
+        assert_eq!(adds.len(), 4, "{commands:?}");
+        assert_eq!(adds[2..], [&old_rule(5000), &old_rule(6000)]);
+    }
+
+    #[tokio::test]
+    async fn exhausted_budget_restores_every_skipped_port() {
+        let commands = cut_off(None, Some(Duration::ZERO)).await;
+        let adds: Vec<&String> = commands.iter().filter(|c| c.contains(" add ")).collect();
+        assert_eq!(adds, [&old_rule(3000), &old_rule(5000), &old_rule(6000)]);
+    }
+}